@@ -102,7 +102,8 @@ public class SimpleLink {
 
     public native boolean tick(int elapsedMs);
 
-    public native int send(int[] route, byte[] data);
+    //PRN in the low 32 bits, negative if the send failed
+    public native long send(int[] route, byte[] data);
 
     static {
         System.loadLibrary("slink_android");
@@ -227,9 +227,10 @@ public class LinkThread {
 
                                 case MSG_SEND:
                                     final SendMessage sm = (SendMessage)msg.obj;
-                                    final int prn = mLink.send(sm.Route, sm.Data);
+                                    final long result = mLink.send(sm.Route, sm.Data);
+                                    final int prn = (int)(result & 0xFFFFFFFFL);
 
-                                    if(prn == 0) {
+                                    if(result < 0) {
                                         notifyConnectionFailure("Error sending message");
                                     } else {
                                         dispatchCallback(new Runnable() {
@@ -72,7 +72,7 @@ unsafe fn get_link(env: &jni::JNIEnv, object: JObject) -> *mut link::Link {
 pub unsafe extern "C" fn Java_vvanders_com_simplelink_SimpleLink_static_1init(env: jni::JNIEnv, _class: JClass) {
     set_env(&env);
 
-    simplelink::util::init_log_callback(log::LogLevelFilter::Trace, false,
+    simplelink::util::init_log_callback(log::LogLevelFilter::Trace, false, simplelink::util::LogFormat::Text,
         |msg, level, _location| {
             logcat(&jni::JNIEnv::from(ENV.unwrap()), level, msg);
         }
@@ -89,17 +89,7 @@ pub unsafe extern "C" fn Java_vvanders_com_simplelink_SimpleLink_internal_1init(
 
     let callsign: String = env.get_string(callsign).expect("Failed").into();
 
-    if callsign.len() > 7 {
-        return JNI_FALSE;
-    }
-
-    let mut callsign_scratch: [char; 7] = ['0'; 7];
-
-    for (i, c) in callsign.chars().enumerate().take(7) {
-        callsign_scratch[i] = c;
-    }
-
-    let callsign_id = match address::encode(callsign_scratch) {
+    let callsign_id = match address::encode_str(callsign.as_str()) {
         Some(c) => c,
         None => return JNI_FALSE
     };
@@ -130,9 +120,18 @@ pub unsafe extern "C" fn Java_vvanders_com_simplelink_SimpleLink_tick(env: jni::
     }
 }
 
+/// Flag set in the result of `send` when the packet couldn't be sent
+const SEND_ERROR_FLAG: jlong = 1 << 63;
+
+/// Sends a packet. Declared on the Java side as `native long send(int[] route, byte[] data)`.
+///
+/// On success the PRN is returned in the low 32 bits with the high bit clear, so every PRN
+/// including 0 is a valid result. A packet that was queued but failed to write is a success since
+/// it will be retried, its ack or expire arrives for the returned PRN. Any negative value means the
+/// send failed and nothing was queued.
 #[no_mangle]
 #[allow(non_snake_case)]
-pub unsafe extern "C" fn Java_vvanders_com_simplelink_SimpleLink_send(env: jni::JNIEnv, object: JObject, route: JObject, data: JObject) -> jint {
+pub unsafe extern "C" fn Java_vvanders_com_simplelink_SimpleLink_send(env: jni::JNIEnv, object: JObject, route: JObject, data: JObject) -> jlong {
     set_env(&env);
 
     let data_array = JArray::from_env(&env, data).unwrap();
@@ -143,7 +142,13 @@ pub unsafe extern "C" fn Java_vvanders_com_simplelink_SimpleLink_send(env: jni::
 
     let link = get_link(&env, object);
 
-    (*link).send(&env, route_data.get().iter().map(|v| *v as u32), data_bytes.get().iter().map(|v| *v as u8)) as jint
+    match (*link).send(&env, route_data.get().iter().map(|v| *v as u32), data_bytes.get().iter().map(|v| *v as u8)) {
+        Ok(prn) => prn as jlong,
+        Err(e) => {
+            info!("Failed to send packet {:?}", e);
+            SEND_ERROR_FLAG
+        }
+    }
 }
 
 #[no_mangle]
@@ -159,15 +164,5 @@ pub unsafe extern "C" fn Java_vvanders_com_simplelink_SimpleLink_decode_1addr(en
 pub unsafe extern "C" fn Java_vvanders_com_simplelink_SimpleLink_encode_1addr(env: jni::JNIEnv, _object: JObject, addr: JString) -> jint {
     let addr_str: String = env.get_string(addr).unwrap().into();
 
-    if addr_str.len() > 7 {
-        return 0
-    }
-
-    let mut translated: [char; 7] = ['0'; 7];
-
-    for (i, c) in addr_str.chars().enumerate().take(7) {
-        translated[i] = c;
-    }
-   
-    simplelink::spec::address::encode(translated).unwrap_or(0) as jint
+    simplelink::spec::address::encode_str(addr_str.as_str()).unwrap_or(0) as jint
 }
\ No newline at end of file
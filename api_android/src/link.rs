@@ -65,7 +65,10 @@ impl Link {
                           JValue::Object(data_arr.into_inner().into())]).unwrap_or(JValue::Void);
                 }
             },
-            |frame, data| {
+            |observed| {
+                let frame = observed.header();
+                let data = observed.payload();
+
                 let (route_arr, data_arr) = match get_frame_data(env, frame, data) {
                     Ok(v) => v,
                     Err(()) => return
@@ -75,7 +78,11 @@ impl Link {
                     &[JValue::Int(frame.prn as jint),
                         JValue::Object(route_arr.into_inner().into()),
                         JValue::Object(data_arr.into_inner().into())]).unwrap_or(JValue::Void);
-        });
+            },
+            |_| {},
+            |prn, e, _| {
+                trace!("Dropped frame {:?} {:?}", prn, e);
+            });
 
         if let Err(_) = recv_res {
             return false
@@ -87,6 +94,9 @@ impl Link {
             },
             |frame,_| {
                 env.call_method(obj, "internal_expire", "(I)V", &[JValue::Int(frame.prn as jint)]).unwrap_or(JValue::Void);
+            },
+            |congested| {
+                trace!("Congestion control {}", congested);
             });
 
         if let Err(_) = tick_res {
@@ -96,14 +106,19 @@ impl Link {
         true
     }
 
-    pub fn send<R,D>(&mut self, env: &JNIEnv, route: R, data: D) -> u32
+    /// Sends a packet, returns its PRN. A packet that was queued but failed to write is still returned as sent
+    /// since tick retries it and its ack or expire will arrive like any other.
+    pub fn send<R,D>(&mut self, env: &JNIEnv, route: R, data: D) -> Result<u32, simplelink::spec::node::SendError>
             where R: Iterator<Item=u32>,
                   D: Iterator<Item=u8> {
         let mut rx_tx = rx_tx::new(env, self.obj.inner());
 
         match self.node.send(data, route, &mut rx_tx) {
-            Ok(prn) => prn,
-            Err(_) => 0
+            Err(simplelink::spec::node::SendError::WriteButQueued(prn, e)) => {
+                info!("Packet {} failed to write, it will be retried {:?}", prn, e);
+                Ok(prn)
+            },
+            result => result
         }
     }
 }
\ No newline at end of file
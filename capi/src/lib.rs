@@ -3,24 +3,36 @@ extern crate libc;
 extern crate log;
 extern crate simplelink;
 
-mod echo;
-
 use std::io;
 use std::ffi;
+use std::sync::{Mutex, MutexGuard};
 
-pub trait ReadWrite: io::Write + io::Read {}
-impl<T> ReadWrite for T where T: io::Write + io::Read {}
+pub trait ReadWrite: io::Write + io::Read + Send {}
+impl<T> ReadWrite for T where T: io::Write + io::Read + Send {}
 
-pub struct Link {
-    link: simplelink::spec::node::Node,
+/// Node and the transport it talks over, always accessed under Link's lock
+struct Connection {
+    node: simplelink::spec::node::Node,
+    rx_tx: Option<Box<ReadWrite>>
+}
 
-    rx_tx: Option<Box<ReadWrite>>,
+/// Handle to a node exposed over the C API.
+///
+/// `tick`, `send`, `close` and the `open_*` functions lock the node and transport for their duration so
+/// the handle can be used from multiple threads, e.g. sending from a UI thread while a worker ticks.
+/// Callbacks should be set before the handle is shared and are invoked with the lock held, so they must
+/// not call back into the same handle.
+pub struct Link {
+    connection: Mutex<Connection>,
 
     recv_callback: Option<extern "C" fn(*const u32, u32, *const u8, usize)>,
     ack_callback: Option<extern "C" fn(*const u32, u32)>,
     expire_callback: Option<extern "C" fn(u32)>,
     retry_callback: Option<extern "C" fn(u32, u32)>,
+    congestion_callback: Option<extern "C" fn(bool)>,
     observe_callback: Option<extern "C" fn(*const u32, u32, *const u8, usize)>,
+    recv_callback2: Option<extern "C" fn(*const u32, u32, *const u8, usize, u8)>,
+    observe_callback2: Option<extern "C" fn(*const u32, u32, *const u8, usize, u8)>,
 
     recv_box_cb: Option<Box<Fn([u32; simplelink::spec::routing::MAX_LENGTH], u32, &[u8])>>,
     ack_box_cb: Option<Box<Fn([u32; simplelink::spec::routing::MAX_LENGTH], u32)>>,
@@ -39,13 +51,18 @@ pub unsafe extern "C" fn new(callsign: u32) -> *mut Link {
 #[no_mangle]
 pub unsafe extern "C" fn new_nolog(callsign: u32) -> *mut Link {
     let boxed = Box::new(Link {
-        link: simplelink::spec::node::new(callsign),
-        rx_tx: None,
+        connection: Mutex::new(Connection {
+            node: simplelink::spec::node::new(callsign),
+            rx_tx: None
+        }),
         recv_callback: None,
         ack_callback: None,
         expire_callback: None,
         retry_callback: None,
+        congestion_callback: None,
         observe_callback: None,
+        recv_callback2: None,
+        observe_callback2: None,
 
         recv_box_cb: None,
         ack_box_cb: None,
@@ -57,14 +74,22 @@ pub unsafe extern "C" fn new_nolog(callsign: u32) -> *mut Link {
     Box::into_raw(boxed)
 }
 
+/// Locks the node and transport, a callback that panicked while holding the lock doesn't leave them unusable
+unsafe fn lock<'a>(link: *mut Link) -> MutexGuard<'a, Connection> {
+    match (*link).connection.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner()
+    }
+}
+
 pub unsafe fn set_rx_tx(link: *mut Link, rx_tx: Box<ReadWrite>) {
-    (*link).rx_tx = Some(rx_tx);
+    lock(link).rx_tx = Some(rx_tx);
 }
 
 
 #[no_mangle]
 pub unsafe extern "C" fn open_loopback(link: *mut Link) -> bool {
-    (*link).rx_tx = Some(Box::new(echo::new()));
+    lock(link).rx_tx = Some(Box::new(simplelink::util::new_loopback()));
 
     trace!("Opened loopback port");
 
@@ -73,19 +98,23 @@ pub unsafe extern "C" fn open_loopback(link: *mut Link) -> bool {
 
 #[no_mangle]
 pub unsafe extern "C" fn close(link: *mut Link) {
-    (*link).rx_tx = None
+    lock(link).rx_tx = None
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn tick(link: *mut Link, elapsed: usize) -> bool {
-    match (*link).rx_tx {
+    let mut guard = lock(link);
+    let connection = &mut *guard;
+
+    match connection.rx_tx {
         Some(ref mut rx_tx) => {
-            match (*link).link.recv(rx_tx, 
-                    |frame,data| {
+            match connection.node.recv_with_info(rx_tx, 
+                    |frame,data,info| {
                         if data.len() != 0 {
-                            match (*link).recv_callback {
-                                Some(recv) => recv(frame.address_route.as_ptr(), frame.prn, data.as_ptr(), data.len()),
-                                None => match (*link).recv_box_cb {
+                            match ((*link).recv_callback2, (*link).recv_callback) {
+                                (Some(recv), _) => recv(frame.address_route.as_ptr(), frame.prn, data.as_ptr(), data.len(), info.port),
+                                (None, Some(recv)) => recv(frame.address_route.as_ptr(), frame.prn, data.as_ptr(), data.len()),
+                                (None, None) => match (*link).recv_box_cb {
                                     Some(ref recv) => recv(frame.address_route, frame.prn, data),
                                     None => ()
                                 }
@@ -100,14 +129,22 @@ pub unsafe extern "C" fn tick(link: *mut Link, elapsed: usize) -> bool {
                             }
                         }
                     },
-                    |frame,data| {
-                       match (*link).observe_callback {
-                            Some(obs) => obs(frame.address_route.as_ptr(), frame.prn, data.as_ptr(), data.len()),
-                            None => match (*link).observe_box_cb {
+                    |observed,info| {
+                       let frame = observed.header();
+                       let data = observed.payload();
+
+                       match ((*link).observe_callback2, (*link).observe_callback) {
+                            (Some(obs), _) => obs(frame.address_route.as_ptr(), frame.prn, data.as_ptr(), data.len(), info.port),
+                            (None, Some(obs)) => obs(frame.address_route.as_ptr(), frame.prn, data.as_ptr(), data.len()),
+                            (None, None) => match (*link).observe_box_cb {
                                 Some(ref obs) => obs(frame.address_route, frame.prn, data),
                                 None => ()
                             }
                         }
+                    },
+                    |_| {},
+                    |prn, e, _| {
+                        trace!("Dropped frame {:?} {:?}", prn, e);
                     }) {
                 Ok(()) => (),
                 Err(e) => {
@@ -116,7 +153,7 @@ pub unsafe extern "C" fn tick(link: *mut Link, elapsed: usize) -> bool {
                 }
             }
 
-            match (*link).link.tick(rx_tx, elapsed, 
+            match connection.node.tick(rx_tx, elapsed, 
                     |frame, _, next_retry| {
                         match (*link).retry_callback {
                             Some(retry) => retry(frame.prn, next_retry as u32),
@@ -134,6 +171,12 @@ pub unsafe extern "C" fn tick(link: *mut Link, elapsed: usize) -> bool {
                                 None => ()
                             }
                         }
+                    },
+                    |congested| {
+                        match (*link).congestion_callback {
+                            Some(congestion) => congestion(congested),
+                            None => ()
+                        }
                     }) {
                 Ok(()) => (),
                 Err(e) => {
@@ -150,12 +193,15 @@ pub unsafe extern "C" fn tick(link: *mut Link, elapsed: usize) -> bool {
 
 #[no_mangle]
 pub unsafe extern "C" fn send(link: *mut Link, dest: *const u32, data: *const u8, size: usize) -> u32 {
-    match (*link).rx_tx {
+    let mut guard = lock(link);
+    let connection = &mut *guard;
+
+    match connection.rx_tx {
         Some(ref mut rx_tx) => {
             let route = std::slice::from_raw_parts(dest, 15).iter().cloned()
                 .filter(|addr| *addr != 0);
 
-            match (*link).link.send_slice(std::slice::from_raw_parts(data, size), route, rx_tx) {
+            match connection.node.send_slice(std::slice::from_raw_parts(data, size), route, rx_tx) {
                 Ok(prn) => prn,
                 Err(e) => {
                     trace!("Error sending {:?}", e);
@@ -192,11 +238,30 @@ pub unsafe extern "C" fn set_retry_callback(link: *mut Link, callback: extern "C
     (*link).retry_callback = Some(callback);
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn set_congestion_callback(link: *mut Link, callback: extern "C" fn(bool)) {
+    (*link).congestion_callback = Some(callback);
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn set_observe_callback(link: *mut Link, callback: extern "C" fn(*const u32, u32, *const u8, usize)) {
     (*link).observe_callback = Some(callback);
 }
 
+/// Same as `set_recv_callback` but the callback is also passed the KISS port the frame arrived on.
+/// Takes priority over a callback set with `set_recv_callback`.
+#[no_mangle]
+pub unsafe extern "C" fn set_recv_callback2(link: *mut Link, callback: extern "C" fn(*const u32, u32, *const u8, usize, u8)) {
+    (*link).recv_callback2 = Some(callback);
+}
+
+/// Same as `set_observe_callback` but the callback is also passed the KISS port the frame arrived on.
+/// Takes priority over a callback set with `set_observe_callback`.
+#[no_mangle]
+pub unsafe extern "C" fn set_observe_callback2(link: *mut Link, callback: extern "C" fn(*const u32, u32, *const u8, usize, u8)) {
+    (*link).observe_callback2 = Some(callback);
+}
+
 pub unsafe fn set_recv_box_cb<T>(link: *mut Link, callback: T) where T: Fn([u32; simplelink::spec::routing::MAX_LENGTH], u32, &[u8]) + 'static {
     (*link).recv_box_cb = Some(Box::new(callback))
 }
@@ -227,17 +292,7 @@ pub unsafe extern "C" fn str_to_addr(addr: *const libc::c_char) -> u32 {
         }
     };
 
-    let mut arr: [char; 7] = ['0'; 7];
-
-    for (i, c) in addr_str.chars().take(7).enumerate() {
-        arr[i] = c;
-    }
-
-    use simplelink::spec::address;
-    match address::encode(arr) {
-        Some(a) => a,
-        None => 0
-    }
+    simplelink::spec::address::encode_str(addr_str).unwrap_or(0)
 }
 
 #[no_mangle]
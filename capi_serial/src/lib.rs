@@ -63,7 +63,7 @@ pub unsafe extern "C" fn open_port(link: *mut slink::Link, port: *const libc::c_
         }
     }
 
-    (*link).rx_tx = Some(Box::new(port));
+    slink::set_rx_tx(link, Box::new(port));
 
     println!("Opened serial port {}", port_str);
 
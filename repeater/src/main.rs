@@ -74,7 +74,7 @@ fn main() {
         None => vec!()
     };
 
-    let callsign_id = match address::encode(string_to_addr(callsign)) {
+    let callsign_id = match address::encode_str(callsign) {
         Some(prn) => prn,
         None => {
             println!("Unable to parse callsign, a valid callsign is up to seven characters containing A-Z, 0-9");
@@ -175,13 +175,24 @@ fn read_frames<T>(node: &mut node::Node, io: &mut T) where T: io::Read + io::Wri
         |header,payload| {
             println!("Recv {}", format_data(header, payload));
         },
-        |header,payload| {
-            if payload.len() > 0 {
-                let msg = format_data(&header, payload);
-                println!("Obs - data {} {}", header.prn, msg);
-            } else {
-                println!("Obs - ack {} {}", header.prn, address::format_addr(routing::get_source(&header.address_route)));
+        |observed| {
+            match *observed {
+                node::ObservedFrame::Data { header, payload } => {
+                    println!("Obs - data {} {}", header.prn, format_data(header, payload));
+                },
+                node::ObservedFrame::Ack { header, src } => {
+                    println!("Obs - ack {} {}", header.prn, address::format_addr(src));
+                },
+                node::ObservedFrame::Forwarded { header, payload } => {
+                    println!("Obs - fwd {} {}", header.prn, format_data(header, payload));
+                }
             }
+        },
+        |header| {
+            println!("Dup {} {}", header.prn, address::format_addr(routing::get_source(&header.address_route)));
+        },
+        |prn, e, _| {
+            warn!("Dropped frame {:?}, {:?}", prn, e);
         });
 
     match read {
@@ -232,24 +243,8 @@ fn configure_port(name: &std::ffi::OsStr, baud: Option<usize>) -> serial::Result
 }
 
 fn format_data(header: &frame::Frame, payload: &[u8]) -> String {
-    use std::str;
-    match str::from_utf8(payload) {
-        Ok(msg) => {
-            let line = msg.to_string();
-            let route = routing::format_route(&header.address_route);
+    let route = routing::format_route(&header.address_route);
 
-            route + ": " + line.as_str()
-        },
-        Err(e) => format!("Unable to decode UTF-8 {:?}", e)
-    }
+    route + ": " + util::format_payload(payload).as_str()
 }
 
-fn string_to_addr(addr: &str) -> [char; 7] {
-    //Translate from string into array up to 7 characters
-    let mut local_addr = ['0'; 7];
-    for (i, chr) in addr.chars().take(7).enumerate() {
-        local_addr[i] = chr;
-    }
-
-    local_addr
-}
@@ -1,5 +1,8 @@
 //! Implements KISS HLDC framing for communcation with TNCs that implement KISS protocol
 use std::io;
+use std::fmt;
+use std::error;
+use util;
 
 ///Frame delimiter code, used to represent start and end of frames.
 pub const FEND: u8 = 0xC0;
@@ -28,6 +31,50 @@ pub const CMD_DUPLEX: u8 = 0x05;
 //Exit KISS mode. This applies to all ports.
 pub const CMD_RETURN: u8 = 0xFF;
 
+///Size of the length + checksum trailer appended by `encode_checked`
+pub const CHECK_SIZE: usize = 3;
+
+/// Reasons a frame from `decode_checked` failed verification
+#[derive(Debug, PartialEq)]
+pub enum CheckError {
+    /// Frame was too short to hold the trailer
+    Truncated,
+    /// Trailer length didn't match the number of bytes decoded, contains the length from the trailer
+    Length(usize),
+    /// Byte sum didn't match, contains the sum from the trailer
+    Sum(u8)
+}
+
+/// Problems with a complete frame found by `decode_result`, each carries the number of bytes to skip past it
+#[derive(Debug, PartialEq)]
+pub enum KissError {
+    /// FESC followed by something other than TFEND or TFESC, contains the byte after the FESC(FEND if the frame ended)
+    BadEscape(u8, usize),
+    /// Command nibble isn't one we know, contains the whole command byte
+    UnknownCommand(u8, usize)
+}
+
+impl KissError {
+    /// Bytes to drop from the front of the buffer to skip the bad frame
+    pub fn bytes_read(&self) -> usize {
+        match *self {
+            KissError::BadEscape(_, bytes_read) |
+            KissError::UnknownCommand(_, bytes_read) => bytes_read
+        }
+    }
+}
+
+impl fmt::Display for KissError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KissError::BadEscape(byte, _) => write!(f, "Invalid KISS escape 0x{:02X}", byte),
+            KissError::UnknownCommand(cmd, _) => write!(f, "Unknown KISS command 0x{:02X}", cmd)
+        }
+    }
+}
+
+impl error::Error for KissError {}
+
 /// Encodes a series of bytes into a KISS frame.
 ///
 /// # Examples
@@ -113,6 +160,101 @@ pub fn encode_part<W>(data: &[u8], encoded: &mut W) -> io::Result<usize> where W
     Ok(written)
 }
 
+/// Encodes a series of bytes into a KISS frame written to `encoded`, without allocating.
+///
+/// Returns the number of bytes written or a `SizeError` if `encoded` can't hold the whole frame, in which case
+/// `encoded` is left untouched.
+///
+/// # Examples
+///
+/// ```
+/// use simplelink::kiss;
+///
+/// let mut data = [0; 8];
+/// let written = kiss::encode_slice(&[0x12, kiss::FEND], &mut data, 0).unwrap();
+/// assert!(data[..written] == [kiss::FEND, kiss::CMD_DATA, 0x12, kiss::FESC, kiss::TFEND, kiss::FEND]);
+/// ```
+pub fn encode_slice(data: &[u8], encoded: &mut [u8], port: u8) -> Result<usize, util::SizeError> {
+    //FEND + command + data + escapes + FEND
+    let escapes = data.iter().filter(|byte| **byte == FEND || **byte == FESC).count();
+    let required = 3 + data.len() + escapes;
+
+    if encoded.len() < required {
+        trace!("Unable to encode KISS frame of {} bytes into {} byte buffer", required, encoded.len());
+        return Err(util::SizeError { required: required })
+    }
+
+    encoded[0] = FEND;
+    encoded[1] = CMD_DATA | ((port & 0x0F) << 4);
+
+    let mut idx = 2;
+    for byte in data.iter().cloned() {
+        match byte {
+            FEND => {
+                encoded[idx] = FESC;
+                encoded[idx+1] = TFEND;
+                idx += 2;
+            },
+            FESC => {
+                encoded[idx] = FESC;
+                encoded[idx+1] = TFESC;
+                idx += 2;
+            },
+            _ => {
+                encoded[idx] = byte;
+                idx += 1;
+            }
+        }
+    }
+
+    encoded[idx] = FEND;
+
+    debug!("Encoded KISS frame of {} bytes for port {}", required, port);
+    Ok(required)
+}
+
+/// Computes the trailer `encode_checked` appends to `data`: big-endian length followed by a byte sum
+fn check_trailer(data: &[u8]) -> [u8; CHECK_SIZE] {
+    let len = [(data.len() >> 8) as u8, data.len() as u8];
+    let sum = data.iter().chain(len.iter()).fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+
+    [len[0], len[1], sum]
+}
+
+/// Encodes a series of bytes into a KISS frame with a length + checksum trailer inside the frame.
+///
+/// This isn't part of KISS, both ends need to agree to use it. It lets corruption on the serial link
+/// be told apart from corruption over the air. Use `decode_checked` to verify and strip the trailer.
+///
+/// # Examples
+///
+/// ```
+/// use simplelink::kiss;
+///
+/// let mut data = vec!();
+/// kiss::encode_checked(&[0x12, 0x34], &mut data, 0).unwrap();
+/// assert!(data == vec!(kiss::FEND, kiss::CMD_DATA, 0x12, 0x34, 0x00, 0x02, 0x48, kiss::FEND));
+/// ```
+pub fn encode_checked<W>(data: &[u8], encoded: &mut W, port: u8) -> io::Result<usize> where W: io::Write {
+    if data.len() > 0xFFFF {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Frame too large for checked encoding"))
+    }
+
+    let mut written = 0;
+
+    try!(encoded.write_all(&[FEND, CMD_DATA | ((port & 0x0F) << 4)]));
+    written += 2;
+
+    written += try!(encode_part(data, encoded));
+    written += try!(encode_part(&check_trailer(data), encoded));
+
+    try!(encoded.write_all(&[FEND]));
+    written += 1;
+
+    debug!("Encoded checked KISS frame of {} bytes for port {}", written, port);
+    Ok(written)
+}
+
 /// Encodes a command to be sent to the KISS TNC.
 ///
 /// # Examples
@@ -145,19 +287,25 @@ pub fn encode_cmd(encoded: &mut Vec<u8>, cmd: u8, data: u8, port: u8) {
 }
 
 /// Result from a decode operation
+#[derive(Debug, PartialEq)]
 pub struct DecodedFrame {
     /// Port that this frame was decoded from
     pub port: u8,
+    /// Command this frame carries, anything other than `CMD_DATA` is a TNC command or response rather than payload
+    pub command: u8,
     /// Number of bytes read from the iterator that was passed to decode(). The calling client is responsible for advancing the interator `bytes_read` after the decode operation.
     pub bytes_read: usize,
     /// Number of bytes in the payload(bytes_read - escape/control bytes)
-    pub payload_size: usize
+    pub payload_size: usize,
+    /// Number of bytes ahead of the frame's opening FEND that were skipped as noise, included in `bytes_read`
+    pub skipped: usize
 }
 
 /// Decode a KISS frame into a series of bytes.
 ///
 /// Appends all bytes decoded to decoded. If no KISS frames are found in the iterator then returns `None`.
-/// Otherwise returns an `Option` of `DecodedFrame`.
+/// Otherwise returns an `Option` of `DecodedFrame`. Invalid escapes are dropped from the frame rather than
+/// failing it, use `decode_result` to tell those apart.
 ///
 /// ```
 /// use simplelink::kiss;
@@ -173,6 +321,54 @@ pub struct DecodedFrame {
 /// }
 /// ```
 pub fn decode<T>(data: T, decoded: &mut Vec<u8>) -> Option<DecodedFrame> where T: Iterator<Item=u8> {
+    decode_frame(data, decoded).map(|(frame, _, _)| frame)
+}
+
+/// Same as `decode` but fails frames with an invalid escape or unknown command instead of passing them along.
+///
+/// `Ok(None)` means there's no complete frame yet. Unless a frame is returned nothing is appended to decoded,
+/// errors contain how many bytes to skip so the caller can resync on the next frame.
+///
+/// ```
+/// use simplelink::kiss;
+///
+/// let data = vec!(kiss::FEND, kiss::CMD_DATA, kiss::FESC, 0x12, kiss::FEND);
+/// let mut decoded = vec!();
+/// match kiss::decode_result(data.iter().cloned(), &mut decoded) {
+///     Err(kiss::KissError::BadEscape(0x12, 5)) => assert!(decoded.is_empty()),
+///     _ => assert!(false)
+/// }
+/// ```
+pub fn decode_result<T>(data: T, decoded: &mut Vec<u8>) -> Result<Option<DecodedFrame>, KissError> where T: Iterator<Item=u8> {
+    let decoded_start = decoded.len();
+
+    let (frame, cmd_byte, bad_escape) = match decode_frame(data, decoded) {
+        Some(result) => result,
+        None => {
+            decoded.truncate(decoded_start);
+            return Ok(None)
+        }
+    };
+
+    let known = match frame.command {
+        CMD_DATA | CMD_TX_DELAY | CMD_PERSISTENCE | CMD_SLOT_TIME | CMD_TX_TAIL | CMD_DUPLEX | CMD_RETURN => true,
+        _ => false
+    };
+
+    let err = match bad_escape {
+        Some(byte) => KissError::BadEscape(byte, frame.bytes_read),
+        None if !known => KissError::UnknownCommand(cmd_byte, frame.bytes_read),
+        None => return Ok(Some(frame))
+    };
+
+    warn!("Rejecting KISS frame: {}", err);
+    decoded.truncate(decoded_start);
+
+    Err(err)
+}
+
+/// Decodes the first frame, returns it along with the raw command byte and the first invalid escape if there was one
+fn decode_frame<T>(data: T, decoded: &mut Vec<u8>) -> Option<(DecodedFrame, u8, Option<u8>)> where T: Iterator<Item=u8> {
     let (reserved, _) = data.size_hint();
     decoded.reserve(reserved);
 
@@ -184,11 +380,13 @@ pub fn decode<T>(data: T, decoded: &mut Vec<u8>) -> Option<DecodedFrame> where T
     enum Token {
         Start(usize),   //Frame start at this idx(FEND)
         End(usize),     //Frame end at this idx(FEND followed by 1+ bytes followed by FEND)
-        Port(u8),       //First byte of valid frame is a port
+        Port(u8),       //First byte of valid frame is a port + command
         Byte(u8),       //Byte data(item inside two FEND values)
         Empty           //Data before or after FEND pairs
     }
 
+    let mut bad_escape = None;
+
     let (port, start_idx, end_idx) = data.enumerate()    //Keep track of idx so we can return the last idx we processed to the caller
         //Find our first valid start + end frame
         .scan((None, None), |&mut (ref mut start_frame, ref mut end_frame), (idx, byte)| {
@@ -217,10 +415,7 @@ pub fn decode<T>(data: T, decoded: &mut Vec<u8>) -> Option<DecodedFrame> where T
                         }
                     } else {
                         if start_frame.unwrap()+1 == idx {
-                            let port = byte >> 4;
-                            //trace!("Decoded port is {}", port);
-
-                            Token::Port(port)
+                            Token::Port(byte)
                         } else {
                             Token::Byte(byte)
                         }
@@ -245,12 +440,24 @@ pub fn decode<T>(data: T, decoded: &mut Vec<u8>) -> Option<DecodedFrame> where T
                         match byte {
                             TFEND => Some(Token::Byte(FEND)),
                             TFESC => Some(Token::Byte(FESC)),
-                            _ => None //This is a bad value, just discard the byte for now since we don't know how to handle it
+                            _ => {
+                                //This is a bad value, just discard the byte since we don't know how to handle it
+                                bad_escape = bad_escape.or(Some(byte));
+                                None
+                            }
                         }
                     } else {
                         Some(Token::Byte(byte))
                     }
                 },
+                Token::End(idx) => {
+                    //Frame ended while we were waiting on the escaped value
+                    if *was_esc {
+                        bad_escape = bad_escape.or(Some(FEND));
+                    }
+
+                    Some(Token::End(idx))
+                },
                 _ => Some(token)
             };
 
@@ -266,24 +473,36 @@ pub fn decode<T>(data: T, decoded: &mut Vec<u8>) -> Option<DecodedFrame> where T
                 },
                 Token::Start(idx) => (port, Some(idx+1), end_idx),
                 Token::End(idx) => (port, start_idx, Some(idx-1)),
-                Token::Port(port_num) => (Some(port_num), start_idx, end_idx),
+                Token::Port(cmd_byte) => (Some(cmd_byte), start_idx, end_idx),
                 Token::Empty => (port, start_idx, end_idx)
             }
         });
 
     //Check if we found anything
-    port.and_then(|port| {
+    port.and_then(|cmd_byte| {
         end_idx.and_then(|end_idx| {
             start_idx.and_then(|start_idx| {
                 let payload_size = end_idx - start_idx;
 
-                debug!("Decoded KISS frame of {} bytes on port {}", payload_size, port);
+                //Return applies to all ports so it takes the whole byte, everything else has the port in the high nibble
+                let (port, command) = match cmd_byte {
+                    CMD_RETURN => (0, CMD_RETURN),
+                    _ => (cmd_byte >> 4, cmd_byte & 0x0F)
+                };
+
+                debug!("Decoded KISS frame of {} bytes on port {} with command {}", payload_size, port, command);
+
+                if start_idx > 1 {
+                    trace!("Skipped {} bytes before the start of the frame", start_idx - 1);
+                }
 
-                Some(DecodedFrame {
+                Some((DecodedFrame {
                     port: port,
+                    command: command,
                     bytes_read: end_idx+2,   //Note that since we truncate the FEND we need to add an extra offset here
-                    payload_size: decoded.len() - decoded_start
-                })
+                    payload_size: decoded.len() - decoded_start,
+                    skipped: start_idx - 1
+                }, cmd_byte, bad_escape))
             })
         })
     }).or_else(|| {
@@ -292,6 +511,172 @@ pub fn decode<T>(data: T, decoded: &mut Vec<u8>) -> Option<DecodedFrame> where T
     })
 }
 
+/// Decodes a KISS frame written by `encode_checked`, verifying and stripping the trailer.
+///
+/// Returns `None` if no frame was found, like `decode`. Otherwise returns the decoded frame along with the
+/// result of the check. On success the trailer is removed from `decoded` and `payload_size`, on failure
+/// `decoded` is left with the raw frame contents so the corruption can be inspected.
+pub fn decode_checked<T>(data: T, decoded: &mut Vec<u8>) -> Option<(DecodedFrame, Result<(), CheckError>)> where T: Iterator<Item=u8> {
+    let decoded_start = decoded.len();
+
+    decode(data, decoded).map(|mut frame| {
+        if frame.payload_size < CHECK_SIZE {
+            warn!("Checked KISS frame of {} bytes is too short for a trailer", frame.payload_size);
+            return (frame, Err(CheckError::Truncated))
+        }
+
+        let split = decoded.len() - CHECK_SIZE;
+        let expected = check_trailer(&decoded[decoded_start..split]);
+
+        let result = {
+            let trailer = &decoded[split..];
+            let len = ((trailer[0] as usize) << 8) | trailer[1] as usize;
+
+            if trailer[..2] != expected[..2] {
+                Err(CheckError::Length(len))
+            } else if trailer[2] != expected[2] {
+                Err(CheckError::Sum(trailer[2]))
+            } else {
+                Ok(())
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                decoded.truncate(split);
+                frame.payload_size -= CHECK_SIZE;
+            },
+            Err(ref e) => warn!("Checked KISS frame failed verification {:?}", e)
+        }
+
+        (frame, result)
+    })
+}
+
+#[test]
+fn test_decode_leading_garbage() {
+    let mut data = (0..50).collect::<Vec<u8>>();
+    data.extend_from_slice(&[FEND, CMD_DATA, 0x12, 0x34, FEND]);
+
+    let mut decoded = vec!();
+    match decode(data.iter().cloned(), &mut decoded) {
+        Some(result) => {
+            assert_eq!(result.skipped, 50);
+            assert_eq!(result.bytes_read, data.len());
+            assert_eq!(decoded, vec!(0x12, 0x34));
+        },
+        None => assert!(false)
+    }
+
+    //Nothing skipped for a clean frame
+    decoded.clear();
+    assert_eq!(decode(data[50..].iter().cloned(), &mut decoded).map(|result| result.skipped), Some(0));
+}
+
+#[test]
+fn test_decode_split_escape() {
+    let data = [FEND, CMD_DATA, 0x01, FESC, TFEND, FESC, TFESC, 0x02, FEND];
+
+    //Every partial buffer is incomplete, the whole one decodes the escapes
+    for split in 1..data.len() {
+        let mut decoded = vec!();
+        assert!(decode(data[..split].iter().cloned(), &mut decoded).is_none());
+    }
+
+    let mut decoded = vec!();
+    match decode(data.iter().cloned(), &mut decoded) {
+        Some(result) => {
+            assert_eq!(result.bytes_read, data.len());
+            assert_eq!(decoded, vec!(0x01, FEND, FESC, 0x02));
+        },
+        None => assert!(false)
+    }
+}
+
+#[test]
+fn test_decode_command() {
+    let mut decoded = vec!();
+    match decode([FEND, CMD_RETURN, FEND].iter().cloned(), &mut decoded) {
+        Some(result) => {
+            assert_eq!(result.command, CMD_RETURN);
+            assert_eq!(result.bytes_read, 3);
+            assert_eq!(result.payload_size, 0);
+        },
+        None => assert!(false)
+    }
+
+    let mut cmd = vec!();
+    encode_cmd(&mut cmd, CMD_TX_DELAY, 4, 2);
+
+    decoded.clear();
+    match decode(cmd.iter().cloned(), &mut decoded) {
+        Some(result) => {
+            assert_eq!(result.command, CMD_TX_DELAY);
+            assert_eq!(result.port, 2);
+            assert_eq!(decoded, vec!(4));
+        },
+        None => assert!(false)
+    }
+
+    decoded.clear();
+    match decode([FEND, CMD_DATA | 0x30, 0x12, FEND].iter().cloned(), &mut decoded) {
+        Some(result) => {
+            assert_eq!(result.command, CMD_DATA);
+            assert_eq!(result.port, 3);
+        },
+        None => assert!(false)
+    }
+}
+
+#[test]
+fn test_decode_result() {
+    let mut decoded = vec!();
+
+    //Nothing complete yet
+    assert_eq!(decode_result([0x12, 0x34].iter().cloned(), &mut decoded), Ok(None));
+    assert_eq!(decode_result([FEND, CMD_DATA, 0x12].iter().cloned(), &mut decoded), Ok(None));
+    assert_eq!(decode_result([FEND, FEND].iter().cloned(), &mut decoded), Ok(None));
+    assert!(decoded.is_empty());
+
+    //Valid escapes decode like normal
+    match decode_result([FEND, CMD_DATA | 0x10, FESC, TFEND, FESC, TFESC, FEND].iter().cloned(), &mut decoded) {
+        Ok(Some(result)) => {
+            assert_eq!(result.port, 1);
+            assert_eq!(result.bytes_read, 7);
+            assert_eq!(decoded, vec!(FEND, FESC));
+        },
+        _ => assert!(false)
+    }
+
+    decoded.clear();
+    let bad_escape = [FEND, CMD_DATA, 0x12, FESC, 0x34, FEND];
+    assert_eq!(decode_result(bad_escape.iter().cloned(), &mut decoded), Err(KissError::BadEscape(0x34, 6)));
+    assert!(decoded.is_empty());
+
+    //Plain decode still passes the frame along without the bad escape
+    match decode(bad_escape.iter().cloned(), &mut decoded) {
+        Some(result) => assert_eq!(result.bytes_read, 6),
+        None => assert!(false)
+    }
+    assert_eq!(decoded, vec!(0x12));
+
+    decoded.clear();
+    assert_eq!(decode_result([FEND, CMD_DATA, 0x12, FESC, FEND].iter().cloned(), &mut decoded), Err(KissError::BadEscape(FEND, 5)));
+    assert_eq!(decode_result([FEND, 0x2E, 0x12, FEND].iter().cloned(), &mut decoded), Err(KissError::UnknownCommand(0x2E, 4)));
+    assert!(decoded.is_empty());
+    assert_eq!(decode_result([FEND, CMD_RETURN, FEND].iter().cloned(), &mut decoded).unwrap().unwrap().command, CMD_RETURN);
+
+    //Skipping the error lets us resync on the next frame
+    let mut data = vec!(FEND, 0x2E, FEND);
+    encode(&mut io::Cursor::new(&[0x56]), &mut data, 0).unwrap();
+    let err = decode_result(data.iter().cloned(), &mut decoded).unwrap_err();
+    assert_eq!(format!("{}", err), "Unknown KISS command 0x2E");
+    match decode_result(data[err.bytes_read()..].iter().cloned(), &mut decoded) {
+        Ok(Some(result)) => assert_eq!(result.payload_size, 1),
+        _ => assert!(false)
+    }
+    assert_eq!(decoded, vec!(0x56));
+}
 
 #[test]
 fn test_encode() {
@@ -334,6 +719,30 @@ fn test_encode() {
     }
 }
 
+#[test]
+fn test_encode_slice() {
+    use std::io::Cursor;
+
+    let inputs: [&[u8]; 3] = [&[], &[1, 2, 3, 4], &[FEND, 5, FESC, FESC, FEND]];
+
+    for input in inputs.iter() {
+        let mut expected = vec!();
+        encode(&mut Cursor::new(input), &mut expected, 3).unwrap();
+
+        let mut data = [0; 16];
+        let written = encode_slice(input, &mut data, 3).unwrap();
+        assert_eq!(&data[..written], expected.as_slice());
+
+        //One byte short should fail without writing anything
+        let mut short = vec!(0; expected.len() - 1);
+        match encode_slice(input, &mut short, 3) {
+            Err(e) => assert_eq!(e.required, expected.len()),
+            Ok(_) => assert!(false)
+        }
+        assert!(short.iter().all(|byte| *byte == 0));
+    }
+}
+
 #[cfg(test)]
 fn test_encode_decode_single<T>(source: T) where T: Iterator<Item=u8> {
     use std::io::Cursor;
@@ -377,6 +786,64 @@ fn test_encode_decode() {
     test_encode_decode_single([FEND, FESC].iter().map(|data| *data));
 }
 
+#[test]
+fn test_encode_decode_checked() {
+    let payload = [0x01, FEND, 0x02, FESC, 0x03];
+
+    let mut data = vec!();
+    let written = encode_checked(&payload, &mut data, 3).unwrap();
+    assert_eq!(written, data.len());
+
+    let mut decoded = vec!();
+    match decode_checked(data.iter().cloned(), &mut decoded) {
+        Some((result, Ok(()))) => {
+            assert_eq!(result.port, 3);
+            assert_eq!(result.bytes_read, data.len());
+            assert_eq!(result.payload_size, payload.len());
+            assert_eq!(decoded.as_slice(), &payload);
+        },
+        _ => assert!(false)
+    }
+
+    //Plain decode still works, it just sees the trailer as payload
+    decoded.clear();
+    assert_eq!(decode(data.iter().cloned(), &mut decoded).map(|result| result.payload_size), Some(payload.len() + CHECK_SIZE));
+
+    //Mangled byte
+    let mut corrupt = data.clone();
+    corrupt[2] = 0x04;
+    decoded.clear();
+    match decode_checked(corrupt.iter().cloned(), &mut decoded) {
+        Some((_, Err(CheckError::Sum(_)))) => assert_eq!(decoded.len(), payload.len() + CHECK_SIZE),
+        _ => assert!(false)
+    }
+
+    //Dropped byte
+    let mut corrupt = data.clone();
+    corrupt.remove(2);
+    decoded.clear();
+    match decode_checked(corrupt.iter().cloned(), &mut decoded) {
+        Some((_, Err(CheckError::Length(len)))) => assert_eq!(len, payload.len()),
+        _ => assert!(false)
+    }
+
+    //Bad escape sequence drops a byte in the decoder
+    let mut corrupt = data.clone();
+    corrupt[4] = 0x00;
+    decoded.clear();
+    match decode_checked(corrupt.iter().cloned(), &mut decoded) {
+        Some((_, Err(CheckError::Length(_)))) => (),
+        _ => assert!(false)
+    }
+
+    //Too short for a trailer
+    decoded.clear();
+    match decode_checked([FEND, CMD_DATA, 0x01, FEND].iter().cloned(), &mut decoded) {
+        Some((_, Err(CheckError::Truncated))) => (),
+        _ => assert!(false)
+    }
+}
+
 #[test]
 fn test_empty_frame() {
     use std::io::Cursor;
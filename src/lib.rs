@@ -1,10 +1,31 @@
 extern crate byteorder;
+#[cfg(feature = "log")]
 #[macro_use]
 extern crate log;
+#[cfg(feature = "logger")]
 extern crate fern;
+#[cfg(feature = "logger")]
 extern crate time;
 extern crate rand;
 
+//Logging compiles away without the log feature, arguments are still type checked
+#[cfg(not(feature = "log"))]
+macro_rules! log_noop {
+    ($($arg:tt)*) => { if false { let _ = format!($($arg)*); } }
+}
+#[cfg(not(feature = "log"))]
+macro_rules! trace { ($($arg:tt)*) => { log_noop!($($arg)*) } }
+#[cfg(not(feature = "log"))]
+macro_rules! debug { ($($arg:tt)*) => { log_noop!($($arg)*) } }
+#[cfg(not(feature = "log"))]
+macro_rules! info { ($($arg:tt)*) => { log_noop!($($arg)*) } }
+#[cfg(not(feature = "log"))]
+macro_rules! warn { ($($arg:tt)*) => { log_noop!($($arg)*) } }
+#[cfg(not(feature = "log"))]
+macro_rules! error { ($($arg:tt)*) => { log_noop!($($arg)*) } }
+#[cfg(not(feature = "log"))]
+macro_rules! log_enabled { ($($arg:tt)*) => { false } }
+
 pub mod kiss;
 pub mod spec;
-pub mod util;
\ No newline at end of file
+pub mod util;
@@ -1,4 +1,5 @@
 //! Encodes address to/from wire format
+use spec::routing;
 
 const SYMBOL_TABLE: [char; 36] = [
     '0', '1', '2', '3', '4', '5', '6', '7', '8', '9',
@@ -85,6 +86,42 @@ pub fn encode(address: [char; 7]) -> Option<u32> {
     }
 }
 
+/// Encodes a human readable address from a string, padding callsigns shorter than 7 characters.
+/// Returns None rather than truncating if there are more than 7 characters, or if `address` is empty. `"*"` is the
+/// broadcast address.
+///
+/// # Examples
+///
+/// ```
+/// use simplelink::spec::address;
+///
+/// assert_eq!(address::encode_str("S53MV"), Some(53098624));
+/// assert_eq!(address::encode_str("KI7EST12"), None);
+/// ```
+pub fn encode_str(address: &str) -> Option<u32> {
+    if address == "*" {
+        return encode(BROADCAST_ADDRESS)
+    }
+
+    //All zeros is the route separator rather than a callsign
+    if address.is_empty() {
+        return None
+    }
+
+    let mut padded = ['0'; 7];
+
+    for (i, chr) in address.chars().enumerate() {
+        if i == padded.len() {
+            warn!("Tried to encode address {} but it's longer than 7 characters", address);
+            return None
+        }
+
+        padded[i] = chr;
+    }
+
+    encode(padded)
+}
+
 fn encode_rec(address: [char; 7], offset: usize) -> Option<u32> {
     if offset == 6 {
         character_to_symbol(address[6]).map(|x| x as u32)
@@ -114,11 +151,37 @@ pub fn decode(address: u32) -> [char; 7] {
     }).0
 }
 
-/// Decodes an address and formats it into a String removing any trailing zeroes
+/// Decodes an address into a String with the padding of short callsigns removed.
+///
+/// Symbols are encoded little-endian so padding `'0'`s end up as the high order digits, which are zero in the
+/// wire value. This means a callsign that really ends in `'0'` encodes to the same value as the shorter
+/// callsign without it. We resolve that by always treating trailing `'0'`s as padding, `'0'`s anywhere
+/// else are significant and kept.
+///
+/// # Examples
+///
+/// ```
+/// use simplelink::spec::address;
+///
+/// assert_eq!(address::decode_trimmed(53098624), "S53MV");
+/// ```
+pub fn decode_trimmed(address: u32) -> String {
+    let decoded = decode(address);
+
+    //Everything past the highest non-zero symbol is padding
+    let len = decoded.iter().rposition(|chr| *chr != '0').map(|idx| idx + 1).unwrap_or(0);
+
+    decoded[..len].iter().cloned().collect()
+}
+
+/// Decodes an address and formats it into a String removing any trailing zeroes.
+/// The broadcast address is rendered as `*`.
 pub fn format_addr(addr: u32) -> String {
-    decode(addr).into_iter().cloned()
-        .collect::<String>()
-        .trim_right_matches('0').to_string()
+    if addr == routing::BROADCAST_ADDRESS {
+        return "*".to_string()
+    }
+
+    decode_trimmed(addr)
 }
 
 #[test]
@@ -139,6 +202,18 @@ fn encode_test() {
     }
 }
 
+#[test]
+fn encode_str_test() {
+    assert_eq!(encode_str("S53MV"), Some(53098624));
+    assert_eq!(encode_str("KI7EST1"), encode(['K', 'I', '7', 'E', 'S', 'T', '1']));
+    assert_eq!(encode_str("*"), Some(0xFFFFFFFF));
+    assert_eq!(encode_str(""), None);
+
+    //Too long is rejected rather than truncated to KI7EST1
+    assert_eq!(encode_str("KI7EST12"), None);
+    assert_eq!(encode_str("KI7-EST"), None);
+}
+
 #[test]
 fn decode_test() {
     assert!(decode(1) == ['1', '0', '0', '0', '0', '0', '0']);
@@ -155,4 +230,19 @@ fn encode_decode_test() {
     assert!(decode(encode(addr1).unwrap_or(0)) == addr1);
     assert!(decode(encode(addr2).unwrap_or(0)) == addr2);
     assert!(decode(encode(addr3).unwrap_or(0)) == addr3);
-}
\ No newline at end of file
+}
+
+#[test]
+fn format_addr_test() {
+    assert_eq!(format_addr(53098624), "S53MV");
+    assert_eq!(format_addr(encode(['K', '0', 'A', 'B', 'C', '0', '0']).unwrap()), "K0ABC");
+    assert_eq!(format_addr(encode(BROADCAST_ADDRESS).unwrap()), "*");
+}
+
+#[test]
+fn decode_trimmed_test() {
+    assert_eq!(decode_trimmed(encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap()), "KI7EST");
+    assert_eq!(decode_trimmed(encode(['K', 'I', '7', 'E', 'S', 'T', '1']).unwrap()), "KI7EST1");
+    assert_eq!(decode_trimmed(encode(['W', '0', '0', 'A', '0', '0', '0']).unwrap()), "W00A");
+    assert_eq!(decode_trimmed(0), "");
+}
@@ -59,6 +59,11 @@ pub fn update_u32(int: u32, mut crc: CRC) -> CRC {
     crc
 }
 
+/// Process 16 bits of data for CRC
+pub fn update_u16(int: u16, crc: CRC) -> CRC {
+    update_u8(int as u8, update_u8((int >> 8) as u8, crc))
+}
+
 /// Process 8 bits of data for CRC
 pub fn update_u8(byte: u8, mut crc: CRC) -> CRC {
     let mut bit = 0x80; //Highest bit of 8-bit value;
@@ -1,27 +1,99 @@
 //! Frame management
 use std::io;
+use std::fmt;
+use std::error;
 use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
 use spec::crc16;
 use spec::prn_id;
 use spec::routing;
+use util;
 
 /// MTU of payload
 pub const MTU: usize = 1500;
 
-/// Max size for an ack (Data + PRN + (Addr + delim) + CRC)
-pub const MAX_ACK_SIZE: usize = 4 + 4 * (routing::MAX_LENGTH + 1) + 2;
+/// Frames with a version start with a marker of VERSION_MAGIC in the top 3 bytes and a version/flags byte.
+/// The version is the high nibble and the flags the low nibble of that byte, so there's only room for 15 versions
+/// and 4 flags. Legacy(version 0) frames have no marker and start directly with their PRN.
+pub const VERSION_MAGIC: u32 = 0x534C4B00;
 
-/// Max size for a packet (Data + PRN + Addr + CRC)
+/// Bits of the version marker that must match VERSION_MAGIC
+pub const VERSION_MAGIC_MASK: u32 = 0xFFFFFF00;
+
+/// Size of the version marker when present
+pub const VERSION_SIZE: usize = 4;
+
+/// Legacy frame without a version marker
+pub const VERSION_LEGACY: u8 = 0;
+
+/// Newest frame version we know how to parse
+pub const VERSION_CURRENT: u8 = 1;
+
+/// Flag for a u16 length of the whole frame following the PRN, lets us reject merged or truncated frames early
+pub const FLAG_LENGTH: u8 = 0x1;
+
+/// Size of the length field when present
+pub const LENGTH_SIZE: usize = 2;
+
+/// Flag for an ack that covers several frames, the header PRN is the first and the payload holds the rest as u32s
+pub const FLAG_MULTI_ACK: u8 = 0x2;
+
+/// Flag for a keepalive that lets peers know we're still around, has no payload and isn't acked
+pub const FLAG_KEEPALIVE: u8 = 0x4;
+
+/// Flag for a ping, with no payload it's a request and with a u32 payload it's the reply carrying the request's PRN.
+/// This takes the last free flag bit, anything else that needs a header field has to bump the version.
+pub const FLAG_PING: u8 = 0x8;
+
+/// Most PRNs that fit in a single multi-ack, the header PRN plus a full payload
+pub const MAX_MULTI_ACK: usize = 1 + MTU / 4;
+
+/// Flags describing optional header fields that we know how to parse, only 4 bits are available on the wire
+pub const KNOWN_FLAGS: u8 = FLAG_LENGTH | FLAG_MULTI_ACK | FLAG_KEEPALIVE | FLAG_PING;
+
+/// Max size for an ack (Version + PRN + Length + (Addr + delim) + CRC)
+///
+/// Acks carry the full return route so they can be relayed back along the path the frame took. This is the canonical
+/// ack format, the older PRN + source callsign ack(10 bytes) is not understood by this module.
+pub const MAX_ACK_SIZE: usize = VERSION_SIZE + 4 + LENGTH_SIZE + 4 * (routing::MAX_LENGTH + 1) + 2;
+
+/// Min size for an ack, a legacy frame with only a destination and source (PRN + Dest + delim + Src + delim + CRC)
+pub const MIN_ACK_SIZE: usize = 4 + 4 * 4 + 2;
+
+/// Min size for a data frame, an ack with at least one byte of payload
+pub const MIN_DATA_SIZE: usize = MIN_ACK_SIZE + 1;
+
+/// Max size for a packet (Version marker + PRN + Length + Addr + Data + CRC)
 pub const MAX_PACKET_SIZE: usize = MAX_ACK_SIZE + MTU;
 
 /// Represents a single Frame. We have two types of frames, data and ack frames.
-/// And header with zero size is an ACK frame.
-#[derive(Copy,Clone,Eq,PartialEq,Debug)]
+/// A legacy header with zero size or a multi-ack is an ACK frame, see `is_ack`.
+#[derive(Copy,Clone,Eq,PartialEq,Hash,Debug)]
 pub struct Frame {
     /// Pseudo-Random unique identifier for this packet. This is combination of PRN + XOR of callsign.
     pub prn: u32,
     /// Forward and return address routing. Each path can contain up to 16 addresses plus a single separator.
-    pub address_route: routing::Route
+    pub address_route: routing::Route,
+    /// Version of the frame format, VERSION_LEGACY frames are encoded without a version marker.
+    pub version: u8,
+    /// Bitfield of optional header fields present, only encoded for versioned frames. Only the low 4 bits fit in the marker.
+    pub flags: u8
+}
+
+impl Frame {
+    /// Next hop this frame is headed to, the first address in the forward path
+    pub fn dest(&self) -> u32 {
+        self.address_route[0]
+    }
+
+    /// Station at the end of the forward path
+    pub fn final_dest(&self) -> u32 {
+        routing::get_dest(&self.address_route)
+    }
+
+    /// Station that originally sent this frame, the last address in the return path
+    pub fn source(&self) -> u32 {
+        routing::get_source(&self.address_route)
+    }
 }
 
 /// Error cases for converting from raw bytes to a frame.
@@ -34,7 +106,9 @@ pub enum ReadError {
     /// Address format is malformed and could not be read.
     BadAddress,
     /// Frame failed CRC validation and contains invalid bits.
-    CRCFailure
+    CRCFailure,
+    /// Frame has a newer version or flags than we know how to parse.
+    UnsupportedVersion
 }
 
 /// Error cases for encoding a packet
@@ -43,26 +117,191 @@ pub enum EncodeError {
     /// Dest address was more than 15 stations
     AddressTooLong,
     /// Address didn't contain a source -> dest separator
-    AddressSeparatorNotFound
+    AddressSeparatorNotFound,
+    /// Route didn't contain any hops to send to
+    EmptyRoute,
+    /// Hops contained a separator, it's added for you when sending along a path
+    UnexpectedSeparator
 }
 
 /// Error cases for converting from a frame to raw bytes.
 #[derive(Debug)]
 pub enum WriteError {
     /// IO error occured while writing.
-    IO(io::Error)
+    IO(io::Error),
+    /// Output slice was too small to hold the frame.
+    Size(util::SizeError)
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReadError::IO(ref e) => write!(f, "IO error reading frame: {}", e),
+            ReadError::Truncated => write!(f, "Frame was truncated"),
+            ReadError::BadAddress => write!(f, "Frame address was malformed"),
+            ReadError::CRCFailure => write!(f, "Frame failed CRC check"),
+            ReadError::UnsupportedVersion => write!(f, "Frame version or flags are not supported")
+        }
+    }
+}
+
+impl error::Error for ReadError {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            ReadError::IO(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::AddressTooLong => write!(f, "Route has too many addresses"),
+            EncodeError::AddressSeparatorNotFound => write!(f, "Route is missing a source separator"),
+            EncodeError::EmptyRoute => write!(f, "Route has no hops"),
+            EncodeError::UnexpectedSeparator => write!(f, "Hops contain a source separator")
+        }
+    }
+}
+
+impl error::Error for EncodeError {}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WriteError::IO(ref e) => write!(f, "IO error writing frame: {}", e),
+            WriteError::Size(ref e) => write!(f, "Unable to fit frame: {}", e)
+        }
+    }
+}
+
+impl error::Error for WriteError {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            WriteError::IO(ref e) => Some(e),
+            WriteError::Size(ref e) => Some(e)
+        }
+    }
 }
 
 // Constructs a new ack frame
 pub fn new_ack(prn: u32, dest: routing::Route) -> Frame {
     Frame {
         prn: prn,
-        address_route: dest
+        address_route: dest,
+        version: VERSION_LEGACY,
+        flags: 0
+    }
+}
+
+/// Constructs an ack for up to MAX_MULTI_ACK frames that share a return route, returns the header and payload.
+/// Only nodes that understand VERSION_CURRENT can parse these.
+pub fn new_multi_ack(prns: &[u32], dest: routing::Route) -> (Frame, Vec<u8>) {
+    let mut payload = vec!();
+    for prn in prns.iter().skip(1) {
+        payload.write_u32::<BigEndian>(*prn).unwrap();
+    }
+
+    let frame = Frame {
+        prn: prns.first().cloned().unwrap_or(0),
+        address_route: dest,
+        version: VERSION_CURRENT,
+        flags: FLAG_MULTI_ACK
+    };
+
+    (frame, payload)
+}
+
+/// Constructs a keepalive frame, only nodes that understand VERSION_CURRENT can parse these
+pub fn new_keepalive(prn: u32, dest: routing::Route) -> Frame {
+    Frame {
+        prn: prn,
+        address_route: dest,
+        version: VERSION_CURRENT,
+        flags: FLAG_KEEPALIVE
+    }
+}
+
+/// Checks if this frame is a keepalive
+pub fn is_keepalive(frame: &Frame) -> bool {
+    frame.version != VERSION_LEGACY && frame.flags & FLAG_KEEPALIVE != 0
+}
+
+/// Constructs a ping request, only nodes that understand VERSION_CURRENT can parse these
+pub fn new_ping(prn: u32, dest: routing::Route) -> Frame {
+    Frame {
+        prn: prn,
+        address_route: dest,
+        version: VERSION_CURRENT,
+        flags: FLAG_PING
     }
 }
 
+/// Constructs the reply to the ping `ping_prn`, returns the header and payload
+pub fn new_pong(prn: u32, ping_prn: u32, dest: routing::Route) -> (Frame, Vec<u8>) {
+    let mut payload = vec!();
+    payload.write_u32::<BigEndian>(ping_prn).unwrap();
+
+    (new_ping(prn, dest), payload)
+}
+
+/// Checks if this frame is a ping request or reply
+pub fn is_ping(frame: &Frame) -> bool {
+    frame.version != VERSION_LEGACY && frame.flags & FLAG_PING != 0
+}
+
+/// PRN of the ping this frame is replying to, None if it isn't a ping reply
+pub fn pong_prn(frame: &Frame, payload: &[u8]) -> Option<u32> {
+    use byteorder::ByteOrder;
+
+    if is_ping(frame) && payload.len() == 4 {
+        Some(BigEndian::read_u32(payload))
+    } else {
+        None
+    }
+}
+
+/// Checks if a parsed frame is an ack, either a legacy frame with no payload or a multi-ack.
+///
+/// Versioned frames are only acks when flagged with `FLAG_MULTI_ACK`, so a versioned frame with no payload and no
+/// flags marking it otherwise is an empty data frame. Keepalives and pings also have no payload but aren't acks.
+pub fn is_ack(frame: &Frame, payload: &[u8]) -> bool {
+    if is_multi_ack(frame) {
+        return true
+    }
+
+    frame.version == VERSION_LEGACY && payload.len() == 0
+}
+
+/// Checks if this frame is an ack covering several frames
+pub fn is_multi_ack(frame: &Frame) -> bool {
+    frame.version != VERSION_LEGACY && frame.flags & FLAG_MULTI_ACK != 0
+}
+
+/// Every PRN acked by a multi-ack frame, header PRN first
+pub fn multi_ack_prns(frame: &Frame, payload: &[u8]) -> Vec<u32> {
+    use byteorder::ByteOrder;
+
+    ::std::iter::once(frame.prn)
+        .chain(payload.chunks(4).filter(|prn| prn.len() == 4).map(|prn| BigEndian::read_u32(prn)))
+        .collect()
+}
+
 /// Constructs a new data frame
 pub fn new_header<T>(prn: &mut prn_id::PRN, dest: T) -> Result<Frame, EncodeError> where T: Iterator<Item=u32> {
+    let route = try!(new_route(dest));
+
+    Ok(Frame {
+        prn: prn.next(),
+        address_route: route,
+        version: VERSION_LEGACY,
+        flags: 0
+    })
+}
+
+/// Validates and packs the route a new data frame would carry, without taking a PRN
+pub fn new_route<T>(dest: T) -> Result<routing::Route, EncodeError> where T: Iterator<Item=u32> {
     let mut addr: routing::Route = [0; routing::MAX_LENGTH];
 
     //Encode and look for valid addr
@@ -81,10 +320,12 @@ pub fn new_header<T>(prn: &mut prn_id::PRN, dest: T) -> Result<Frame, EncodeErro
         return Err(EncodeError::AddressSeparatorNotFound)
     }
 
-    Ok(Frame {
-        prn: prn.next(),
-        address_route: addr
-    })
+    Ok(addr)
+}
+
+/// Checks if the leading u32 of a frame is a version marker rather than a legacy PRN
+pub fn is_version_marker(value: u32) -> bool {
+    value & VERSION_MAGIC_MASK == VERSION_MAGIC && (value >> 4) & 0xF != VERSION_LEGACY as u32
 }
 
 fn read_u32<T>(bytes: &mut T, crc: &mut crc16::CRC) -> Result<u32, ReadError> where T: io::Read {
@@ -98,11 +339,49 @@ fn read_u32<T>(bytes: &mut T, crc: &mut crc16::CRC) -> Result<u32, ReadError> wh
 pub fn from_bytes<T>(bytes: &mut T, out_payload: &mut [u8], size: usize) -> Result<(Frame, usize), ReadError> where T: io::Read {
     trace!("Reading frame from bytes");
 
+    //Can't be a frame, don't bother reading any of it
+    if size < MIN_ACK_SIZE {
+        error!("Frame of {} bytes is shorter than the minimum of {}", size, MIN_ACK_SIZE);
+        return Err(ReadError::Truncated)
+    }
+
     let mut crc = crc16::new();
     let mut err = None;
 
-    //All frames start with PRN
-    let prn = try!(read_u32(bytes, &mut crc));
+    //All frames start with PRN, unless they have a version marker in front of it
+    let mut version = VERSION_LEGACY;
+    let mut flags = 0;
+    let mut version_size = 0;
+
+    let mut prn = try!(read_u32(bytes, &mut crc));
+
+    if is_version_marker(prn) {
+        version = ((prn >> 4) & 0xF) as u8;
+        flags = (prn & 0xF) as u8;
+        version_size = VERSION_SIZE;
+
+        trace!("Frame has version {} flags {:x}", version, flags);
+
+        //We don't know the layout of anything newer so we can't continue parsing
+        if version > VERSION_CURRENT || flags & !KNOWN_FLAGS != 0 {
+            error!("Unsupported frame version {} flags {:x}", version, flags);
+            return Err(ReadError::UnsupportedVersion)
+        }
+
+        prn = try!(read_u32(bytes, &mut crc));
+
+        //Bail before parsing anything else if this frame was merged with another or cut short
+        if flags & FLAG_LENGTH != 0 {
+            let declared_len = try!(bytes.read_u16::<BigEndian>().map_err(|e| ReadError::IO(e)));
+            crc = crc16::update_u16(declared_len, crc);
+            version_size += LENGTH_SIZE;
+
+            if declared_len as usize != size {
+                error!("Frame {} declared {} bytes but was {} bytes", prn, declared_len, size);
+                return Err(ReadError::Truncated)
+            }
+        }
+    }
 
     debug!("Decoding frame with PRN {} size {}", prn, size);
 
@@ -139,23 +418,36 @@ pub fn from_bytes<T>(bytes: &mut T, out_payload: &mut [u8], size: usize) -> Resu
         if value != 0 {
             error!("Malformed address in packet {}, {:?}", prn, addr);
             err = Some(ReadError::BadAddress);
+        } else {
+            addr_marker += 1;
         }
     }
 
-    let header_size = 4 + addr_len * 4 + 2;
+    //Both paths need at least one address, an extra separator shows up as an empty path since scanning stops at the
+    //second one. Anything after that is payload, which is free to start with zero bytes.
+    let empty_path = addr[0] == routing::ADDRESS_SEPARATOR
+        || addr[..addr_len.min(routing::MAX_LENGTH)].windows(2).any(|pair| pair[0] == routing::ADDRESS_SEPARATOR && pair[1] == routing::ADDRESS_SEPARATOR);
+
+    if addr_marker != 2 || empty_path {
+        error!("Malformed address in packet {}, {} separators {:?}", prn, addr_marker, addr);
+        err = Some(ReadError::BadAddress);
+    }
+
+    let header_size = version_size + 4 + addr_len * 4 + 2;
 
     if size < header_size {
         return Err(ReadError::IO(io::Error::new(io::ErrorKind::InvalidData, "Packet was malformed")))
     } 
 
-    //size - (PRN + ADDR size + CRC)
+    //size - (Version + PRN + ADDR size + CRC)
     let payload_size = size - header_size;
 
     debug!("Decode payload of {} bytes", payload_size);
 
+    //Nowhere to put the payload so we can't validate the CRC either
     if payload_size > out_payload.len() {
         error!("Payload exceeded output buffer size {} > {} in packet {}", payload_size, out_payload.len(), prn);
-        err = Some(ReadError::Truncated);
+        return Err(ReadError::Truncated)
     }
 
     use std::io::Read;
@@ -163,6 +455,10 @@ pub fn from_bytes<T>(bytes: &mut T, out_payload: &mut [u8], size: usize) -> Resu
 
     trace!("Read payload");
 
+    if log_enabled!(::log::LogLevel::Trace) {
+        trace!("Payload of {}\n{}", prn, util::hexdump(&out_payload[..payload_size]));
+    }
+
     //Update CRC
     crc = out_payload[..payload_size].iter().fold(crc, |crc, byte| {
         crc16::update_u8(*byte, crc)
@@ -172,7 +468,9 @@ pub fn from_bytes<T>(bytes: &mut T, out_payload: &mut [u8], size: usize) -> Resu
 
     let frame = (Frame {
         prn: prn,
-        address_route: addr
+        address_route: addr,
+        version: version,
+        flags: flags
     }, payload_size);
 
     crc = crc16::finish(crc);
@@ -193,6 +491,51 @@ pub fn from_bytes<T>(bytes: &mut T, out_payload: &mut [u8], size: usize) -> Resu
         .unwrap_or(Ok(frame))
 }
 
+/// Parses a frame from the front of `buf`, returning the frame, its payload and any bytes after the frame.
+///
+/// Frames that declare their length(`FLAG_LENGTH`) stop there and leave the rest of `buf`, otherwise the frame takes
+/// the whole buffer and the rest is empty.
+pub fn parse(buf: &[u8]) -> Result<(Frame, &[u8], &[u8]), ReadError> {
+    let mut size = buf.len();
+
+    //Only a length prefixed frame can tell us where it ends
+    if buf.len() >= VERSION_SIZE + 4 + LENGTH_SIZE {
+        let marker = try!(io::Cursor::new(buf).read_u32::<BigEndian>().map_err(|e| ReadError::IO(e)));
+
+        if is_version_marker(marker) && marker as u8 & FLAG_LENGTH != 0 {
+            let offset = VERSION_SIZE + 4;
+            let declared_len = try!(io::Cursor::new(&buf[offset..]).read_u16::<BigEndian>().map_err(|e| ReadError::IO(e))) as usize;
+
+            if declared_len > buf.len() {
+                error!("Frame declared {} bytes but only {} are available", declared_len, buf.len());
+                return Err(ReadError::Truncated)
+            }
+
+            size = declared_len;
+        }
+    }
+
+    let mut payload: [u8; MTU] = [0; MTU];
+    let (frame, payload_size) = try!(from_bytes(&mut io::Cursor::new(&buf[..size]), &mut payload, size));
+
+    //Payload sits right before the CRC
+    let payload_start = size - 2 - payload_size;
+
+    Ok((frame, &buf[payload_start..payload_start+payload_size], &buf[size..]))
+}
+
+/// Checks the trailing CRC of a serialized frame without parsing it, false if `buf` is too short to hold one
+pub fn verify_crc(buf: &[u8]) -> bool {
+    use byteorder::ByteOrder;
+
+    if buf.len() < 2 {
+        return false
+    }
+
+    let (frame, crc) = buf.split_at(buf.len() - 2);
+    crc16::calc(frame.iter().cloned()) == BigEndian::read_u16(crc)
+}
+
 fn write_u32<T>(value: u32, bytes: &mut T, crc: &mut crc16::CRC) -> Result<usize, WriteError> where T: io::Write {
    	try!(bytes.write_u32::<BigEndian>(value).map_err(|e| WriteError::IO(e)));
     *crc = crc16::update_u32(value, *crc);
@@ -202,14 +545,54 @@ fn write_u32<T>(value: u32, bytes: &mut T, crc: &mut crc16::CRC) -> Result<usize
 
 /// Convert a frame to a series of bytes.
 pub fn to_bytes<T>(bytes: &mut T, frame: &Frame, payload: Option<&[u8]>) -> Result<usize, WriteError> where T: io::Write {
+    let (mut size, crc) = try!(write_body(bytes, frame, payload));
+
+    //Last part of the packet is our CRC
+    let crc = crc16::finish(crc);
+
+    try!(bytes.write_u16::<BigEndian>(crc).map_err(|e| WriteError::IO(e)));
+    size += 2;
+
+    trace!("Finished encoding packet {} bytes", size);
+
+    Ok(size)
+}
+
+/// CRC that `to_bytes` would end the frame with, computed without writing it out anywhere
+pub fn frame_crc(frame: &Frame, payload: Option<&[u8]>) -> u16 {
+    //Writing to a sink can't fail
+    match write_body(&mut io::sink(), frame, payload) {
+        Ok((_, crc)) => crc16::finish(crc),
+        Err(_) => 0
+    }
+}
+
+/// Writes everything but the trailing CRC, returns the bytes written and the unfinished CRC over them
+fn write_body<T>(bytes: &mut T, frame: &Frame, payload: Option<&[u8]>) -> Result<(usize, crc16::CRC), WriteError> where T: io::Write {
     let mut crc = crc16::new();
     let mut size = 0;
 
     debug!("Encoding DATA frame {} to bytes", frame.prn);
 
-    //Start with PRN
+    //Versioned frames lead with a marker, legacy frames start with PRN
+    if frame.version != VERSION_LEGACY {
+        let marker = VERSION_MAGIC | ((frame.version as u32 & 0xF) << 4) | (frame.flags as u32 & 0xF);
+        size += try!(write_u32(marker, bytes, &mut crc));
+    }
+
     size += try!(write_u32(frame.prn, bytes, &mut crc));
 
+    if frame.version != VERSION_LEGACY && frame.flags & FLAG_LENGTH != 0 {
+        //Measure the rest of the frame so we know what length to declare
+        let mut unflagged = *frame;
+        unflagged.flags &= !FLAG_LENGTH;
+        let total_len = try!(to_bytes(&mut io::sink(), &unflagged, payload)) + LENGTH_SIZE;
+
+        try!(bytes.write_u16::<BigEndian>(total_len as u16).map_err(|e| WriteError::IO(e)));
+        crc = crc16::update_u16(total_len as u16, crc);
+        size += LENGTH_SIZE;
+    }
+
     //Address follows, it's in for format of <source>, 0x0, <dest>, 0x0
     let mut delim_count = 0;
     for addr in frame.address_route.iter() {
@@ -233,6 +616,10 @@ pub fn to_bytes<T>(bytes: &mut T, frame: &Frame, payload: Option<&[u8]>) -> Resu
     //Handle the actual payload
     match payload {
         Some(data) => {
+            if log_enabled!(::log::LogLevel::Trace) {
+                trace!("Payload of {}\n{}", frame.prn, util::hexdump(data));
+            }
+
             try!(bytes.write_all(data).map_err(|e| WriteError::IO(e)));
             size += data.len();
 
@@ -243,15 +630,22 @@ pub fn to_bytes<T>(bytes: &mut T, frame: &Frame, payload: Option<&[u8]>) -> Resu
         None => ()
     }
 
-    //Last part of the packet is our CRC
-    crc = crc16::finish(crc);
+    Ok((size, crc))
+}
 
-    try!(bytes.write_u16::<BigEndian>(crc).map_err(|e| WriteError::IO(e)));
-    size += 2;
+/// Convert a frame to bytes written into `bytes`, without allocating.
+///
+/// Returns the number of bytes written or `WriteError::Size` if `bytes` can't hold the whole frame.
+pub fn to_slice(bytes: &mut [u8], frame: &Frame, payload: Option<&[u8]>) -> Result<usize, WriteError> {
+    //Measure first so nothing is written if it won't fit
+    let required = try!(to_bytes(&mut io::sink(), frame, payload));
 
-    trace!("Finished encoding packet {} bytes", size);
+    if bytes.len() < required {
+        trace!("Unable to encode frame {} of {} bytes into {} byte buffer", frame.prn, required, bytes.len());
+        return Err(WriteError::Size(util::SizeError { required: required }))
+    }
 
-    Ok(size)
+    to_bytes(&mut io::Cursor::new(bytes), frame, payload)
 }
 
 #[cfg(test)]
@@ -451,7 +845,9 @@ fn test_max_size() {
         .chain(iter::once(routing::ADDRESS_SEPARATOR))
         .chain(iter::once(prn.callsign))
         .collect::<Vec<u32>>();
-    let header = new_header(&mut prn, route.iter().cloned()).unwrap();
+    let mut header = new_header(&mut prn, route.iter().cloned()).unwrap();
+    header.version = VERSION_CURRENT;
+    header.flags = FLAG_LENGTH;
 
     let mut packet = vec!();
 
@@ -459,9 +855,478 @@ fn test_max_size() {
 
     assert_eq!(MAX_PACKET_SIZE, packet.len());
 
-    let ack_header = new_ack(prn.next(), routing::gen_route(route.iter()));
+    let mut ack_header = new_ack(prn.next(), routing::gen_route(route.iter()));
+    ack_header.version = VERSION_CURRENT;
+    ack_header.flags = FLAG_LENGTH;
     packet.drain(..);
     to_bytes(&mut packet, &ack_header, None).unwrap();
 
     assert_eq!(MAX_ACK_SIZE, packet.len());
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_ack_round_trip() {
+    use std::io::Cursor;
+
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+    let data_header = new_header(&mut prn, [callsign, routing::ADDRESS_SEPARATOR, callsign].iter().cloned()).unwrap();
+
+    //Shortest ack we generate, a reply to a single hop frame
+    let ack = new_ack(data_header.prn, routing::reverse(&data_header.address_route));
+
+    let mut data = vec!();
+    let count = to_bytes(&mut data, &ack, None).unwrap();
+    assert!(count >= MIN_ACK_SIZE && count <= MAX_ACK_SIZE);
+
+    let mut payload = [0; MTU];
+    match from_bytes(&mut Cursor::new(&data), &mut payload, count) {
+        Ok((header, size)) => {
+            assert_eq!(header, ack);
+            assert!(is_ack(&header, &payload[..size]));
+        },
+        _ => assert!(false)
+    }
+
+    //Longest ack we can generate
+    let route = (0..routing::MAX_HOPS).map(|_| routing::BROADCAST_ADDRESS)
+        .chain(::std::iter::once(routing::ADDRESS_SEPARATOR))
+        .chain(::std::iter::once(callsign))
+        .collect::<Vec<u32>>();
+    let mut ack = new_ack(prn.next(), routing::gen_route(route.iter()));
+    ack.version = VERSION_CURRENT;
+    ack.flags = FLAG_LENGTH | FLAG_MULTI_ACK;
+
+    let mut data = vec!();
+    let count = to_bytes(&mut data, &ack, None).unwrap();
+    assert_eq!(count, MAX_ACK_SIZE);
+
+    match parse(&data) {
+        Ok((header, read_payload, _)) => {
+            assert_eq!(header, ack);
+            assert!(is_ack(&header, read_payload));
+        },
+        _ => assert!(false)
+    }
+
+    //Multi-acks count, data frames, keepalives and pings don't
+    let (multi, multi_payload) = new_multi_ack(&[1, 2], ack.address_route);
+    assert!(is_ack(&multi, &multi_payload));
+    assert!(!is_ack(&data_header, &[1]));
+    assert!(!is_ack(&new_keepalive(1, ack.address_route), &[]));
+    assert!(!is_ack(&new_ping(1, ack.address_route), &[]));
+
+    //Versioned frames with no payload are only acks when flagged as one
+    let mut signal = data_header;
+    signal.version = VERSION_CURRENT;
+    assert!(!is_ack(&signal, &[]));
+    signal.flags = FLAG_LENGTH;
+    assert!(!is_ack(&signal, &[]));
+}
+
+#[test]
+fn test_to_slice() {
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+    let header = new_header(&mut prn, [callsign, routing::ADDRESS_SEPARATOR, callsign].iter().cloned()).unwrap();
+    let payload = (0..32).collect::<Vec<u8>>();
+
+    let mut expected = vec!();
+    to_bytes(&mut expected, &header, Some(&payload)).unwrap();
+
+    let mut data = [0; MAX_PACKET_SIZE];
+    let written = to_slice(&mut data, &header, Some(&payload)).unwrap();
+    assert_eq!(&data[..written], expected.as_slice());
+
+    match to_slice(&mut data[..expected.len()-1], &header, Some(&payload)) {
+        Err(WriteError::Size(e)) => assert_eq!(e.required, expected.len()),
+        _ => assert!(false)
+    }
+}
+
+#[test]
+fn test_version() {
+    use std::io::Cursor;
+
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+    let route = [callsign, routing::ADDRESS_SEPARATOR, callsign];
+    let payload = [1, 2, 3];
+
+    //Legacy frames are unchanged on the wire
+    let legacy = new_header(&mut prn, route.iter().cloned()).unwrap();
+    let mut legacy_data = vec!();
+    to_bytes(&mut legacy_data, &legacy, Some(&payload)).unwrap();
+    assert_eq!(legacy_data.len(), 4 + 4 * 4 + payload.len() + 2);
+    assert!(!is_version_marker(Cursor::new(&legacy_data).read_u32::<BigEndian>().unwrap()));
+
+    let mut versioned = new_header(&mut prn, route.iter().cloned()).unwrap();
+    versioned.version = VERSION_CURRENT;
+    let mut data = vec!();
+    let count = to_bytes(&mut data, &versioned, Some(&payload)).unwrap();
+    assert_eq!(count, legacy_data.len() + VERSION_SIZE);
+
+    let mut read_payload = [0; MTU];
+    match from_bytes(&mut Cursor::new(&data), &mut read_payload, count) {
+        Ok((header, size)) => {
+            assert_eq!(header, versioned);
+            assert_eq!(&read_payload[..size], &payload);
+        },
+        _ => assert!(false)
+    }
+
+    //Every flag bit is in use so there's no undefined flag left to reject, new fields have to come with a new version
+    assert_eq!(KNOWN_FLAGS, 0xF);
+
+    //Versions from the future are rejected rather than misparsed
+    for &(version, flags) in [(VERSION_CURRENT + 1, 0), (VERSION_CURRENT + 1, FLAG_PING)].iter() {
+        let mut future = versioned;
+        future.version = version;
+        future.flags = flags;
+
+        let mut data = vec!();
+        let count = to_bytes(&mut data, &future, Some(&payload)).unwrap();
+        match from_bytes(&mut Cursor::new(&data), &mut read_payload, count) {
+            Err(ReadError::UnsupportedVersion) => (),
+            _ => assert!(false)
+        }
+    }
+}
+
+#[test]
+fn test_length_prefix() {
+    use std::io::Cursor;
+
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+    let mut header = new_header(&mut prn, [callsign, routing::ADDRESS_SEPARATOR, callsign].iter().cloned()).unwrap();
+    header.version = VERSION_CURRENT;
+    header.flags = FLAG_LENGTH;
+
+    let payload = [1, 2, 3, 4, 5];
+    let mut data = vec!();
+    let count = to_bytes(&mut data, &header, Some(&payload)).unwrap();
+    assert_eq!(count, data.len());
+    assert_eq!(count, VERSION_SIZE + 4 + LENGTH_SIZE + 4 * 4 + payload.len() + 2);
+
+    let mut read_payload = [0; MTU];
+    match from_bytes(&mut Cursor::new(&data), &mut read_payload, count) {
+        Ok((read_header, size)) => {
+            assert_eq!(read_header, header);
+            assert_eq!(&read_payload[..size], &payload);
+        },
+        _ => assert!(false)
+    }
+
+    //Frame that was merged with the start of the next one should fail before the address is parsed
+    let mut merged = data.clone();
+    merged.extend_from_slice(&data[..8]);
+    match from_bytes(&mut Cursor::new(&merged), &mut read_payload, merged.len()) {
+        Err(ReadError::Truncated) => (),
+        _ => assert!(false)
+    }
+
+    //As should one that was cut short
+    match from_bytes(&mut Cursor::new(&data[..count-3]), &mut read_payload, count-3) {
+        Err(ReadError::Truncated) => (),
+        _ => assert!(false)
+    }
+}
+
+#[test]
+fn test_parse() {
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+    let route = [callsign, routing::ADDRESS_SEPARATOR, callsign];
+    let payload = [1, 2, 3, 4, 5];
+
+    //Legacy frames take the whole buffer
+    let legacy = new_header(&mut prn, route.iter().cloned()).unwrap();
+    let mut data = vec!();
+    to_bytes(&mut data, &legacy, Some(&payload)).unwrap();
+
+    match parse(&data) {
+        Ok((header, read_payload, rest)) => {
+            assert_eq!(header, legacy);
+            assert_eq!(read_payload, &payload);
+            assert_eq!(rest.len(), 0);
+        },
+        _ => assert!(false)
+    }
+
+    //Length prefixed frames leave whatever follows them
+    let mut header = new_header(&mut prn, route.iter().cloned()).unwrap();
+    header.version = VERSION_CURRENT;
+    header.flags = FLAG_LENGTH;
+
+    let mut data = vec!();
+    to_bytes(&mut data, &header, Some(&payload)).unwrap();
+    to_bytes(&mut data, &header, None).unwrap();
+
+    let rest = match parse(&data) {
+        Ok((read_header, read_payload, rest)) => {
+            assert_eq!(read_header, header);
+            assert_eq!(read_payload, &payload);
+            rest
+        },
+        _ => panic!("Failed to parse first frame")
+    };
+
+    match parse(rest) {
+        Ok((read_header, read_payload, rest)) => {
+            assert_eq!(read_header, header);
+            assert_eq!(read_payload.len(), 0);
+            assert_eq!(rest.len(), 0);
+        },
+        _ => assert!(false)
+    }
+
+    //Cut short before the declared length
+    match parse(&rest[..rest.len()-1]) {
+        Err(ReadError::Truncated) => (),
+        _ => assert!(false)
+    }
+
+    match parse(&data[..4]) {
+        Err(ReadError::Truncated) => (),
+        _ => assert!(false)
+    }
+}
+
+#[test]
+fn test_error_display() {
+    use std::error::Error;
+
+    assert_eq!(format!("{}", ReadError::IO(io::Error::new(io::ErrorKind::Other, "oops"))), "IO error reading frame: oops");
+    assert_eq!(format!("{}", ReadError::Truncated), "Frame was truncated");
+    assert_eq!(format!("{}", ReadError::BadAddress), "Frame address was malformed");
+    assert_eq!(format!("{}", ReadError::CRCFailure), "Frame failed CRC check");
+    assert_eq!(format!("{}", ReadError::UnsupportedVersion), "Frame version or flags are not supported");
+
+    assert_eq!(format!("{}", EncodeError::AddressTooLong), "Route has too many addresses");
+    assert_eq!(format!("{}", EncodeError::AddressSeparatorNotFound), "Route is missing a source separator");
+    assert_eq!(format!("{}", EncodeError::EmptyRoute), "Route has no hops");
+    assert_eq!(format!("{}", EncodeError::UnexpectedSeparator), "Hops contain a source separator");
+
+    let err = WriteError::IO(io::Error::new(io::ErrorKind::Other, "oops"));
+    assert_eq!(format!("{}", err), "IO error writing frame: oops");
+    assert_eq!(format!("{}", err.source().unwrap()), "oops");
+    assert!(ReadError::Truncated.source().is_none());
+}
+
+#[test]
+fn test_ping() {
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let route = routing::gen_route(&[callsign, routing::ADDRESS_SEPARATOR, callsign]);
+
+    let ping = new_ping(10, route);
+    assert!(is_ping(&ping));
+    assert_eq!(pong_prn(&ping, &[]), None);
+
+    let (pong, payload) = new_pong(20, 10, route);
+    assert!(is_ping(&pong));
+    assert_eq!(pong.prn, 20);
+
+    let mut data = vec!();
+    let count = to_bytes(&mut data, &pong, Some(&payload)).unwrap();
+    match parse(&data[..count]) {
+        Ok((header, read_payload, _)) => {
+            assert_eq!(header, pong);
+            assert_eq!(pong_prn(&header, read_payload), Some(10));
+        },
+        _ => assert!(false)
+    }
+
+    assert!(!is_ping(&new_ack(10, route)));
+}
+
+#[test]
+fn test_multi_ack() {
+    use std::io::Cursor;
+
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let route = routing::gen_route(&[callsign, routing::ADDRESS_SEPARATOR, callsign]);
+    let prns = [10, 20, 30];
+
+    let (header, payload) = new_multi_ack(&prns, route);
+    assert!(is_multi_ack(&header));
+    assert_eq!(payload.len(), 2 * 4);
+
+    let mut data = vec!();
+    let count = to_bytes(&mut data, &header, Some(&payload)).unwrap();
+
+    let mut read_payload = [0; MTU];
+    match from_bytes(&mut Cursor::new(&data), &mut read_payload, count) {
+        Ok((read_header, size)) => {
+            assert_eq!(read_header, header);
+            assert_eq!(multi_ack_prns(&read_header, &read_payload[..size]), prns.to_vec());
+        },
+        _ => assert!(false)
+    }
+
+    assert!(!is_multi_ack(&new_ack(10, route)));
+}
+
+#[test]
+fn test_hash() {
+    use std::collections::HashSet;
+
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+    let route = [callsign, routing::ADDRESS_SEPARATOR, callsign];
+
+    let first = new_header(&mut prn, route.iter().cloned()).unwrap();
+    let second = new_header(&mut prn, route.iter().cloned()).unwrap();
+    let mut versioned = first;
+    versioned.version = VERSION_CURRENT;
+
+    let mut frames = HashSet::new();
+    assert!(frames.insert(first));
+    assert!(frames.insert(second));
+    assert!(frames.insert(versioned));
+    assert!(!frames.insert(first));
+
+    //Every field ends up on the wire so equal frames encode the same and different ones don't
+    let encode = |frame: &Frame| {
+        let mut data = vec!();
+        to_bytes(&mut data, frame, None).unwrap();
+        data
+    };
+
+    assert_eq!(encode(&first), encode(&first.clone()));
+    assert!(encode(&first) != encode(&second));
+    assert!(encode(&first) != encode(&versioned));
+}
+
+#[test]
+fn test_min_size() {
+    use std::io::Cursor;
+
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+    let route = [callsign, routing::ADDRESS_SEPARATOR, callsign];
+
+    let header = new_header(&mut prn, route.iter().cloned()).unwrap();
+    let mut data = vec!();
+    assert_eq!(to_bytes(&mut data, &header, None).unwrap(), MIN_ACK_SIZE);
+
+    let mut with_payload = vec!();
+    assert_eq!(to_bytes(&mut with_payload, &header, Some(&[1])).unwrap(), MIN_DATA_SIZE);
+
+    let mut payload = [0; MTU];
+    for size in 0..MIN_ACK_SIZE {
+        match from_bytes(&mut Cursor::new(&data[..size]), &mut payload, size) {
+            Err(ReadError::Truncated) => (),
+            _ => assert!(false)
+        }
+    }
+
+    assert!(from_bytes(&mut Cursor::new(&data), &mut payload, MIN_ACK_SIZE).is_ok());
+}
+
+#[test]
+fn test_separator_count() {
+    use std::io::Cursor;
+
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let dest = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+    let mut header = new_header(&mut prn, [dest, routing::ADDRESS_SEPARATOR, callsign].iter().cloned()).unwrap();
+
+    //Payload that happens to start with a separator is fine after a well formed route
+    let mut payload_out = [0; MTU];
+    let data = [0, 0, 0, 0, 1, 2, 3];
+    let mut bytes = vec!();
+    let count = to_bytes(&mut bytes, &header, Some(&data)).unwrap();
+    match from_bytes(&mut Cursor::new(&bytes), &mut payload_out, count) {
+        Ok((read, size)) => {
+            assert_eq!(read, header);
+            assert_eq!(&payload_out[..size], &data);
+        },
+        _ => assert!(false)
+    }
+
+    //An extra separator leaves one of the paths empty and would shift the rest of the route into the payload
+    for route in [
+        [dest, routing::ADDRESS_SEPARATOR, routing::ADDRESS_SEPARATOR, callsign, routing::ADDRESS_SEPARATOR],
+        [routing::ADDRESS_SEPARATOR, dest, routing::ADDRESS_SEPARATOR, callsign, routing::ADDRESS_SEPARATOR]
+    ].iter() {
+        header.address_route = routing::gen_route(route.iter());
+
+        let mut bytes = vec!();
+        let count = to_bytes(&mut bytes, &header, Some(&[1, 2, 3, 4, 5, 6, 7, 8])).unwrap();
+        match from_bytes(&mut Cursor::new(&bytes), &mut payload_out, count) {
+            Err(ReadError::BadAddress) => (),
+            _ => assert!(false)
+        }
+    }
+}
+
+#[test]
+fn test_frame_crc() {
+    use byteorder::ByteOrder;
+
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+    let mut header = new_header(&mut prn, [callsign, routing::ADDRESS_SEPARATOR, callsign].iter().cloned()).unwrap();
+
+    let mut bytes = vec!();
+    to_bytes(&mut bytes, &header, Some(&[1, 2, 3])).unwrap();
+    assert_eq!(frame_crc(&header, Some(&[1, 2, 3])), BigEndian::read_u16(&bytes[bytes.len()-2..]));
+    assert!(frame_crc(&header, Some(&[1, 2, 4])) != frame_crc(&header, Some(&[1, 2, 3])));
+
+    header.version = VERSION_CURRENT;
+    header.flags = FLAG_LENGTH;
+    let mut bytes = vec!();
+    to_bytes(&mut bytes, &header, None).unwrap();
+    assert_eq!(frame_crc(&header, None), BigEndian::read_u16(&bytes[bytes.len()-2..]));
+}
+
+#[test]
+fn test_verify_crc() {
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+    let mut header = new_header(&mut prn, [callsign, routing::ADDRESS_SEPARATOR, callsign].iter().cloned()).unwrap();
+
+    let mut bytes = vec!();
+    to_bytes(&mut bytes, &header, Some(&[1, 2, 3])).unwrap();
+    assert!(verify_crc(&bytes));
+
+    //Any flipped bit fails, including in the CRC itself
+    for idx in 0..bytes.len() {
+        let mut flipped = bytes.clone();
+        flipped[idx] ^= 1 << (idx % 8);
+        assert!(!verify_crc(&flipped));
+    }
+
+    //Versioned frames cover the marker and length too
+    header.version = VERSION_CURRENT;
+    header.flags = FLAG_LENGTH;
+    let mut bytes = vec!();
+    to_bytes(&mut bytes, &header, None).unwrap();
+    assert!(verify_crc(&bytes));
+    assert!(!verify_crc(&bytes[1..]));
+
+    assert!(!verify_crc(&[]));
+    assert!(!verify_crc(&[0xFF]));
+}
+
+#[test]
+fn test_route_accessors() {
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let relay = address::encode(['W', '7', 'R', 'L', 'Y', '0', '0']).unwrap();
+    let dest = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+
+    let header = new_header(&mut prn, [relay, dest, routing::ADDRESS_SEPARATOR, callsign].iter().cloned()).unwrap();
+    assert_eq!(header.dest(), relay);
+    assert_eq!(header.final_dest(), dest);
+    assert_eq!(header.source(), callsign);
+
+    //Once relayed the next hop is the final destination and the relay is on the return path
+    let mut relayed = header;
+    relayed.address_route = routing::gen_route(&[dest, routing::ADDRESS_SEPARATOR, relay, callsign]);
+    assert_eq!(relayed.dest(), dest);
+    assert_eq!(relayed.final_dest(), dest);
+    assert_eq!(relayed.source(), callsign);
+}
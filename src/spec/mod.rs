@@ -4,4 +4,124 @@ pub mod prn_id;
 pub mod crc16;
 pub mod routing;
 
-pub mod node;
\ No newline at end of file
+pub mod node;
+
+use std::io;
+use std::fmt;
+use std::error;
+use kiss;
+
+/// Error cases for decoding a KISS wrapped packet
+#[derive(Debug)]
+pub enum PacketError {
+    /// No complete KISS frame is available yet
+    Incomplete,
+    /// A KISS command frame other than data was found, along with the number of bytes it took
+    Command(u8, usize),
+    /// The KISS frame didn't hold a valid frame, along with the number of bytes it took
+    Frame(frame::ReadError, usize)
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PacketError::Incomplete => write!(f, "No complete KISS frame available"),
+            PacketError::Command(cmd, _) => write!(f, "KISS command {} is not a data frame", cmd),
+            PacketError::Frame(ref e, _) => write!(f, "KISS frame didn't hold a valid frame: {}", e)
+        }
+    }
+}
+
+impl error::Error for PacketError {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            PacketError::Frame(ref e, _) => Some(e),
+            _ => None
+        }
+    }
+}
+
+/// Decodes the first KISS frame in `bytes` into a frame and its payload, without a `Node`.
+/// Returns the number of bytes consumed from `bytes`, including any noise ahead of the frame.
+pub fn decode_packet(bytes: &[u8]) -> Result<(frame::Frame, Vec<u8>, usize), PacketError> {
+    let mut raw = vec!();
+    let decoded = match kiss::decode(bytes.iter().cloned(), &mut raw) {
+        Some(decoded) => decoded,
+        None => return Err(PacketError::Incomplete)
+    };
+
+    if decoded.command != kiss::CMD_DATA {
+        return Err(PacketError::Command(decoded.command, decoded.bytes_read))
+    }
+
+    match frame::parse(&raw[..decoded.payload_size]) {
+        //Anything after a length prefixed frame means it was merged with something else
+        Ok((_, _, rest)) if rest.len() > 0 => Err(PacketError::Frame(frame::ReadError::Truncated, decoded.bytes_read)),
+        Ok((packet, payload, _)) => Ok((packet, payload.to_vec(), decoded.bytes_read)),
+        Err(e) => Err(PacketError::Frame(e, decoded.bytes_read))
+    }
+}
+
+/// Encodes a frame and payload into a KISS data frame on port 0, ready to write to a TNC
+pub fn encode_packet(header: &frame::Frame, payload: Option<&[u8]>) -> Vec<u8> {
+    let mut packet = vec!();
+    let mut encoded = vec!();
+
+    //Writing to a Vec can't fail
+    frame::to_bytes(&mut packet, header, payload).unwrap();
+    kiss::encode(&mut io::Cursor::new(&packet), &mut encoded, 0).unwrap();
+
+    encoded
+}
+
+#[test]
+fn test_packet_round_trip() {
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let dest = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+    let relay = address::encode(['W', '7', 'R', 'L', 'Y', '0', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+
+    let header = frame::new_header(&mut prn, [dest, routing::ADDRESS_SEPARATOR, callsign].iter().cloned()).unwrap();
+    let payload = [kiss::FEND, 1, 2, kiss::FESC, 3];
+
+    //Noise ahead and a partial frame behind are left for the caller
+    let mut bytes = vec!(0x55);
+    bytes.extend_from_slice(&encode_packet(&header, Some(&payload)));
+    let first_len = bytes.len();
+    bytes.push(kiss::FEND);
+
+    let (decoded, decoded_payload, consumed) = decode_packet(&bytes).unwrap();
+    assert_eq!(decoded, header);
+    assert_eq!(decoded_payload.as_slice(), &payload);
+    assert_eq!(consumed, first_len);
+
+    match decode_packet(&bytes[consumed..]) {
+        Err(PacketError::Incomplete) => (),
+        _ => assert!(false)
+    }
+
+    //Rewrite the route and it comes back out the same
+    let mut rewritten = decoded;
+    rewritten.address_route = routing::gen_route(&[relay, dest, routing::ADDRESS_SEPARATOR, callsign]);
+    let (decoded, decoded_payload, _) = decode_packet(&encode_packet(&rewritten, Some(&decoded_payload))).unwrap();
+    assert_eq!(decoded, rewritten);
+    assert_eq!(decoded_payload.as_slice(), &payload);
+
+    //Acks have no payload
+    let ack = frame::new_ack(header.prn, header.address_route);
+    let (decoded, decoded_payload, _) = decode_packet(&encode_packet(&ack, None)).unwrap();
+    assert_eq!(decoded, ack);
+    assert_eq!(decoded_payload.len(), 0);
+
+    let mut cmd = vec!();
+    kiss::encode_cmd(&mut cmd, kiss::CMD_TX_DELAY, 10, 0);
+    match decode_packet(&cmd) {
+        Err(PacketError::Command(kiss::CMD_TX_DELAY, 4)) => (),
+        _ => assert!(false)
+    }
+
+    match decode_packet(&[kiss::FEND, kiss::CMD_DATA, 1, 2, 3, kiss::FEND]) {
+        Err(PacketError::Frame(_, 6)) => (),
+        _ => assert!(false)
+    }
+}
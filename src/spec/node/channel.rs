@@ -0,0 +1,14 @@
+///! Channel access hooks so a node can share a half-duplex link
+
+/// Reports whether the channel is free to transmit on, typically backed by a TNC's DCD signal
+pub trait ChannelState {
+    /// True if nobody else is transmitting, the default assumes we always have the channel
+    fn is_clear(&self) -> bool {
+        true
+    }
+}
+
+/// Channel that is always clear, used when there's no way to sense the carrier
+pub struct AlwaysClear;
+
+impl ChannelState for AlwaysClear {}
@@ -0,0 +1,27 @@
+///! Time sources so a node can work out how long it's been between ticks itself
+use std::time::Instant;
+
+/// Monotonic time in ms from an arbitrary starting point
+pub trait Clock {
+    /// Current time in ms, must never go backwards
+    fn now_ms(&self) -> u64;
+}
+
+/// Clock backed by `std::time::Instant`, used unless another one is provided
+pub struct SystemClock {
+    start: Instant
+}
+
+/// Constructs a system clock starting from now
+pub fn new_system() -> SystemClock {
+    SystemClock {
+        start: Instant::now()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        let elapsed = self.start.elapsed();
+        elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64
+    }
+}
@@ -0,0 +1,158 @@
+///! Drives a node and its transport, surfacing everything that happens as events
+use std::io;
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
+use spec::node;
+use spec::frame;
+use spec::prn_id;
+
+/// How often we check the transport for data when nothing else is due
+pub const READ_POLL_MS: u64 = 10;
+
+/// Owns a node and the transport it talks over
+pub struct Driver<T> where T: io::Read + io::Write {
+    node: node::Node,
+    transport: T,
+    /// Last time we ticked the node
+    last_tick: Instant
+}
+
+/// Constructs a new driver for a node communicating over `transport`
+pub fn new<T>(node: node::Node, transport: T) -> Driver<T> where T: io::Read + io::Write {
+    Driver {
+        node: node,
+        transport: transport,
+        last_tick: Instant::now()
+    }
+}
+
+/// Converts a node error that didn't come from the transport into an IO error
+fn to_io_error<E>(err: E) -> io::Error where E: ::std::fmt::Debug {
+    io::Error::new(io::ErrorKind::Other, format!("{:?}", err))
+}
+
+impl<T> Driver<T> where T: io::Read + io::Write {
+    /// Node being driven
+    pub fn node(&self) -> &node::Node {
+        &self.node
+    }
+
+    /// Node being driven
+    pub fn node_mut(&mut self) -> &mut node::Node {
+        &mut self.node
+    }
+
+    /// Sends a packet out over our transport. Returns the PRN of the packet that was sent
+    pub fn send<A>(&mut self, data: &[u8], addr_route: A) -> Result<prn_id::PrnValue, node::SendError> where A: Iterator<Item=u32> {
+        self.node.send_slice(data, addr_route, &mut self.transport)
+    }
+
+    /// Receives and ticks the node until something happens or `timeout` elapses. Sleeps between
+    /// checks, waking early when a retry is due.
+    pub fn poll(&mut self, timeout: Duration) -> io::Result<Vec<node::Event>> {
+        let start = Instant::now();
+
+        loop {
+            let events = try!(self.poll_once());
+
+            let waited = start.elapsed();
+            if events.len() > 0 || waited >= timeout {
+                return Ok(events)
+            }
+
+            //Sleep until we need to read again, a retry is due or we run out of time
+            let mut sleep = cmp::min(timeout - waited, Duration::from_millis(READ_POLL_MS));
+            if let Some(deadline) = self.node.next_tick_deadline() {
+                sleep = cmp::min(sleep, Duration::from_millis(deadline as u64));
+            }
+
+            thread::sleep(sleep);
+        }
+    }
+
+    /// Runs a single recv + tick pass, collecting any events
+    fn poll_once(&mut self) -> io::Result<Vec<node::Event>> {
+        //Only consume whole ms so we don't drift
+        let elapsed_ms = {
+            let elapsed = self.last_tick.elapsed();
+            elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64
+        };
+        self.last_tick += Duration::from_millis(elapsed_ms);
+
+        let mut events = vec!();
+
+        match self.node.pump(&mut self.transport, elapsed_ms as usize, &mut events) {
+            Ok(()) => (),
+            Err(node::RecvError::Io(e)) => return Err(e),
+            Err(node::RecvError::Send(node::SendError::Io(e))) => return Err(e),
+            Err(node::RecvError::Send(node::SendError::Write(frame::WriteError::IO(e)))) => return Err(e),
+            Err(e) => return Err(to_io_error(e))
+        }
+
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+use spec::address;
+#[cfg(test)]
+use util;
+
+#[test]
+fn test_poll() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let (local_io, remote_io) = util::duplex();
+    let mut local = new(node::new(local_addr), local_io);
+    let mut remote = new(node::new(remote_addr), remote_io);
+
+    let data = [1, 2, 3, 4, 5];
+    let prn = local.send(&data, [remote_addr].iter().cloned()).unwrap();
+
+    //Frames addressed to us are also observed
+    let events = remote.poll(Duration::from_millis(100)).unwrap();
+    assert_eq!(events.len(), 2);
+    match events[0] {
+        node::Event::Recv(ref header, ref payload) => {
+            assert_eq!(header.prn, prn);
+            assert_eq!(payload.as_slice(), &data);
+        },
+        _ => assert!(false)
+    }
+
+    let events = local.poll(Duration::from_millis(100)).unwrap();
+    assert_eq!(events.len(), 2);
+    match events[0] {
+        node::Event::Ack(ref header) => assert_eq!(header.prn, prn),
+        _ => assert!(false)
+    }
+
+    //Nothing left to happen so we should wait out the timeout
+    let events = local.poll(Duration::from_millis(20)).unwrap();
+    assert_eq!(events.len(), 0);
+}
+
+#[test]
+fn test_poll_retry() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let (local_io, _remote_io) = util::duplex();
+    let mut local = new(node::new(local_addr), local_io);
+
+    let prn = local.send(&[1, 2, 3], [remote_addr].iter().cloned()).unwrap();
+
+    //Nobody acks so we should wake up for the first retry
+    let start = Instant::now();
+    let events = local.poll(Duration::from_millis(node::tx_queue::RETRY_DELAY_MS as u64 * 4)).unwrap();
+
+    //The retry clock starts when the driver is created, just before we started timing
+    assert!(start.elapsed() >= Duration::from_millis(node::tx_queue::RETRY_DELAY_MS as u64 - READ_POLL_MS));
+    assert_eq!(events.len(), 1);
+    match events[0] {
+        node::Event::Retry(ref header, _) => assert_eq!(header.prn, prn),
+        _ => assert!(false)
+    }
+}
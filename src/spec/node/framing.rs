@@ -0,0 +1,123 @@
+///! Framing used to delimit packets on the wire, KISS unless the link needs something else
+use std::io;
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use kiss;
+use util;
+
+/// Size of the length prefix written by `LengthPrefixFraming`
+pub const LENGTH_PREFIX_SIZE: usize = 2;
+
+/// Splits a byte stream into frames and wraps outgoing packets so the other side can do the same
+pub trait Framing {
+    /// Writes `data` as a single frame on `port`, returns the number of bytes written
+    fn encode(&self, data: &[u8], encoded: &mut io::Write, port: u8) -> io::Result<usize>;
+
+    /// Same as `encode` but without allocating, `encoded` is left untouched if it's too small
+    fn encode_slice(&self, data: &[u8], encoded: &mut [u8], port: u8) -> Result<usize, util::SizeError>;
+
+    /// Decodes the first complete frame in `data` into `decoded`, None if there isn't one yet
+    fn decode(&self, data: &[u8], decoded: &mut Vec<u8>) -> Option<kiss::DecodedFrame>;
+
+    /// Number of bytes at the start of `data` that can never be part of a frame and can be dropped
+    fn noise(&self, _data: &[u8]) -> usize {
+        0
+    }
+}
+
+/// KISS framing for talking to a TNC, used unless another framing is provided
+pub struct KissFraming;
+
+impl Framing for KissFraming {
+    fn encode(&self, data: &[u8], encoded: &mut io::Write, port: u8) -> io::Result<usize> {
+        kiss::encode(&mut io::Cursor::new(data), &mut util::new_write_dispatch(encoded), port)
+    }
+
+    fn encode_slice(&self, data: &[u8], encoded: &mut [u8], port: u8) -> Result<usize, util::SizeError> {
+        kiss::encode_slice(data, encoded, port)
+    }
+
+    fn decode(&self, data: &[u8], decoded: &mut Vec<u8>) -> Option<kiss::DecodedFrame> {
+        kiss::decode(data.iter().cloned(), decoded)
+    }
+
+    //Frames start with a FEND so anything ahead of the first one is line noise that will never decode.
+    //Without a FEND we can't tell noise from the tail of a frame so leave that to the overflow check.
+    fn noise(&self, data: &[u8]) -> usize {
+        data.iter().position(|byte| *byte == kiss::FEND).unwrap_or(0)
+    }
+}
+
+/// Big-endian u16 length followed by the frame, for links without a TNC such as TCP to a software modem.
+/// There are no ports or commands, everything decodes as `kiss::CMD_DATA` on port 0.
+pub struct LengthPrefixFraming;
+
+impl Framing for LengthPrefixFraming {
+    fn encode(&self, data: &[u8], encoded: &mut io::Write, _port: u8) -> io::Result<usize> {
+        if data.len() > u16::max_value() as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Frame too large for a length prefix"))
+        }
+
+        try!(encoded.write_u16::<BigEndian>(data.len() as u16));
+        try!(encoded.write_all(data));
+
+        Ok(LENGTH_PREFIX_SIZE + data.len())
+    }
+
+    fn encode_slice(&self, data: &[u8], encoded: &mut [u8], _port: u8) -> Result<usize, util::SizeError> {
+        let required = LENGTH_PREFIX_SIZE + data.len();
+        if encoded.len() < required || data.len() > u16::max_value() as usize {
+            return Err(util::SizeError { required: required })
+        }
+
+        BigEndian::write_u16(&mut encoded[..LENGTH_PREFIX_SIZE], data.len() as u16);
+        encoded[LENGTH_PREFIX_SIZE..required].copy_from_slice(data);
+
+        Ok(required)
+    }
+
+    fn decode(&self, data: &[u8], decoded: &mut Vec<u8>) -> Option<kiss::DecodedFrame> {
+        if data.len() < LENGTH_PREFIX_SIZE {
+            return None
+        }
+
+        let len = BigEndian::read_u16(&data[..LENGTH_PREFIX_SIZE]) as usize;
+        if data.len() < LENGTH_PREFIX_SIZE + len {
+            return None
+        }
+
+        decoded.extend_from_slice(&data[LENGTH_PREFIX_SIZE..LENGTH_PREFIX_SIZE + len]);
+
+        Some(kiss::DecodedFrame {
+            port: 0,
+            command: kiss::CMD_DATA,
+            bytes_read: LENGTH_PREFIX_SIZE + len,
+            payload_size: len,
+            skipped: 0
+        })
+    }
+}
+
+#[test]
+fn test_length_prefix() {
+    let framing = LengthPrefixFraming;
+
+    let mut encoded = vec!();
+    assert_eq!(framing.encode(&[1, 2, kiss::FEND], &mut encoded, 3).unwrap(), 5);
+    assert_eq!(encoded, vec!(0, 3, 1, 2, kiss::FEND));
+
+    let mut slice = [0; 5];
+    assert_eq!(framing.encode_slice(&[1, 2, kiss::FEND], &mut slice, 3).unwrap(), 5);
+    assert_eq!(&slice[..], &encoded[..]);
+    assert_eq!(framing.encode_slice(&[1, 2, kiss::FEND], &mut slice[..4], 3).unwrap_err().required, 5);
+
+    //Partial frames wait for the rest
+    let mut decoded = vec!();
+    assert!(framing.decode(&encoded[..4], &mut decoded).is_none());
+    assert!(decoded.is_empty());
+
+    let frame = framing.decode(&encoded, &mut decoded).unwrap();
+    assert_eq!(frame.bytes_read, 5);
+    assert_eq!(frame.payload_size, 3);
+    assert_eq!(frame.command, kiss::CMD_DATA);
+    assert_eq!(decoded, vec!(1, 2, kiss::FEND));
+}
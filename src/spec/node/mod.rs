@@ -1,22 +1,230 @@
 pub mod prn_table;
 pub mod tx_queue;
+pub mod driver;
+pub mod channel;
+pub mod clock;
+pub mod framing;
 
 use std::io;
+use std::fmt;
+use std::error;
 use std::mem;
+use std::cmp;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use spec::prn_id;
 use spec::frame;
 use spec::routing;
 use spec::address;
 use kiss;
+use util;
 
 pub struct Node {
     prn: prn_id::PRN,
     
     recv_prn_table: prn_table::Table,
+    /// If false every frame is delivered, even ones we've already seen
+    dedup: bool,
+    /// Only listen, frames are never acked, forwarded or answered
+    monitor: bool,
+    /// Hold acks until the end of each recv and send them as multi-acks
+    coalesce_acks: bool,
+    /// Acks held for coalescing as PRN, return route and port
+    pending_acks: Vec<(prn_id::PrnValue, routing::Route, u8)>,
     tx_queue: tx_queue::Queue,
 
+    /// Bitmask of KISS ports we accept frames from
+    rx_ports: u16,
+    /// KISS port that new frames are sent out on
+    tx_port: u8,
+    /// Largest payload we'll send, never more than frame::MTU
+    max_payload: usize,
+    /// Longest forward path we'll send or relay
+    max_hops: usize,
+
     recv_buffer: Vec<u8>,
-    kiss_frame_scratch: Vec<u8>
+    /// Most bytes we'll buffer without finding a complete frame
+    max_recv_buffer: usize,
+    kiss_frame_scratch: Vec<u8>,
+    /// How frames are delimited on the wire
+    framing: Box<framing::Framing + Send>,
+
+    /// KISS encoded bytes written to tx_drain, including acks, forwards and retries
+    bytes_transmitted: u64,
+
+    /// Consulted before sending or retrying frames, busy channels defer them
+    channel: Box<channel::ChannelState + Send>,
+
+    /// Total elapsed ms passed to tick
+    clock_ms: u64,
+    /// Source of time for tick_now
+    clock: Box<clock::Clock + Send>,
+    /// Reading of `clock` at the last tick_now, None until the first
+    last_tick_now: Option<u64>,
+    /// Stations we've received frames from
+    heard: HashMap<u32, HeardStation>,
+    /// How often we broadcast a keepalive, None if disabled
+    keepalive_interval: Option<usize>,
+    /// Elapsed ms since our last keepalive
+    since_keepalive: usize,
+    /// How often we beacon our callsign and the payload to send, None if disabled
+    beacon: Option<(usize, Vec<u8>)>,
+    /// Elapsed ms since we last transmitted anything, cleared on every transmit
+    since_beacon: usize,
+
+    /// Answer pings addressed to us
+    ping_reply: bool,
+    /// Pings we've sent that haven't been answered, with the `clock_ms` they were sent at
+    pings: HashMap<prn_id::PrnValue, u64>,
+    /// Called with the PRN and round trip time in ms when a ping we sent is answered
+    on_pong: Option<Box<FnMut(prn_id::PrnValue, u64) + Send>>,
+    /// Called with the PRN and round trip time in ms when a packet we sent is acked
+    on_ack: Option<Box<FnMut(prn_id::PrnValue, u64) + Send>>,
+
+    /// While set recv stops processing frames and leaves them buffered
+    stop: Option<Arc<AtomicBool>>
+}
+
+/// Station we've received frames from, see `Node::heard_stations`
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeardStation {
+    /// Source address of the frames
+    pub addr: u32,
+    /// Time on `clock_ms` of the last frame we received from it
+    pub last_heard: u64,
+    /// Number of frames we've received from it
+    pub count: u64,
+    /// Already reported as gone by check_links
+    timed_out: bool
+}
+
+/// Owned record of something that happened on a node, used where callbacks are inconvenient
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// Data frame that was addressed to us
+    Recv(frame::Frame, Vec<u8>),
+    /// Ack for a frame that we sent
+    Ack(frame::Frame),
+    /// Frame that we sent was resent, with the ms until the next retry
+    Retry(frame::Frame, usize),
+    /// Frame that we sent exceeded its retries and was discarded
+    Expire(frame::Frame, Vec<u8>),
+    /// Frame that we relayed to the next hop in its route
+    Forward(frame::Frame, Vec<u8>),
+    /// Data or ack frame we heard that we didn't relay, including ones addressed to us
+    Observe(frame::Frame, Vec<u8>),
+    /// Congestion control started(true) or stopped(false)
+    Congestion(bool)
+}
+
+/// Frame seen by observe_drain, typed so clients don't have to work out what kind of frame it was
+#[derive(Debug, PartialEq)]
+pub enum ObservedFrame<'a> {
+    /// Data frame that we didn't relay
+    Data {
+        header: &'a frame::Frame,
+        payload: &'a [u8]
+    },
+    /// Ack that we didn't relay, `src` is the station that sent the ack
+    Ack {
+        header: &'a frame::Frame,
+        src: u32
+    },
+    /// Frame we relayed to the next hop in its route, `header` is the frame as we heard it
+    Forwarded {
+        header: &'a frame::Frame,
+        payload: &'a [u8]
+    }
+}
+
+impl<'a> ObservedFrame<'a> {
+    /// Header of the observed frame
+    pub fn header(&self) -> &'a frame::Frame {
+        match *self {
+            ObservedFrame::Data { header, .. } => header,
+            ObservedFrame::Ack { header, .. } => header,
+            ObservedFrame::Forwarded { header, .. } => header
+        }
+    }
+
+    /// Payload of the observed frame, empty for acks
+    pub fn payload(&self) -> &'a [u8] {
+        match *self {
+            ObservedFrame::Data { payload, .. } => payload,
+            ObservedFrame::Ack { .. } => &[],
+            ObservedFrame::Forwarded { payload, .. } => payload
+        }
+    }
+}
+
+/// Details about how a received frame reached us
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecvInfo<'a> {
+    /// KISS port the frame arrived on
+    pub port: u8,
+    /// Station that originated the frame, for acks this is the station that acked
+    pub src: u32,
+    /// We're the final destination of the frame, or it was broadcast
+    pub addressed_to_us: bool,
+    /// We're the current hop of the frame and passed it along, if neither this nor `addressed_to_us` is set it was overheard
+    pub is_relay: bool,
+    /// Frame bytes as they came off the wire, after KISS decoding and before parsing
+    pub raw: &'a [u8]
+}
+
+/// Where a multicast packet stands for a single destination
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeliveryState {
+    /// Waiting on an ack
+    Pending,
+    /// Destination acked the packet
+    Acked,
+    /// Packet exceeded its retries without an ack
+    Expired
+}
+
+/// Tracks a packet sent with `send_multicast` per destination. Feed it the PRNs from acks and
+/// expired frames, the send is done once every destination has acked or expired.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeliveryResult {
+    /// Destination to the PRN sent to it and its delivery state
+    pub destinations: HashMap<u32, (prn_id::PrnValue, DeliveryState)>
+}
+
+impl DeliveryResult {
+    /// Marks the destination sent `prn` as acked, returns false if `prn` isn't part of this send
+    pub fn ack(&mut self, prn: prn_id::PrnValue) -> bool {
+        self.update(prn, DeliveryState::Acked)
+    }
+
+    /// Marks the destination sent `prn` as expired, returns false if `prn` isn't part of this send
+    pub fn expire(&mut self, prn: prn_id::PrnValue) -> bool {
+        self.update(prn, DeliveryState::Expired)
+    }
+
+    /// Delivery state for `dest` if it was one of the destinations
+    pub fn state(&self, dest: u32) -> Option<DeliveryState> {
+        self.destinations.get(&dest).map(|&(_, state)| state)
+    }
+
+    /// Every destination has either acked or expired
+    pub fn is_done(&self) -> bool {
+        self.destinations.values().all(|&(_, state)| state != DeliveryState::Pending)
+    }
+
+    fn update(&mut self, prn: prn_id::PrnValue, new_state: DeliveryState) -> bool {
+        match self.destinations.values_mut().find(|&&mut (dest_prn, _)| dest_prn == prn) {
+            Some(&mut (_, ref mut state)) => {
+                if *state == DeliveryState::Pending {
+                    *state = new_state;
+                }
+                true
+            },
+            None => false
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -35,8 +243,13 @@ pub enum SendError {
     Write(frame::WriteError),
     /// IO Error occured
     Io(io::Error),
-    /// Packet was larger than MTU
-    Truncated
+    /// Packet was larger than the max payload
+    Truncated,
+    /// Output buffer was too small for the encoded packet
+    Size(util::SizeError),
+    /// Packet was queued but writing it out failed. It will still be retried on tick so it shouldn't be sent again,
+    /// contains the PRN of the queued packet and the write error
+    WriteButQueued(prn_id::PrnValue, io::Error)
 }
 
 impl From<frame::EncodeError> for SendError {
@@ -51,12 +264,33 @@ impl From<frame::WriteError> for SendError {
     }
 }
 
+impl From<util::SizeError> for SendError {
+    fn from(err: util::SizeError) -> SendError {
+        SendError::Size(err)
+    }
+}
+
 impl From<io::Error> for SendError {
     fn from(err: io::Error) -> SendError {
         SendError::Io(err)
     }
 }
 
+/// Error cases for `try_send`
+#[derive(Debug)]
+pub enum TrySendError {
+    /// The tx queue can't take the frame right now, nothing was queued or sent. Try again once acks free up room.
+    WouldCongest,
+    /// Sending failed the same way `send_slice` would
+    Send(SendError)
+}
+
+impl From<SendError> for TrySendError {
+    fn from(err: SendError) -> TrySendError {
+        TrySendError::Send(err)
+    }
+}
+
 #[derive(Debug)]
 pub enum RecvError {
     /// Error decoding frame
@@ -68,7 +302,9 @@ pub enum RecvError {
     /// Parse error reading address
     Routing(routing::ParseError),
     /// Error sending ack/routing packet during recv
-    Send(SendError)
+    Send(SendError),
+    /// Receive buffer passed its limit without a complete frame and was cleared to resync
+    Overflow
 }
 
 impl From<frame::ReadError> for RecvError {
@@ -101,6 +337,122 @@ impl From<SendError> for RecvError {
     }
 }
 
+/// Whether a recv error came from writing to the transport, those abort recv while anything else only drops the frame
+fn is_transport_error(err: &RecvError) -> bool {
+    match *err {
+        RecvError::Io(_) |
+        RecvError::Ack(frame::WriteError::IO(_)) |
+        RecvError::Send(SendError::Io(_)) |
+        RecvError::Send(SendError::Write(frame::WriteError::IO(_))) |
+        RecvError::Send(SendError::WriteButQueued(_, _)) => true,
+        RecvError::Ack(frame::WriteError::Size(_)) |
+        RecvError::Send(SendError::Frame(_)) |
+        RecvError::Send(SendError::Enqueue(_)) |
+        RecvError::Send(SendError::Write(frame::WriteError::Size(_))) |
+        RecvError::Send(SendError::Truncated) |
+        RecvError::Send(SendError::Size(_)) |
+        RecvError::Frame(_) |
+        RecvError::Routing(_) |
+        RecvError::Overflow => false
+    }
+}
+
+impl fmt::Display for NodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NodeError::BadCallsign => write!(f, "Callsign is not valid")
+        }
+    }
+}
+
+impl error::Error for NodeError {}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SendError::Frame(ref e) => write!(f, "Unable to build frame: {}", e),
+            SendError::Enqueue(ref e) => write!(f, "Unable to queue frame: {}", e),
+            SendError::Write(ref e) => write!(f, "Unable to write frame: {}", e),
+            SendError::Io(ref e) => write!(f, "IO error sending frame: {}", e),
+            SendError::Truncated => write!(f, "Packet is larger than the max payload"),
+            SendError::Size(ref e) => write!(f, "Unable to encode frame: {}", e),
+            SendError::WriteButQueued(prn, ref e) => write!(f, "Frame {} was queued but failed to write: {}", prn, e)
+        }
+    }
+}
+
+impl error::Error for SendError {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            SendError::Frame(ref e) => Some(e),
+            SendError::Enqueue(ref e) => Some(e),
+            SendError::Write(ref e) => Some(e),
+            SendError::Io(ref e) => Some(e),
+            SendError::Size(ref e) => Some(e),
+            SendError::WriteButQueued(_, ref e) => Some(e),
+            SendError::Truncated => None
+        }
+    }
+}
+
+impl fmt::Display for TrySendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TrySendError::WouldCongest => write!(f, "Tx queue is full, try again later"),
+            TrySendError::Send(ref e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl error::Error for TrySendError {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            TrySendError::Send(ref e) => Some(e),
+            TrySendError::WouldCongest => None
+        }
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RecvError::Frame(ref e) => write!(f, "Unable to decode frame: {}", e),
+            RecvError::Ack(ref e) => write!(f, "Unable to write ack: {}", e),
+            RecvError::Io(ref e) => write!(f, "IO error receiving: {}", e),
+            RecvError::Routing(ref e) => write!(f, "Unable to route frame: {}", e),
+            RecvError::Send(ref e) => write!(f, "Unable to send while receiving: {}", e),
+            RecvError::Overflow => write!(f, "Receive buffer overflowed without a complete frame")
+        }
+    }
+}
+
+impl error::Error for RecvError {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            RecvError::Frame(ref e) => Some(e),
+            RecvError::Ack(ref e) => Some(e),
+            RecvError::Io(ref e) => Some(e),
+            RecvError::Routing(ref e) => Some(e),
+            RecvError::Send(ref e) => Some(e),
+            RecvError::Overflow => None
+        }
+    }
+}
+
+/// Port mask that accepts frames from every KISS port
+const ALL_PORTS: u16 = 0xFFFF;
+/// Highest KISS port, the port is the high nibble of the command byte
+const MAX_PORT: u8 = 0x0F;
+
+/// Most unanswered pings we keep track of, the oldest is forgotten past this
+pub const MAX_PENDING_PINGS: usize = 32;
+
+/// Most stations we remember hearing, the one heard longest ago is forgotten past this
+pub const MAX_HEARD_STATIONS: usize = 64;
+
+/// Default limit on buffered receive bytes, room for a few max size frames even if every byte was escaped
+pub const DEFAULT_MAX_RECV_BUFFER: usize = 4 * 2 * frame::MAX_PACKET_SIZE;
+
 /// Constructs a new SimpleLink node that can be used to communicate with other SimpleLink nodes
 pub fn new(callsign: u32) -> Node {
     info!("New link created with callsign {:?}", address::decode(callsign));
@@ -108,504 +460,3918 @@ pub fn new(callsign: u32) -> Node {
     Node {
         prn: prn_id::new(callsign),
         recv_prn_table: prn_table::new(),
+        dedup: true,
+        monitor: false,
+        coalesce_acks: false,
+        pending_acks: vec!(),
         tx_queue: tx_queue::new(),
+        rx_ports: ALL_PORTS,
+        tx_port: 0,
+        max_payload: frame::MTU,
+        max_hops: routing::MAX_LENGTH,
         recv_buffer: vec!(),
-        kiss_frame_scratch: vec!()
+        max_recv_buffer: DEFAULT_MAX_RECV_BUFFER,
+        kiss_frame_scratch: vec!(),
+        framing: Box::new(framing::KissFraming),
+        bytes_transmitted: 0,
+        channel: Box::new(channel::AlwaysClear),
+        clock_ms: 0,
+        clock: Box::new(clock::new_system()),
+        last_tick_now: None,
+        heard: HashMap::new(),
+        keepalive_interval: None,
+        since_keepalive: 0,
+        beacon: None,
+        since_beacon: 0,
+        ping_reply: false,
+        pings: HashMap::new(),
+        on_pong: None,
+        on_ack: None,
+        stop: None
     }
 }
 
-impl Node {
-    /// Sends a packet out on the wire. Returns the PRN of the packet that was sent
-    pub fn send<B,T,A>(&mut self, in_data: B, addr_route: A, tx_drain: &mut T) -> Result<prn_id::PrnValue, SendError> 
-        where
-            B: Iterator<Item=u8>,
-            T: io::Write,
-            A: Iterator<Item=u32>
-    {
-        //Copy data into scratch array
-        let mut scratch: [u8; frame::MTU] = unsafe { mem::uninitialized() };
-        
-        let data_size = in_data
-            .fold(0, |idx, byte| {
-                if idx < frame::MTU {
-                    scratch[idx] = byte;
-                }
+/// Marks a data frame with no payload as versioned, a legacy frame with no payload is an ack while
+/// versioned ones are only acks when flagged
+fn data_header(mut header: frame::Frame, in_data: &[u8]) -> frame::Frame {
+    if in_data.len() == 0 && header.version == frame::VERSION_LEGACY {
+        header.version = frame::VERSION_CURRENT;
+    }
 
-                idx+1
-            });
+    header
+}
 
-        if data_size > frame::MTU {
-            trace!("Tried sending packet but larger than MTU");
-            return Err(SendError::Truncated)
-        }
+/// Configures a node in one place before it's constructed, every option defaults to the same value as `new`
+pub struct Builder {
+    node: Node
+}
 
-        self.send_slice(&scratch[..data_size], addr_route, tx_drain)
+/// Starts configuring a node with our callsign, see `Node`'s setters for what each option does
+pub fn builder(callsign: u32) -> Builder {
+    Builder {
+        node: new(callsign)
     }
+}
 
-    /// Sends a packet out on the wire. Returns the PRN of the packet that was sent
-    pub fn send_slice<T,A>(&mut self, in_data: &[u8], addr_route: A, tx_drain: &mut T) -> Result<prn_id::PrnValue, SendError>
-        where
-            T: io::Write,
-            A: Iterator<Item=u32>
-    {
-        use std::iter;
+impl Builder {
+    /// See `Node::set_rx_ports`
+    pub fn rx_ports(mut self, ports: &[u8]) -> Builder {
+        self.node.set_rx_ports(ports);
+        self
+    }
 
-        if in_data.len() > frame::MTU {
-            trace!("Tried sending packet but larger than MTU");
-            return Err(SendError::Truncated)
-        }
+    /// See `Node::set_tx_port`
+    pub fn tx_port(mut self, port: u8) -> Builder {
+        self.node.set_tx_port(port);
+        self
+    }
 
-        let final_route = addr_route
-            .chain(iter::once(routing::ADDRESS_SEPARATOR))
-            .chain(iter::once(self.prn.callsign));
+    /// See `Node::set_channel_state`
+    pub fn channel_state<C>(mut self, channel: C) -> Builder where C: channel::ChannelState + Send + 'static {
+        self.node.set_channel_state(channel);
+        self
+    }
 
-        let header = try!(frame::new_header(&mut self.prn, final_route));
-        try!(self.enqueue_frame(header, in_data, tx_drain));
+    /// See `Node::set_framing`
+    pub fn framing<F>(mut self, framing: F) -> Builder where F: framing::Framing + Send + 'static {
+        self.node.set_framing(framing);
+        self
+    }
 
-        Ok(self.prn.current())
+    /// See `Node::set_keepalive`
+    pub fn keepalive(mut self, interval_ms: usize) -> Builder {
+        self.node.set_keepalive(Some(interval_ms));
+        self
     }
 
-    fn enqueue_frame<T>(&mut self, header: frame::Frame, in_data: &[u8], tx_drain: &mut T) -> Result<(), SendError>
-        where T: io::Write
-    {
-        //Save packet for resend
-        match self.tx_queue.enqueue(header, in_data) {
-            Ok(()) => {
-                try!(self.send_frame(header, in_data, tx_drain));
-            },
-            Err(e) => {
-                trace!("Error sending frame {:?}", e);
-                return Err(SendError::Enqueue(e))
-            }
-        }
+    /// See `Node::enable_beacon`
+    pub fn beacon(mut self, interval_ms: usize, payload: Vec<u8>) -> Builder {
+        self.node.enable_beacon(interval_ms, payload);
+        self
+    }
 
-        Ok(())
+    /// See `Node::set_stop_flag`
+    pub fn stop_flag(mut self, stop: Arc<AtomicBool>) -> Builder {
+        self.node.set_stop_flag(stop);
+        self
     }
 
-    fn send_frame<T>(&self, header: frame::Frame, in_data: &[u8], tx_drain: &mut T) -> Result<(), SendError>
-        where T: io::Write
-    {
-        let mut packet_data: [u8; frame::MAX_PACKET_SIZE] = unsafe { mem::uninitialized() };
-        let packet_len = try!(frame::to_bytes(&mut io::Cursor::new(&mut packet_data[..frame::MAX_PACKET_SIZE]), &header, Some(in_data)));
-        try!(kiss::encode(&mut io::Cursor::new(&packet_data[..packet_len]), tx_drain, 0));
-        trace!("Sent frame {}", header.prn);
+    /// See `Node::set_ping_reply`
+    pub fn ping_reply(mut self, reply: bool) -> Builder {
+        self.node.set_ping_reply(reply);
+        self
+    }
 
-        Ok(())
+    /// See `Node::set_dedup`
+    pub fn dedup(mut self, dedup: bool) -> Builder {
+        self.node.set_dedup(dedup);
+        self
     }
 
-    /// Receives any packets, sends immediate acks, packets are delivered via packet_drain callback
-    pub fn recv<RW,P,O>(&mut self, rx_tx: &mut RW, mut recv_drain: P, mut observe_drain: O) -> Result<(), RecvError>
-        where
-            RW: io::Read + io::Write,
-            P: FnMut(&frame::Frame, &[u8]),
-            O: FnMut(&frame::Frame, &[u8])
-    {
-        const SCRACH_SIZE: usize = 256;
-        let mut scratch: [u8; SCRACH_SIZE] = unsafe { mem::uninitialized() };
+    /// See `Node::set_prn_table_capacity`
+    pub fn prn_table_capacity(mut self, capacity: usize) -> Builder {
+        self.node.set_prn_table_capacity(capacity);
+        self
+    }
 
-        loop {
-            let bytes = try!(rx_tx.read(&mut scratch));
+    /// See `Node::set_coalesce_acks`
+    pub fn coalesce_acks(mut self, coalesce_acks: bool) -> Builder {
+        self.node.set_coalesce_acks(coalesce_acks);
+        self
+    }
 
-            if bytes == 0 {
-                break;
-            }
+    /// See `Node::set_congestion_policy`
+    pub fn congestion_policy(mut self, policy: tx_queue::CongestionPolicy) -> Builder {
+        self.node.set_congestion_policy(policy);
+        self
+    }
 
-            //Copy data to our read buffer
-            self.recv_buffer.extend_from_slice(&scratch[..bytes]);
-            
-            //Parse any KISS frames
-            loop {
-                self.kiss_frame_scratch.drain(..);
-                match kiss::decode(self.recv_buffer.iter().cloned(), &mut self.kiss_frame_scratch) {
-                    Some(decoded) => {
-                        let mut payload: [u8; frame::MTU] = unsafe { mem::uninitialized() };
-                        let result = match frame::from_bytes(&mut io::Cursor::new(&self.kiss_frame_scratch[..decoded.payload_size]), &mut payload, decoded.payload_size) {
-                            Ok((packet, payload_size)) => {
-                                self.dispatch_recv(rx_tx, &packet, &payload[..payload_size], &mut recv_drain, &mut observe_drain)
-                            },
-                            Err(e) => Err(e).map_err(|e| RecvError::Frame(e))
-                        };
-                        
-                        //Clear recieved, make sure we do this even on error
-                        self.recv_buffer.drain(..decoded.bytes_read);
-
-                        try!(result);
-                    },
-                    None => break
-                }
-            }
-        }
+    /// See `Node::set_merge_duplicates`
+    pub fn merge_duplicates(mut self, merge: bool) -> Builder {
+        self.node.set_merge_duplicates(merge);
+        self
+    }
 
-        Ok(())
+    /// See `Node::set_max_in_flight`
+    pub fn max_in_flight(mut self, max_packets: usize) -> Builder {
+        self.node.set_max_in_flight(max_packets);
+        self
     }
 
-    /// Dispaches packet based on data/ack and if this was a routing destination
-    fn dispatch_recv<T,P,O>(&mut self, tx_drain: &mut T, packet: &frame::Frame, payload: &[u8], recv_drain: &mut P, observe_drain: &mut O) -> Result<(), RecvError>
-        where 
-            T: io::Write,
-            P: FnMut(&frame::Frame, &[u8]),
-            O: FnMut(&frame::Frame, &[u8])
-    {
-        if routing::is_destination(&packet.address_route, self.prn.callsign) {
-            trace!("Recieved packet with our address in the route {}", packet.prn);
+    /// See `Node::set_max_recv_buffer`
+    pub fn max_recv_buffer(mut self, max_recv_buffer: usize) -> Builder {
+        self.node.set_max_recv_buffer(max_recv_buffer);
+        self
+    }
 
-            //Respond that we've received this packet if we're the final destination, note that
-            //we might ack something we've already receieved since the sender may have not
-            //heard the ack.
-            if routing::final_addr(&packet.address_route) {
-                //If we got an ack packet then pass that along to our tx queue
-                if payload.len() == 0 {
-                    trace!("Recieved ack {}", packet.prn);
-                    self.tx_queue.ack_recv(packet.prn);
-                    recv_drain(&packet, payload);
-                } else {
-                    let ack = frame::new_ack(packet.prn, routing::reverse(&packet.address_route));
-                    let mut ack_packet: [u8; frame::MAX_ACK_SIZE] = unsafe { mem::uninitialized() };
-                    let ack_packet_len = try!(frame::to_bytes(&mut io::Cursor::new(&mut ack_packet[..frame::MAX_ACK_SIZE]), &ack, None));
-                    try!(kiss::encode(&mut io::Cursor::new(&ack_packet[..ack_packet_len]), tx_drain, 0));
-                    trace!("Sending ack for {}", packet.prn);
+    /// See `Node::set_max_payload`
+    pub fn max_payload(mut self, max_payload: usize) -> Builder {
+        self.node.set_max_payload(max_payload);
+        self
+    }
 
-                    let new_packet = !self.recv_prn_table.contains(packet.prn);
+    /// See `Node::set_max_hops`
+    pub fn max_hops(mut self, max_hops: usize) -> Builder {
+        self.node.set_max_hops(max_hops);
+        self
+    }
 
-                    //Don't process duplicates
-                    if new_packet {
-                        trace!("New packet that we haven't seen yet");
-                        self.recv_prn_table.add(packet.prn);
+    /// See `Node::set_retry_count`
+    pub fn retry_count(mut self, retry_count: usize) -> Builder {
+        self.node.set_retry_count(retry_count);
+        self
+    }
 
-                        //If we're the final destination then we should process this packet
-                        trace!("Final dest, surfacing packet as data");
-                        recv_drain(&packet, payload);
-                    } else {
-                        trace!("Duplicate packet already recieved before");
-                    }
-                }
-            } else {    //Route this packet along
-                trace!("Packet has routes yet to complete, sending");
-                let mut routed_header = *packet;
-                routed_header.address_route = try!(routing::advance(&packet.address_route, self.prn.callsign));
+    /// See `Node::set_monitor`
+    pub fn monitor(mut self, monitor: bool) -> Builder {
+        self.node.set_monitor(monitor);
+        self
+    }
 
-                //@todo: Reject packets that already have this ID in the source path since that means we've seen it before
+    /// Finishes configuring and returns the node
+    pub fn build(self) -> Node {
+        self.node
+    }
+}
 
-                //Just pass along, we don't ack unless we are the end host
-                try!(self.send_frame(routed_header, payload, tx_drain));
-            }
+impl Node {
+    /// Restricts which KISS ports frames are accepted from, frames on any other port are skipped.
+    /// An empty set restores the default of accepting every port. Ports past 15 can't be carried by KISS and are ignored.
+    pub fn set_rx_ports(&mut self, ports: &[u8]) {
+        self.rx_ports = if ports.len() == 0 {
+            ALL_PORTS
         } else {
-            trace!("Data frame but addr {:?} is not our dest {:?}", address::decode(packet.address_route[0]), address::decode(self.prn.callsign));
+            ports.iter().fold(0, |mask, port| {
+                if *port > MAX_PORT {
+                    warn!("Ignoring rx port {}, KISS ports only go up to {}", port, MAX_PORT);
+                    mask
+                } else {
+                    mask | (1 << port)
+                }
+            })
+        };
+
+        trace!("Accepting frames from port mask {:x}", self.rx_ports);
+    }
+
+    /// Whether frames on `port` pass the rx port filter, ports past 15 from a custom framing only pass when accepting every port
+    fn accepts_port(&self, port: u8) -> bool {
+        if self.rx_ports == ALL_PORTS {
+            return true
         }
 
-        trace!("obs");
-        observe_drain(packet, payload);
+        port <= MAX_PORT && self.rx_ports & (1 << port) != 0
+    }
 
-        Ok(())
+    /// Sets the KISS port that new frames are sent out on, retries of a frame always use the port it was first sent on
+    pub fn set_tx_port(&mut self, port: u8) {
+        self.tx_port = port & 0x0F;
     }
 
-    /// Ticks any packet retries that need to be sent
-    pub fn tick<T,R,D>(&mut self, tx_drain: &mut T, elapsed_ms: usize, mut retry_drain: R, discard_drain: D) -> Result<(), SendError>
-        where
-            T: io::Write,
-            R: FnMut(&frame::Frame, &[u8], usize),
-            D: FnMut(&frame::Frame, &[u8]),
-    {
-        try!(self.tx_queue.tick::<_,_,SendError>(elapsed_ms,
-            |header, data, next_retry| {
-                trace!("Packet {} retrying", header.prn);
+    /// Sets the channel state consulted before transmitting. While it isn't clear new frames are queued instead of
+    /// written and retries are held off by a random slot delay. Acks, forwarded frames and frames sent on behalf of
+    /// another station with `send_as` always go out immediately.
+    pub fn set_channel_state<C>(&mut self, channel: C) where C: channel::ChannelState + Send + 'static {
+        self.channel = Box::new(channel);
+    }
 
-                //Retry our frame
-                try!(frame::to_bytes(tx_drain, header, Some(data)));
+    /// Sets how frames are delimited on the wire, defaults to KISS. Both ends of a link need to agree and
+    /// anything already buffered from recv is decoded with the new framing.
+    pub fn set_framing<F>(&mut self, framing: F) where F: framing::Framing + Send + 'static {
+        self.framing = Box::new(framing);
+    }
 
-                //Notify client that we resent
-                retry_drain(header, data, next_retry);
+    /// Broadcasts a keepalive from tick every interval_ms so peers know we're still around, None disables them.
+    /// Keepalives need VERSION_CURRENT and aren't acked, defaults to disabled.
+    pub fn set_keepalive(&mut self, interval_ms: Option<usize>) {
+        self.keepalive_interval = interval_ms;
+        self.since_keepalive = 0;
+    }
 
-                Ok(())
-            },
-            discard_drain));
+    /// Broadcasts `payload` in a keepalive frame from tick whenever we haven't transmitted for interval_ms, for station
+    /// identification. Any other transmit restarts the interval since it also carries our callsign. Beacons aren't
+    /// acked, queued or retried and are passed to recv_drain by nodes that hear them directly.
+    pub fn enable_beacon(&mut self, interval_ms: usize, payload: Vec<u8>) {
+        self.beacon = Some((interval_ms, payload));
+        self.since_beacon = 0;
+    }
 
-        Ok(())
+    /// Stops beaconing
+    pub fn disable_beacon(&mut self) {
+        self.beacon = None;
     }
-}
 
+    /// Sets a flag that stops recv between frames while it's set, for example from a recv callback or on shutdown.
+    /// Frames that weren't processed stay buffered and are handled by the first recv after the flag is cleared.
+    pub fn set_stop_flag(&mut self, stop: Arc<AtomicBool>) {
+        self.stop = Some(stop);
+    }
 
-#[cfg(test)]
-use util;
+    fn is_stopped(&self) -> bool {
+        self.stop.as_ref().map(|stop| stop.load(Ordering::SeqCst)).unwrap_or(false)
+    }
 
-#[test]
-fn test_send() {
-    let addr = [
-        address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap(),
-        address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap()
-    ];
+    /// Replies to pings addressed to us from recv without involving the application, defaults to off
+    pub fn set_ping_reply(&mut self, reply: bool) {
+        self.ping_reply = reply;
+    }
 
-    let mut node = new(addr[1]);
+    /// Sets the callback for answered pings, called with the ping's PRN and its round trip time in ms on `clock_ms`
+    pub fn set_pong_handler<F>(&mut self, handler: F) where F: FnMut(prn_id::PrnValue, u64) + Send + 'static {
+        self.on_pong = Some(Box::new(handler));
+    }
 
-    let mut tx: Vec<u8> = vec!();
+    /// Sets the callback for acked packets, called from recv with the packet's PRN and the ms ticked between sending it and its ack
+    pub fn set_ack_handler<F>(&mut self, handler: F) where F: FnMut(prn_id::PrnValue, u64) + Send + 'static {
+        self.on_ack = Some(Box::new(handler));
+    }
 
-    node.send((0..5).map(|x| x as u8), addr.iter().cloned(), &mut tx).unwrap();
+    /// Sends a ping to dest, returns its PRN. Pings aren't queued or retried, the pong handler is called if it's answered.
+    /// Needs VERSION_CURRENT on both ends.
+    pub fn ping<T>(&mut self, dest: u32, tx_drain: &mut T) -> Result<prn_id::PrnValue, SendError> where T: io::Write {
+        let mut route: routing::Route = [routing::ADDRESS_SEPARATOR; routing::MAX_LENGTH];
+        route[0] = dest;
+        route[2] = self.prn.callsign;
 
-    assert!(tx.len() > 0);
-}
+        let ping = frame::new_ping(self.prn.next(), route);
 
-#[test]
-fn test_send_recv() {
-    let data = (0..5).map(|x| x as u8).collect::<Vec<_>>();
+        //Forget the oldest ping rather than growing forever when nobody answers
+        if self.pings.len() >= MAX_PENDING_PINGS {
+            let oldest = self.pings.iter().min_by_key(|&(_, sent)| *sent).map(|(prn, _)| *prn);
+            if let Some(prn) = oldest {
+                self.pings.remove(&prn);
+            }
+        }
 
-    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
-    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+        let written = try!(Node::send_frame(&*self.framing, ping, &[], self.tx_port, tx_drain));
+        self.transmitted(written as u64);
+        self.pings.insert(ping.prn, self.clock_ms);
 
-    let mut tx_local = vec!();
-    let mut tx_remote = vec!();
+        debug!("Sent ping {} to {}", ping.prn, address::format_addr(dest));
+        Ok(ping.prn)
+    }
 
-    let mut local = new(local_addr);
-    let mut remote = new(remote_addr);
+    /// Sets the clock tick_now measures elapsed time with, defaults to the system's monotonic clock
+    pub fn set_clock<C>(&mut self, clock: C) where C: clock::Clock + Send + 'static {
+        self.clock = Box::new(clock);
+        self.last_tick_now = None;
+    }
 
-    let prn = local.send(data.iter().cloned(), [remote_addr].iter().cloned(), &mut tx_local).unwrap();
+    /// Total elapsed ms that has been passed to tick, the clock `last_heard` is measured in
+    pub fn clock_ms(&self) -> u64 {
+        self.clock_ms
+    }
 
-    let mut match_recv = false;
-    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx_local), &mut tx_remote),
-        |_,recv_data| {
-            match_recv = true;
-            assert!(recv_data.iter().eq(data.iter()));
-        },
-        |_,_| {
+    /// Time on `clock_ms` that we last received any frame from addr, None if we've never heard it
+    pub fn last_heard(&self, addr: u32) -> Option<u64> {
+        self.heard.get(&addr).map(|station| station.last_heard)
+    }
 
-        }).unwrap();
+    /// Every station we've received frames from, most recently heard first. Only the last `MAX_HEARD_STATIONS` are kept.
+    pub fn heard_stations(&self) -> Vec<HeardStation> {
+        let mut stations = self.heard.values().cloned().collect::<Vec<_>>();
+        stations.sort_by(|a, b| b.last_heard.cmp(&a.last_heard).then(a.addr.cmp(&b.addr)));
 
-    assert!(match_recv);
+        stations
+    }
+
+    /// Calls link_timeout for every station that hasn't been heard from in more than timeout_ms. Each station is only
+    /// reported once until it's heard from again.
+    pub fn check_links<L>(&mut self, timeout_ms: u64, mut link_timeout: L) where L: FnMut(u32) {
+        let now = self.clock_ms;
+
+        for (addr, station) in self.heard.iter_mut() {
+            if !station.timed_out && now - station.last_heard > timeout_ms {
+                info!("Link to {} timed out, last heard {}ms ago", address::format_addr(*addr), now - station.last_heard);
+                station.timed_out = true;
+                link_timeout(*addr);
+            }
+        }
+    }
+
+    /// Enables or disables duplicate suppression, when disabled frames we've already received are delivered
+    /// again instead of going to dup_drain. Useful for replaying captured traffic, defaults to enabled.
+    pub fn set_dedup(&mut self, dedup: bool) {
+        self.dedup = dedup;
+    }
+
+    /// Puts the node in monitor mode, where recv never transmits. Frames aren't acked, forwarded or answered even
+    /// when they're addressed to us, everything is reported to observe_drain as overheard. Defaults to disabled.
+    pub fn set_monitor(&mut self, monitor: bool) {
+        self.monitor = monitor;
+    }
+
+    /// Sets how many received PRNs are remembered for duplicate suppression, defaults to `prn_table::TABLE_SIZE`.
+    /// PRNs already remembered are forgotten.
+    pub fn set_prn_table_capacity(&mut self, capacity: usize) {
+        self.recv_prn_table = prn_table::with_capacity(capacity);
+    }
+
+    /// Enables or disables ack coalescing. When enabled acks for frames received in the same recv call are sent
+    /// together as multi-acks at the end of the call, one per return route. Multi-acks need VERSION_CURRENT so
+    /// only enable this if every peer understands them, defaults to disabled.
+    pub fn set_coalesce_acks(&mut self, coalesce_acks: bool) {
+        self.coalesce_acks = coalesce_acks;
+    }
+
+    /// Sets how the tx queue sheds load under congestion control, defaults to dropping packets as their retries come due
+    pub fn set_congestion_policy(&mut self, policy: tx_queue::CongestionPolicy) {
+        self.tx_queue.set_congestion_policy(policy);
+    }
+
+    /// Sets whether re-sending a payload that's still waiting for an ack on the same route returns the
+    /// PRN already in flight instead of sending a second copy, defaults to off
+    pub fn set_merge_duplicates(&mut self, merge: bool) {
+        self.tx_queue.set_merge_duplicates(merge);
+    }
+
+    /// Sets the maximum number of sent packets that can wait for an ack, further sends fail until one is acked or expires
+    pub fn set_max_in_flight(&mut self, max_packets: usize) {
+        self.tx_queue.set_max_packets(max_packets);
+    }
+
+    /// Sets how many times a sent packet is retried without an ack before it's discarded, defaults to `tx_queue::RETRY_COUNT`
+    /// and capped at `tx_queue::MAX_RETRY_COUNT`
+    pub fn set_retry_count(&mut self, retry_count: usize) {
+        self.tx_queue.set_retry_count(retry_count);
+    }
+
+    /// Sets the most bytes we'll buffer while waiting for a complete KISS frame. If exceeded the buffer is
+    /// cleared and reported as `RecvError::Overflow` through recv's error_drain.
+    pub fn set_max_recv_buffer(&mut self, max_recv_buffer: usize) {
+        self.max_recv_buffer = max_recv_buffer;
+    }
+
+    /// Number of sent packets that are still waiting for an ack
+    pub fn pending_packets(&self) -> usize {
+        self.tx_queue.pending_packets()
+    }
+
+    /// Bytes of payload in sent packets that are still waiting for an ack
+    pub fn buffered_bytes(&self) -> usize {
+        self.tx_queue.buffered_bytes()
+    }
+
+    /// Details of every sent packet that's still waiting for an ack
+    pub fn pending(&self) -> Vec<tx_queue::PendingInfo> {
+        self.tx_queue.pending_info()
+    }
+
+    /// Serializes packets waiting for an ack so they can be checkpointed and restored with `import_pending`
+    pub fn export_pending(&self) -> Vec<u8> {
+        self.tx_queue.export()
+    }
+
+    /// Flushes tx_drain. Sends, retries and acks already flush after each frame they write, this is for
+    /// transports that are written to outside of the node or wrapped in extra buffering by the caller.
+    pub fn flush<T>(&mut self, tx_drain: &mut T) -> io::Result<()> where T: io::Write {
+        tx_drain.flush()
+    }
+
+    /// Gives up on every packet waiting for an ack, for example on link teardown. discard_drain is called once for each.
+    pub fn drop_all_pending<D>(&mut self, discard_drain: D) where D: FnMut(&frame::Frame, &[u8]) {
+        self.tx_queue.expire_all(discard_drain);
+    }
+
+    /// Restores packets from `export_pending`, they keep retrying from where they left off
+    pub fn import_pending(&mut self, exported: &[u8]) -> Result<(), tx_queue::ImportError> {
+        self.tx_queue.import(exported)
+    }
+
+    /// Number of bytes written to the transport, includes KISS framing and every ack, forward and retry
+    pub fn bytes_transmitted(&self) -> u64 {
+        self.bytes_transmitted
+    }
+
+    /// Resets the count of bytes written to the transport back to zero
+    pub fn reset_bytes_transmitted(&mut self) {
+        self.bytes_transmitted = 0;
+    }
+
+    /// Records bytes written to the transport, anything we send identifies us so it restarts the beacon interval
+    fn transmitted(&mut self, bytes: u64) {
+        self.bytes_transmitted += bytes;
+
+        if bytes > 0 {
+            self.since_beacon = 0;
+        }
+    }
+
+    /// Sets the largest payload we'll send, capped at frame::MTU. Useful for links with small TNC buffers.
+    /// Frames up to frame::MTU are still accepted when receiving.
+    pub fn set_max_payload(&mut self, max_payload: usize) {
+        self.max_payload = cmp::min(max_payload, frame::MTU);
+    }
+
+    /// Largest payload we'll send
+    pub fn max_payload(&self) -> usize {
+        self.max_payload
+    }
+
+    /// Sets the longest forward path we'll send or relay, routes past this are rejected on send and dropped on recv
+    /// before they're forwarded. Defaults to routing::MAX_LENGTH which allows anything the protocol does.
+    pub fn set_max_hops(&mut self, max_hops: usize) {
+        self.max_hops = max_hops;
+    }
+
+    /// Longest forward path we'll send or relay
+    pub fn max_hops(&self) -> usize {
+        self.max_hops
+    }
+
+    /// Rejects routes with a forward path longer than max_hops
+    fn check_hops(&self, route: &routing::Route) -> Result<(), SendError> {
+        if routing::forward_len(route) > self.max_hops {
+            trace!("Route {} has more than {} hops", routing::format_route(route), self.max_hops);
+            return Err(SendError::Frame(frame::EncodeError::AddressTooLong))
+        }
+
+        Ok(())
+    }
+
+    /// Sends a packet out on the wire, copying in_data into a scratch buffer first. Prefer `send_slice` if the data
+    /// is already in a slice. Returns the PRN of the packet that was sent
+    pub fn send<B,T,A>(&mut self, in_data: B, addr_route: A, tx_drain: &mut T) -> Result<prn_id::PrnValue, SendError> 
+        where
+            B: Iterator<Item=u8>,
+            T: io::Write,
+            A: Iterator<Item=u32>
+    {
+        //Copy data into scratch array
+        let mut scratch: [u8; frame::MTU] = unsafe { mem::uninitialized() };
+        let mut data_size = 0;
+
+        for byte in in_data {
+            //Bail as soon as we know it won't fit instead of draining the rest of the iterator
+            if data_size == self.max_payload {
+                trace!("Tried sending packet but larger than max payload {}", self.max_payload);
+                return Err(SendError::Truncated)
+            }
+
+            scratch[data_size] = byte;
+            data_size += 1;
+        }
+
+        self.send_slice(&scratch[..data_size], addr_route, tx_drain)
+    }
+
+    /// Sends a packet out on the wire straight from in_data without an intermediate copy. Returns the PRN of the packet that was sent
+    ///
+    /// The frame is flushed to tx_drain once written, unless the channel is busy and it's queued for tick to send instead.
+    /// An empty in_data is sent as a versioned frame so it's delivered as data rather than taken for an ack, the receiver needs VERSION_CURRENT.
+    pub fn send_slice<T,A>(&mut self, in_data: &[u8], addr_route: A, tx_drain: &mut T) -> Result<prn_id::PrnValue, SendError>
+        where
+            T: io::Write,
+            A: Iterator<Item=u32>
+    {
+        let callsign = self.prn.callsign;
+        self.send_as(callsign, in_data, addr_route, tx_drain)
+    }
+
+    /// Same as `send_slice` but returns the whole frame that was sent, with the final route and PRN that
+    /// retry and discard callbacks will report
+    pub fn send_slice_frame<T,A>(&mut self, in_data: &[u8], addr_route: A, tx_drain: &mut T) -> Result<frame::Frame, SendError>
+        where
+            T: io::Write,
+            A: Iterator<Item=u32>
+    {
+        let callsign = self.prn.callsign;
+        self.send_header(callsign, in_data, addr_route, tx_drain)
+    }
+
+    /// Same as `send_slice` but with `src` as the source of the frame instead of our callsign, for gateways sending on
+    /// behalf of another station. The PRN still comes from our callsign so it stays unique. Acks are routed back to
+    /// `src` rather than us so the frame is written once and never queued for retry, that's left to `src`. Since there's
+    /// nothing queued to send later it also goes out immediately without checking `set_channel_state`.
+    pub fn send_as<T,A>(&mut self, src: u32, in_data: &[u8], addr_route: A, tx_drain: &mut T) -> Result<prn_id::PrnValue, SendError>
+        where
+            T: io::Write,
+            A: Iterator<Item=u32>
+    {
+        self.send_header(src, in_data, addr_route, tx_drain).map(|header| header.prn)
+    }
+
+    /// Validates a new data frame from `src` along addr_route and builds its full route, doesn't take a PRN
+    fn build_route<A>(&self, src: u32, in_data: &[u8], addr_route: A) -> Result<routing::Route, SendError>
+        where A: Iterator<Item=u32>
+    {
+        use std::iter;
+
+        if in_data.len() > self.max_payload {
+            trace!("Tried sending packet but larger than max payload {}", self.max_payload);
+            return Err(SendError::Truncated)
+        }
+
+        let final_route = addr_route
+            .chain(iter::once(routing::ADDRESS_SEPARATOR))
+            .chain(iter::once(src));
+
+        let route = try!(frame::new_route(final_route));
+        try!(self.check_hops(&route));
+
+        Ok(route)
+    }
+
+    /// Builds the header for a new data frame along an already validated route, taking the next PRN
+    fn build_header(&mut self, route: routing::Route, in_data: &[u8]) -> frame::Frame {
+        let header = frame::Frame {
+            prn: self.prn.next(),
+            address_route: route,
+            version: frame::VERSION_LEGACY,
+            flags: 0
+        };
+
+        data_header(header, in_data)
+    }
+
+    fn send_header<T,A>(&mut self, src: u32, in_data: &[u8], addr_route: A, tx_drain: &mut T) -> Result<frame::Frame, SendError>
+        where
+            T: io::Write,
+            A: Iterator<Item=u32>
+    {
+        //Validate everything before taking a PRN so a rejected or merged send doesn't use one up
+        let route = try!(self.build_route(src, in_data, addr_route));
+
+        //Same payload is already waiting for an ack on this route, let its retries carry it
+        if let Some(existing) = self.tx_queue.find_duplicate(&route, in_data) {
+            debug!("Payload already in flight as frame {}, not sending again", existing.prn);
+            return Ok(existing)
+        }
+
+        let header = self.build_header(route, in_data);
+
+        //We'll never see the ack for someone else's frame, so there's nothing to retry
+        if src != self.prn.callsign {
+            let written = try!(Node::send_frame(&*self.framing, header, in_data, self.tx_port, tx_drain));
+            self.transmitted(written as u64);
+            trace!("Sent frame {} on behalf of {}", header.prn, address::format_addr(src));
+
+            return Ok(header)
+        }
+
+        self.enqueue_frame(header, in_data, tx_drain)
+    }
+
+    /// Same as `send_slice` but fails with `TrySendError::WouldCongest` instead of discarding the frame when the tx queue
+    /// can't take it, nothing is sent in that case so the caller can hold on to the data and try again later. A payload
+    /// that merges into one already in flight(see `set_merge_duplicates`) doesn't need room so it never congests.
+    pub fn try_send<T,A>(&mut self, in_data: &[u8], addr_route: A, tx_drain: &mut T) -> Result<prn_id::PrnValue, TrySendError>
+        where
+            T: io::Write,
+            A: Iterator<Item=u32>
+    {
+        let addr_route = addr_route.collect::<Vec<_>>();
+        let callsign = self.prn.callsign;
+
+        //Bad routes fall through to send_slice to report the error
+        let duplicate = match self.build_route(callsign, in_data, addr_route.iter().cloned()) {
+            Ok(route) => self.tx_queue.find_duplicate(&route, in_data).is_some(),
+            Err(_) => false
+        };
+
+        if !duplicate && self.tx_queue.can_enqueue(in_data.len()).is_err() {
+            trace!("Holding off send of {} bytes, {} bytes already in flight", in_data.len(), self.tx_queue.buffered_bytes());
+            return Err(TrySendError::WouldCongest)
+        }
+
+        Ok(try!(self.send_slice(in_data, addr_route.into_iter(), tx_drain)))
+    }
+
+    /// Sends a packet along `hops`, ending at the last hop. The separator and our callsign are added for you.
+    /// This is the recommended way to send, `send`/`send_slice` take the raw route for advanced use.
+    pub fn send_to_path<T>(&mut self, in_data: &[u8], hops: &[u32], tx_drain: &mut T) -> Result<prn_id::PrnValue, SendError>
+        where T: io::Write
+    {
+        if hops.len() == 0 {
+            trace!("Tried sending packet with no hops");
+            return Err(SendError::Frame(frame::EncodeError::EmptyRoute))
+        }
+
+        if hops.len() > routing::MAX_HOPS {
+            trace!("Tried sending packet with {} hops, max is {}", hops.len(), routing::MAX_HOPS);
+            return Err(SendError::Frame(frame::EncodeError::AddressTooLong))
+        }
+
+        if hops.iter().any(|hop| *hop == routing::ADDRESS_SEPARATOR) {
+            trace!("Tried sending packet with a separator in its hops");
+            return Err(SendError::Frame(frame::EncodeError::UnexpectedSeparator))
+        }
+
+        self.send_slice(in_data, hops.iter().cloned(), tx_drain)
+    }
+
+    /// Sends a copy of a packet to each of `dests`, each with its own PRN so acks and retries are tracked per destination.
+    /// Duplicate destinations are only sent once. If a send fails the copies already sent stay queued and are returned with
+    /// the error so they can still be tracked, a copy that was queued but failed to write is tracked too since it will be retried.
+    pub fn send_multicast<T>(&mut self, in_data: &[u8], dests: &[u32], tx_drain: &mut T) -> Result<DeliveryResult, (DeliveryResult, SendError)>
+        where T: io::Write
+    {
+        let mut result = DeliveryResult {
+            destinations: HashMap::new()
+        };
+
+        if dests.len() == 0 {
+            trace!("Tried multicasting packet with no destinations");
+            return Err((result, SendError::Frame(frame::EncodeError::EmptyRoute)))
+        }
+
+        for dest in dests.iter().cloned() {
+            if result.destinations.contains_key(&dest) {
+                continue
+            }
+
+            let prn = match self.send_slice(in_data, ::std::iter::once(dest), tx_drain) {
+                Ok(prn) => prn,
+                Err(SendError::WriteButQueued(prn, _)) => prn,
+                Err(e) => return Err((result, e))
+            };
+
+            result.destinations.insert(dest, (prn, DeliveryState::Pending));
+        }
+
+        debug!("Multicast packet to {} destinations", result.destinations.len());
+        Ok(result)
+    }
+
+    /// Sends a packet by encoding it into `out` rather than a writer, without allocating for the encode.
+    /// Returns the PRN of the packet and the number of bytes written to `out`. The packet is only queued for
+    /// retry and a PRN used up if it fit in `out`, otherwise `SendError::Size` has the size `out` needed to be.
+    /// A payload that merges into one already in flight(see `set_merge_duplicates`) returns its PRN and writes nothing.
+    pub fn send_to_slice<A>(&mut self, in_data: &[u8], addr_route: A, out: &mut [u8]) -> Result<(prn_id::PrnValue, usize), SendError>
+        where A: Iterator<Item=u32>
+    {
+        let callsign = self.prn.callsign;
+
+        let route = try!(self.build_route(callsign, in_data, addr_route));
+
+        if let Some(existing) = self.tx_queue.find_duplicate(&route, in_data) {
+            debug!("Payload already in flight as frame {}, not encoding again", existing.prn);
+            return Ok((existing.prn, 0))
+        }
+
+        //Escaping makes the encoded size depend on the PRN, so take one and hand it back if the frame doesn't fit
+        let prn_state = self.prn.current;
+        let header = self.build_header(route, in_data);
+
+        let result = self.encode_to_slice(&header, in_data, out);
+        if result.is_err() {
+            self.prn.seed(prn_state);
+        }
+        let written = try!(result);
+
+        trace!("Sent frame {} to slice", header.prn);
+
+        Ok((header.prn, written))
+    }
+
+    /// Encodes header and in_data into out and queues it for retry if it fit
+    fn encode_to_slice(&mut self, header: &frame::Frame, in_data: &[u8], out: &mut [u8]) -> Result<usize, SendError> {
+        let mut packet_data: [u8; frame::MAX_PACKET_SIZE] = unsafe { mem::uninitialized() };
+        let packet_len = try!(frame::to_slice(&mut packet_data, header, Some(in_data)));
+        let written = try!(self.framing.encode_slice(&packet_data[..packet_len], out, self.tx_port));
+
+        try!(self.tx_queue.enqueue(*header, in_data, self.tx_port).map_err(|e| SendError::Enqueue(e)));
+
+        Ok(written)
+    }
+
+    /// Sends an already built frame, keeping its PRN and route rather than generating new ones.
+    /// It's queued for retry like any other data frame, useful for relaying or replaying captured frames. Like `send_slice` a
+    /// legacy header with no payload is sent as versioned so it isn't taken for an ack. Returns the PRN the payload is
+    /// in flight as, which is an existing frame's rather than `header`'s if it merged(see `set_merge_duplicates`).
+    pub fn send_raw<T>(&mut self, header: frame::Frame, in_data: &[u8], tx_drain: &mut T) -> Result<prn_id::PrnValue, SendError>
+        where T: io::Write
+    {
+        if in_data.len() > self.max_payload {
+            trace!("Tried sending raw packet but larger than max payload {}", self.max_payload);
+            return Err(SendError::Truncated)
+        }
+
+        try!(self.check_hops(&header.address_route));
+
+        let header = data_header(header, in_data);
+        trace!("Sending raw frame {} to {}", header.prn, routing::format_route(&header.address_route));
+        self.enqueue_frame(header, in_data, tx_drain).map(|queued| queued.prn)
+    }
+
+    /// Queues and writes a frame, returns the frame in flight which is an existing one if the payload merged into it
+    fn enqueue_frame<T>(&mut self, header: frame::Frame, in_data: &[u8], tx_drain: &mut T) -> Result<frame::Frame, SendError>
+        where T: io::Write
+    {
+        //Someone else is transmitting, tick sends it once the channel clears
+        if !self.channel.is_clear() {
+            trace!("Channel busy, deferring frame {}", header.prn);
+            return self.tx_queue.enqueue_deferred(header, in_data, self.tx_port).map_err(SendError::Enqueue)
+        }
+
+        //Save packet for resend
+        match self.tx_queue.enqueue(header, in_data, self.tx_port) {
+            Ok(queued) if queued.prn != header.prn => {
+                debug!("Frame {} merged into frame {} already in flight", header.prn, queued.prn);
+                return Ok(queued)
+            },
+            Ok(_) => {
+                //Already queued so a failed write just means we wait for the first retry
+                let written = try!(Node::send_frame(&*self.framing, header, in_data, self.tx_port, tx_drain).map_err(|e| {
+                    match e {
+                        SendError::Write(frame::WriteError::IO(e)) | SendError::Io(e) => {
+                            warn!("Failed to write frame {}, it will be retried {:?}", header.prn, e);
+                            SendError::WriteButQueued(header.prn, e)
+                        },
+                        e => e
+                    }
+                }));
+                self.transmitted(written as u64);
+            },
+            Err(e) => {
+                trace!("Error sending frame {:?}", e);
+                return Err(SendError::Enqueue(e))
+            }
+        }
+
+        Ok(header)
+    }
+
+    /// Encodes and writes a frame, returns the number of framed bytes written
+    fn send_frame<T>(framing: &framing::Framing, header: frame::Frame, in_data: &[u8], port: u8, tx_drain: &mut T) -> Result<usize, SendError>
+        where T: io::Write
+    {
+        let mut packet_data: [u8; frame::MAX_PACKET_SIZE] = unsafe { mem::uninitialized() };
+        let packet_len = try!(frame::to_bytes(&mut io::Cursor::new(&mut packet_data[..frame::MAX_PACKET_SIZE]), &header, Some(in_data)));
+        let written = try!(framing.encode(&packet_data[..packet_len], tx_drain, port));
+
+        //Buffered transports may hold on to the frame until the next write otherwise
+        try!(tx_drain.flush());
+        trace!("Sent frame {} on port {}", header.prn, port);
+
+        Ok(written)
+    }
+
+    /// Receives any packets, sends immediate acks, packets are delivered via packet_drain callback.
+    /// Frames we've already delivered are acked again and reported through dup_drain instead.
+    /// Every frame that decodes is also passed to observe_drain as an `ObservedFrame`.
+    ///
+    /// A frame that fails to decode or route, or whose ack or forward can't be built or queued, is dropped and reported
+    /// through error_drain along with its PRN if it could be parsed and its bytes after KISS decoding, then the remaining
+    /// frames are processed. Only IO errors on `rx_tx` abort the read.
+    pub fn recv<RW,P,O,D,E>(&mut self, rx_tx: &mut RW, mut recv_drain: P, mut observe_drain: O, dup_drain: D, error_drain: E) -> Result<(), RecvError>
+        where
+            RW: io::Read + io::Write,
+            P: FnMut(&frame::Frame, &[u8]),
+            O: FnMut(&ObservedFrame),
+            D: FnMut(&frame::Frame),
+            E: FnMut(Option<prn_id::PrnValue>, &RecvError, &[u8])
+    {
+        self.recv_with_info(rx_tx,
+            |header, data, _| recv_drain(header, data),
+            |observed, _| observe_drain(observed),
+            dup_drain,
+            error_drain)
+    }
+
+    /// Same as `recv` for nodes that only care about data addressed to them. Observed frames, duplicates and frames
+    /// that fail to decode aren't reported, the no-op drains compile away so there's no per frame overhead for them.
+    pub fn recv_simple<RW,P>(&mut self, rx_tx: &mut RW, mut recv_drain: P) -> Result<(), RecvError>
+        where
+            RW: io::Read + io::Write,
+            P: FnMut(&frame::Frame, &[u8])
+    {
+        self.recv_with_info(rx_tx,
+            |header, data, _| recv_drain(header, data),
+            |_, _| {},
+            |_| {},
+            |_, _, _| {})
+    }
+
+    /// Same as `recv` but recv_drain and observe_drain are also passed the `RecvInfo` for each frame.
+    pub fn recv_with_info<RW,P,O,D,E>(&mut self, rx_tx: &mut RW, mut recv_drain: P, mut observe_drain: O, mut dup_drain: D, mut error_drain: E) -> Result<(), RecvError>
+        where
+            RW: io::Read + io::Write,
+            P: FnMut(&frame::Frame, &[u8], &RecvInfo),
+            O: FnMut(&ObservedFrame, &RecvInfo),
+            D: FnMut(&frame::Frame),
+            E: FnMut(Option<prn_id::PrnValue>, &RecvError, &[u8])
+    {
+        const SCRACH_SIZE: usize = 256;
+        let mut scratch: [u8; SCRACH_SIZE] = unsafe { mem::uninitialized() };
+
+        loop {
+            try!(self.process_buffer(rx_tx, &mut recv_drain, &mut observe_drain, &mut dup_drain, &mut error_drain));
+
+            if self.is_stopped() {
+                break
+            }
+
+            let bytes = match rx_tx.read(&mut scratch) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    //Whatever we got before the read failed still needs to be acked
+                    try!(self.send_pending_acks(rx_tx));
+                    return Err(RecvError::Io(e))
+                }
+            };
+
+            if bytes == 0 {
+                break;
+            }
+
+            //Copy data to our read buffer
+            self.recv_buffer.extend_from_slice(&scratch[..bytes]);
+        }
+
+        try!(self.send_pending_acks(rx_tx));
+
+        Ok(())
+    }
+
+    /// Decodes and dispatches bytes that were already read from the transport, for event loops that
+    /// read from non-blocking sources themselves. Partial frames are kept until the next `feed` or `recv`,
+    /// `tx_drain` is only written to and never read from.
+    pub fn feed<T,P,O,D,E>(&mut self, bytes: &[u8], tx_drain: &mut T, mut recv_drain: P, mut observe_drain: O, mut dup_drain: D, mut error_drain: E) -> Result<(), RecvError>
+        where
+            T: io::Write,
+            P: FnMut(&frame::Frame, &[u8]),
+            O: FnMut(&ObservedFrame),
+            D: FnMut(&frame::Frame),
+            E: FnMut(Option<prn_id::PrnValue>, &RecvError, &[u8])
+    {
+        self.recv_buffer.extend_from_slice(bytes);
+
+        try!(self.process_buffer(tx_drain,
+            &mut |header, data, _| recv_drain(header, data),
+            &mut |observed, _| observe_drain(observed),
+            &mut dup_drain,
+            &mut error_drain));
+        try!(self.send_pending_acks(tx_drain));
+
+        Ok(())
+    }
+
+    /// Dispatches every complete frame in our receive buffer, dropping any noise ahead of the next one
+    fn process_buffer<T,P,O,D,E>(&mut self, tx_drain: &mut T, recv_drain: &mut P, observe_drain: &mut O, dup_drain: &mut D, error_drain: &mut E) -> Result<(), RecvError>
+        where
+            T: io::Write,
+            P: FnMut(&frame::Frame, &[u8], &RecvInfo),
+            O: FnMut(&ObservedFrame, &RecvInfo),
+            D: FnMut(&frame::Frame),
+            E: FnMut(Option<prn_id::PrnValue>, &RecvError, &[u8])
+    {
+        //Parse any KISS frames, including ones left over from a stopped recv
+        loop {
+            if self.is_stopped() {
+                trace!("Stop requested, leaving {} bytes buffered", self.recv_buffer.len());
+                break
+            }
+
+            self.kiss_frame_scratch.drain(..);
+            match self.framing.decode(&self.recv_buffer, &mut self.kiss_frame_scratch) {
+                Some(ref decoded) if decoded.command != kiss::CMD_DATA => {
+                    trace!("Skipping KISS command {} on port {}, not a data frame", decoded.command, decoded.port);
+                    self.recv_buffer.drain(..decoded.bytes_read);
+                },
+                Some(ref decoded) if !self.accepts_port(decoded.port) => {
+                    trace!("Skipping frame on port {}, not in our accepted ports", decoded.port);
+                    self.recv_buffer.drain(..decoded.bytes_read);
+                },
+                Some(decoded) => {
+                    //Take the decoded bytes so we can lend them to the drains while dispatching
+                    let raw = mem::replace(&mut self.kiss_frame_scratch, vec!());
+
+                    let result = match frame::parse(&raw[..decoded.payload_size]) {
+                        //Anything after a length prefixed frame means it was merged with something else
+                        Ok((packet, _, rest)) if rest.len() > 0 => {
+                            error!("Frame {} had {} unexpected bytes after it", packet.prn, rest.len());
+                            Err((None, RecvError::Frame(frame::ReadError::Truncated)))
+                        },
+                        Ok((packet, payload, _)) => {
+                            let destination = routing::is_destination(&packet.address_route, self.prn.callsign);
+                            let info = RecvInfo {
+                                port: decoded.port,
+                                src: routing::get_source(&packet.address_route),
+                                addressed_to_us: destination && routing::final_addr(&packet.address_route),
+                                is_relay: destination && !routing::final_addr(&packet.address_route),
+                                raw: &raw[..decoded.payload_size]
+                            };
+
+                            self.hear(info.src);
+                            self.dispatch_recv(tx_drain, &info, &packet, payload, recv_drain, observe_drain, dup_drain)
+                                .map_err(|e| (Some(packet.prn), e))
+                        },
+                        Err(e) => Err((None, RecvError::Frame(e)))
+                    };
+
+                    //Clear recieved, make sure we do this even on error
+                    self.recv_buffer.drain(..decoded.bytes_read);
+
+                    let result = match result {
+                        Ok(()) => Ok(()),
+                        //Failing to write to the transport is fatal, anything else only affects this frame
+                        Err((_, e)) if is_transport_error(&e) => Err(e),
+                        Err((prn, e)) => {
+                            trace!("Dropping frame {:?} that failed to decode or send {:?}", prn, e);
+                            error_drain(prn, &e, &raw[..decoded.payload_size]);
+                            Ok(())
+                        }
+                    };
+
+                    self.kiss_frame_scratch = raw;
+                    try!(result);
+                },
+                None => break
+            }
+        }
+
+        //Anything ahead of where a frame could start will never decode
+        let noise = self.framing.noise(&self.recv_buffer);
+        if noise > 0 {
+            trace!("Dropping {} bytes of noise ahead of the next frame", noise);
+            self.recv_buffer.drain(..noise);
+        }
+
+        //Whatever is left is a partial frame, a peer that never sends a FEND would grow this forever
+        if self.recv_buffer.len() > self.max_recv_buffer {
+            warn!("Receive buffer reached {} bytes without a complete frame, clearing", self.recv_buffer.len());
+            self.recv_buffer.clear();
+            error_drain(None, &RecvError::Overflow, &[]);
+        }
+
+        Ok(())
+    }
+
+    /// Records that we heard `src`, called once per received frame
+    fn hear(&mut self, src: u32) {
+        if !self.heard.contains_key(&src) && self.heard.len() >= MAX_HEARD_STATIONS {
+            let oldest = self.heard.values().min_by_key(|station| station.last_heard).map(|station| station.addr);
+            if let Some(addr) = oldest {
+                trace!("Forgetting {}, heard too many stations", address::format_addr(addr));
+                self.heard.remove(&addr);
+            }
+        }
+
+        let clock_ms = self.clock_ms;
+        let station = self.heard.entry(src).or_insert(HeardStation {
+            addr: src,
+            last_heard: clock_ms,
+            count: 0,
+            timed_out: false
+        });
+
+        station.last_heard = clock_ms;
+        station.count += 1;
+        station.timed_out = false;
+    }
+
+    /// Dispaches packet based on data/ack and if this was a routing destination
+    fn dispatch_recv<T,P,O,D>(&mut self, tx_drain: &mut T, info: &RecvInfo, packet: &frame::Frame, payload: &[u8], recv_drain: &mut P, observe_drain: &mut O, dup_drain: &mut D) -> Result<(), RecvError>
+        where 
+            T: io::Write,
+            P: FnMut(&frame::Frame, &[u8], &RecvInfo),
+            O: FnMut(&ObservedFrame, &RecvInfo),
+            D: FnMut(&frame::Frame)
+    {
+        let port = info.port;
+        let mut forwarded = false;
+
+        //Keepalives only tell us the station is there, beacons also carry a payload for the client
+        if frame::is_keepalive(packet) {
+            trace!("Keepalive from {}", address::format_addr(info.src));
+
+            if payload.len() > 0 {
+                recv_drain(packet, payload, info);
+            }
+
+            return Ok(())
+        }
+
+        //Multi-acks addressed to us or just overheard are handled as one ack per PRN, only forwarding passes them along as is
+        if frame::is_multi_ack(packet) && !info.is_relay {
+            for prn in frame::multi_ack_prns(packet, payload) {
+                let ack = frame::new_ack(prn, packet.address_route);
+
+                try!(self.dispatch_recv(tx_drain, info, &ack, &[], recv_drain, observe_drain, dup_drain));
+            }
+
+            return Ok(())
+        }
+
+        //Pings are answered by us rather than delivered, relays pass them along like any other frame
+        if frame::is_ping(packet) && info.addressed_to_us && !self.monitor {
+            match frame::pong_prn(packet, payload) {
+                Some(ping_prn) => {
+                    match self.pings.remove(&ping_prn) {
+                        Some(sent) => {
+                            let rtt = self.clock_ms - sent;
+                            debug!("Pong for {} from {} in {}ms", ping_prn, address::format_addr(info.src), rtt);
+
+                            if let Some(ref mut on_pong) = self.on_pong {
+                                on_pong(ping_prn, rtt);
+                            }
+                        },
+                        None => trace!("Pong for unknown ping {}", ping_prn)
+                    }
+                },
+                None if self.ping_reply => {
+                    let (pong, pong_payload) = frame::new_pong(self.prn.next(), packet.prn, routing::reverse(&packet.address_route));
+                    let written = try!(Node::send_frame(&*self.framing, pong, &pong_payload, port, tx_drain));
+                    self.transmitted(written as u64);
+                    trace!("Answered ping {} from {}", packet.prn, address::format_addr(info.src));
+                },
+                None => trace!("Ignoring ping {}, replies are disabled", packet.prn)
+            }
+
+            return Ok(())
+        }
+
+        if self.monitor {
+            trace!("Monitoring frame {}", packet.prn);
+        } else if routing::is_destination(&packet.address_route, self.prn.callsign) {
+            trace!("Recieved packet with our address in the route {}", packet.prn);
+
+            //Respond that we've received this packet if we're the final destination, note that
+            //we might ack something we've already receieved since the sender may have not
+            //heard the ack.
+            if routing::final_addr(&packet.address_route) {
+                //If we got an ack packet then pass that along to our tx queue
+                if frame::is_ack(packet, payload) {
+                    trace!("Recieved ack {}", packet.prn);
+                    if let Some(rtt) = self.tx_queue.ack_recv_rtt(packet.prn) {
+                        if let Some(ref mut on_ack) = self.on_ack {
+                            on_ack(packet.prn, rtt as u64);
+                        }
+                    }
+                    recv_drain(&packet, payload, info);
+                } else {
+                    if self.coalesce_acks {
+                        trace!("Holding ack for {} to coalesce", packet.prn);
+                        self.pending_acks.push((packet.prn, routing::reverse(&packet.address_route), port));
+                    } else {
+                        let ack = frame::new_ack(packet.prn, routing::reverse(&packet.address_route));
+                        let mut ack_packet: [u8; frame::MAX_ACK_SIZE] = unsafe { mem::uninitialized() };
+                        let ack_packet_len = try!(frame::to_bytes(&mut io::Cursor::new(&mut ack_packet[..frame::MAX_ACK_SIZE]), &ack, None));
+                        let written = try!(self.framing.encode(&ack_packet[..ack_packet_len], tx_drain, port));
+                        try!(tx_drain.flush());
+                        self.transmitted(written as u64);
+                        trace!("Sending ack for {} on port {}", packet.prn, port);
+                    }
+
+                    let new_packet = !self.dedup || !self.recv_prn_table.contains(packet.prn);
+
+                    //Don't process duplicates
+                    if new_packet {
+                        trace!("New packet that we haven't seen yet");
+                        self.recv_prn_table.add(packet.prn);
+
+                        //If we're the final destination then we should process this packet
+                        info!("Received packet {} with {} bytes from {}", packet.prn, payload.len(), address::format_addr(info.src));
+                        recv_drain(&packet, payload, info);
+                    } else {
+                        trace!("Duplicate packet already recieved before");
+                        dup_drain(&packet);
+                    }
+                }
+            } else {    //Route this packet along
+                trace!("Packet has routes yet to complete, sending");
+
+                if routing::forward_len(&packet.address_route) > self.max_hops {
+                    trace!("Route for {} is longer than {} hops, dropping {}", packet.prn, self.max_hops, routing::format_route(&packet.address_route));
+                    return Err(RecvError::Routing(routing::ParseError::TooLong))
+                }
+
+                let mut routed_header = *packet;
+                routed_header.address_route = try!(routing::advance(&packet.address_route, self.prn.callsign));
+
+                //@todo: Reject packets that already have this ID in the source path since that means we've seen it before
+
+                //Just pass along on the port we heard it, we don't ack unless we are the end host
+                let written = try!(Node::send_frame(&*self.framing, routed_header, payload, port, tx_drain));
+                self.transmitted(written as u64);
+                forwarded = true;
+            }
+        } else {
+            trace!("Data frame but addr {:?} is not our dest {:?}", address::decode(packet.address_route[0]), address::decode(self.prn.callsign));
+        }
+
+        let observed = if forwarded {
+            ObservedFrame::Forwarded {
+                header: packet,
+                payload: payload
+            }
+        } else if frame::is_ack(packet, payload) {
+            ObservedFrame::Ack {
+                header: packet,
+                src: routing::get_source(&packet.address_route)
+            }
+        } else {
+            ObservedFrame::Data {
+                header: packet,
+                payload: payload
+            }
+        };
+
+        observe_drain(&observed, info);
+
+        Ok(())
+    }
+
+    /// Sends every held ack, grouped into multi-acks by return route and port
+    fn send_pending_acks<T>(&mut self, tx_drain: &mut T) -> Result<(), RecvError> where T: io::Write {
+        let mut pending = mem::replace(&mut self.pending_acks, vec!());
+
+        while pending.len() > 0 {
+            let (_, route, port) = pending[0];
+
+            let (group, rest): (Vec<_>, Vec<_>) = pending.into_iter()
+                .partition(|&(_, ack_route, ack_port)| ack_route == route && ack_port == port);
+            pending = rest;
+
+            let prns = group.iter().map(|&(prn, _, _)| prn).collect::<Vec<_>>();
+
+            for chunk in prns.chunks(frame::MAX_MULTI_ACK) {
+                //Single acks stay legacy so they're understood by everyone
+                let (header, payload) = if chunk.len() == 1 {
+                    (frame::new_ack(chunk[0], route), vec!())
+                } else {
+                    frame::new_multi_ack(chunk, route)
+                };
+
+                trace!("Sending ack for {} frames on port {}", chunk.len(), port);
+                let written = try!(Node::send_frame(&*self.framing, header, &payload, port, tx_drain));
+                self.transmitted(written as u64);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Receives any pending frames then ticks retries, pushing everything that happened into `events`.
+    /// Reads that time out or would block count as no data rather than errors. Frames that fail to decode
+    /// are dropped, use `recv`/`tick` directly if you need to know about them.
+    pub fn pump<RW>(&mut self, rx_tx: &mut RW, elapsed_ms: usize, events: &mut Vec<Event>) -> Result<(), RecvError>
+        where RW: io::Read + io::Write
+    {
+        let events = RefCell::new(events);
+
+        let recv = self.recv(rx_tx,
+            |header, data| {
+                if frame::is_ack(header, data) {
+                    events.borrow_mut().push(Event::Ack(*header));
+                } else {
+                    events.borrow_mut().push(Event::Recv(*header, data.to_vec()));
+                }
+            },
+            |observed| {
+                let event = match *observed {
+                    ObservedFrame::Forwarded { header, payload } => Event::Forward(*header, payload.to_vec()),
+                    _ => Event::Observe(*observed.header(), observed.payload().to_vec())
+                };
+
+                events.borrow_mut().push(event);
+            },
+            |_| {},
+            |prn, e, _| {
+                trace!("Pump dropped frame {:?} {:?}", prn, e);
+            });
+
+        match recv {
+            Ok(()) => (),
+            Err(RecvError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => (),
+            Err(e) => return Err(e)
+        }
+
+        try!(self.tick(rx_tx, elapsed_ms,
+            |header, _, next_retry| {
+                events.borrow_mut().push(Event::Retry(*header, next_retry));
+            },
+            |header, data| {
+                events.borrow_mut().push(Event::Expire(*header, data.to_vec()));
+            },
+            |congested| {
+                events.borrow_mut().push(Event::Congestion(congested));
+            }));
+
+        Ok(())
+    }
+
+    /// Milliseconds of elapsed time until tick next has a retry or discard to process, None if nothing is waiting for an ack
+    pub fn next_tick_deadline(&self) -> Option<usize> {
+        self.tx_queue.next_deadline()
+    }
+
+    /// Same as `tick` but works out elapsed_ms from our clock, see `set_clock`. The first call only starts the clock.
+    pub fn tick_now<T,R,D,C>(&mut self, tx_drain: &mut T, retry_drain: R, discard_drain: D, congestion_drain: C) -> Result<(), SendError>
+        where
+            T: io::Write,
+            R: FnMut(&frame::Frame, &[u8], usize),
+            D: FnMut(&frame::Frame, &[u8]),
+            C: FnMut(bool)
+    {
+        let now = self.clock.now_ms();
+        let elapsed_ms = self.last_tick_now.map(|last| now.saturating_sub(last)).unwrap_or(0);
+        self.last_tick_now = Some(now);
+
+        self.tick(tx_drain, elapsed_ms as usize, retry_drain, discard_drain, congestion_drain)
+    }
+
+    /// Ticks any packet retries that need to be sent. congestion_drain is called with the new state whenever
+    /// congestion control starts or stops, new sends should be held off while congested.
+    pub fn tick<T,R,D,C>(&mut self, tx_drain: &mut T, elapsed_ms: usize, retry_drain: R, discard_drain: D, mut congestion_drain: C) -> Result<(), SendError>
+        where
+            T: io::Write,
+            R: FnMut(&frame::Frame, &[u8], usize),
+            D: FnMut(&frame::Frame, &[u8]),
+            C: FnMut(bool)
+    {
+        //Time passes even if a retry fails to write, otherwise last heard and ping times would drift
+        self.clock_ms += elapsed_ms as u64;
+
+        if !self.channel.is_clear() {
+            self.tx_queue.hold(elapsed_ms);
+        } else {
+            try!(self.tick_clear(tx_drain, elapsed_ms, retry_drain, discard_drain));
+        }
+
+        if let Some(congested) = self.tx_queue.update_congestion() {
+            congestion_drain(congested);
+        }
+
+        if let Some(interval) = self.keepalive_interval {
+            self.since_keepalive += elapsed_ms;
+
+            //Wait for the channel rather than deferring, there's no point in queuing up stale keepalives
+            if self.since_keepalive >= interval && self.channel.is_clear() {
+                let mut route: routing::Route = [routing::ADDRESS_SEPARATOR; routing::MAX_LENGTH];
+                route[0] = routing::BROADCAST_ADDRESS;
+                route[2] = self.prn.callsign;
+
+                let keepalive = frame::new_keepalive(self.prn.next(), route);
+
+                trace!("Sending keepalive {}", keepalive.prn);
+                let written = try!(Node::send_frame(&*self.framing, keepalive, &[], self.tx_port, tx_drain));
+                self.transmitted(written as u64);
+                self.since_keepalive = 0;
+            }
+        }
+
+        try!(self.tick_beacon(tx_drain, elapsed_ms));
+
+        Ok(())
+    }
+
+    fn tick_beacon<T>(&mut self, tx_drain: &mut T, elapsed_ms: usize) -> Result<(), SendError> where T: io::Write {
+        let interval = match self.beacon {
+            Some((interval, _)) => interval,
+            None => return Ok(())
+        };
+
+        self.since_beacon += elapsed_ms;
+
+        //Sent as a keepalive so it isn't acked, the payload rides along for anyone listening
+        if self.since_beacon >= interval && self.channel.is_clear() {
+            let mut route: routing::Route = [routing::ADDRESS_SEPARATOR; routing::MAX_LENGTH];
+            route[0] = routing::BROADCAST_ADDRESS;
+            route[2] = self.prn.callsign;
+
+            let beacon = frame::new_keepalive(self.prn.next(), route);
+            let written = match self.beacon {
+                Some((_, ref payload)) => try!(Node::send_frame(&*self.framing, beacon, payload, self.tx_port, tx_drain)),
+                None => 0
+            };
+
+            debug!("Sent beacon {}", beacon.prn);
+            self.transmitted(written as u64);
+        }
+
+        Ok(())
+    }
+
+    fn tick_clear<T,R,D>(&mut self, tx_drain: &mut T, elapsed_ms: usize, mut retry_drain: R, discard_drain: D) -> Result<(), SendError>
+        where
+            T: io::Write,
+            R: FnMut(&frame::Frame, &[u8], usize),
+            D: FnMut(&frame::Frame, &[u8])
+    {
+        let mut written = 0;
+        let result = {
+            let framing = &*self.framing;
+            let result = self.tx_queue.tick::<_,_,SendError>(elapsed_ms,
+                |header, data, port, next_retry| {
+                    trace!("Packet {} retrying", header.prn);
+
+                    //Retry our frame on the same port it was originally sent
+                    written += try!(Node::send_frame(framing, *header, data, port, tx_drain)) as u64;
+
+                    //Notify client that we resent
+                    retry_drain(header, data, next_retry);
+
+                    Ok(())
+                },
+                discard_drain);
+
+            //First transmit of anything held off while the channel was busy, these aren't retries
+            match result {
+                Ok(()) => self.tx_queue.send_deferred::<_,SendError>(|header, data, port| {
+                    written += try!(Node::send_frame(framing, *header, data, port, tx_drain)) as u64;
+                    Ok(())
+                }),
+                Err(e) => Err(e)
+            }
+        };
+
+        //Anything that went out before an error still counts
+        self.transmitted(written);
+        result
+    }
+}
+
+#[test]
+fn test_send() {
+    let addr = [
+        address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap(),
+        address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap()
+    ];
+
+    let mut node = new(addr[1]);
+
+    let mut tx: Vec<u8> = vec!();
+
+    node.send((0..5).map(|x| x as u8), addr.iter().cloned(), &mut tx).unwrap();
+
+    assert!(tx.len() > 0);
+}
+
+#[test]
+fn test_send_recv() {
+    let data = (0..5).map(|x| x as u8).collect::<Vec<_>>();
+
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut tx_local = vec!();
+    let mut tx_remote = vec!();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    let prn = local.send(data.iter().cloned(), [remote_addr].iter().cloned(), &mut tx_local).unwrap();
+    assert_eq!(local.pending_packets(), 1);
+
+    let mut match_recv = false;
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx_local), &mut tx_remote),
+        |_,recv_data| {
+            match_recv = true;
+            assert!(recv_data.iter().eq(data.iter()));
+        },
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+
+    assert!(match_recv);
+
+    tx_local.drain(..);
+
+    let mut match_ack = false;
+    local.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx_remote), &mut tx_local),
+        |_,_| {},
+        |observed| {
+            match *observed {
+                ObservedFrame::Ack { header, src } => {
+                    match_ack = true;
+                    assert_eq!(prn, header.prn);
+                    assert_eq!(src, remote_addr);
+                    assert_eq!(header.address_route, routing::gen_route(&[local_addr, routing::ADDRESS_SEPARATOR, remote_addr]));
+                },
+                _ => assert!(false)
+            }
+        },
+        |_| {},
+        |_,_,_| assert!(false)).unwrap();
+
+    assert!(match_ack);
+    assert_eq!(local.pending_packets(), 0);
+}
+
+#[test]
+fn test_send_empty() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    let mut tx = vec!();
+    let prn = local.send_slice(&[], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    assert_eq!(local.pending_packets(), 1);
+
+    //Delivered as data and acked like any other
+    let mut received = vec!();
+    let mut ack = vec!();
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut ack),
+        |header,data| received.push((header.prn, data.len())),
+        |observed| match *observed {
+            ObservedFrame::Data { .. } => (),
+            _ => assert!(false)
+        },
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(received, vec!((prn, 0)));
+    assert!(ack.len() > 0);
+
+    local.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&ack), &mut vec!()),
+        |_,_| {},
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(local.pending_packets(), 0);
+
+    //Every way of sending marks them, and pump reports them as data rather than acks
+    let mut tx = vec![0; frame::MAX_PACKET_SIZE * 2];
+    let (slice_prn, written) = local.send_to_slice(&[], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    tx.truncate(written);
+
+    //PRN from a different generator so it doesn't collide with what local has already sent
+    let raw = frame::new_header(&mut prn_id::new(remote_addr), [remote_addr, routing::ADDRESS_SEPARATOR, local_addr].iter().cloned()).unwrap();
+    local.send_raw(raw, &[], &mut tx).unwrap();
+
+    let mut events = vec!();
+    remote.pump(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut vec!()), 0, &mut events).unwrap();
+
+    let received = events.iter()
+        .filter_map(|event| match *event {
+            Event::Recv(ref header, ref data) => Some((header.prn, data.len())),
+            Event::Ack(_) => { assert!(false); None },
+            _ => None
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(received, vec!((slice_prn, 0), (raw.prn, 0)));
+}
+
+#[test]
+fn test_builder() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = builder(local_addr)
+        .tx_port(3)
+        .max_payload(16)
+        .max_hops(2)
+        .max_in_flight(1)
+        .build();
+    let mut remote = builder(remote_addr)
+        .rx_ports(&[3])
+        .dedup(false)
+        .build();
+
+    assert_eq!(local.max_payload(), 16);
+    assert_eq!(local.max_hops(), 2);
+
+    match local.send_slice(&[0; 17], [remote_addr].iter().cloned(), &mut vec!()) {
+        Err(SendError::Truncated) => (),
+        _ => assert!(false)
+    }
+
+    let mut tx = vec!();
+    local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    match local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut vec!()) {
+        Err(SendError::Enqueue(tx_queue::QueueError::TooManyInFlight)) => (),
+        _ => assert!(false)
+    }
+
+    //Sent on port 3, which is the only one remote listens to, and delivered twice without dedup
+    let frame = tx.clone();
+    tx.extend_from_slice(&frame);
+
+    let mut recv_count = 0;
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut vec!()),
+        |_,_| recv_count += 1,
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(recv_count, 2);
+
+    //Defaults match new
+    let defaults = builder(local_addr).build();
+    assert_eq!(defaults.max_payload(), frame::MTU);
+    assert_eq!(defaults.max_hops(), routing::MAX_LENGTH);
+
+    //Monitors only observe, even frames addressed to them
+    let mut monitor = builder(remote_addr)
+        .rx_ports(&[3])
+        .monitor(true)
+        .build();
+
+    let mut observed = 0;
+    let mut ack = vec!();
+    monitor.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&frame), &mut ack),
+        |_,_| assert!(false),
+        |_| observed += 1,
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(observed, 1);
+    assert_eq!(ack.len(), 0);
+    assert_eq!(monitor.bytes_transmitted(), 0);
+
+    //Packets give up after the configured number of retries
+    let mut retrying = builder(local_addr)
+        .retry_count(1)
+        .build();
+    retrying.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut vec!()).unwrap();
+
+    let mut retries = 0;
+    let mut discards = 0;
+    for _ in 0..3 {
+        retrying.tick(&mut vec!(), tx_queue::RETRY_DELAY_MS << tx_queue::RETRY_COUNT,
+            |_,_,_| retries += 1,
+            |_,_| discards += 1,
+            |_| {}).unwrap();
+    }
+    assert_eq!(retries, 1);
+    assert_eq!(discards, 1);
+    assert_eq!(retrying.pending_packets(), 0);
+}
+
+#[test]
+fn test_drop_all_pending() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+
+    let mut tx = vec!();
+    let prns = (0..4u8)
+        .map(|i| local.send_slice(&[i], [remote_addr].iter().cloned(), &mut tx).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(local.pending_packets(), 4);
+
+    let mut dropped = vec!();
+    local.drop_all_pending(|header, data| dropped.push((header.prn, data[0])));
+    assert_eq!(dropped, prns.iter().cloned().zip(0..4).collect::<Vec<_>>());
+    assert_eq!(local.pending_packets(), 0);
+
+    local.tick(&mut tx, tx_queue::RETRY_DELAY_MS, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+}
+
+#[test]
+fn test_recv_simple() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    let mut tx = vec!();
+    let prn = local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+
+    let mut received = vec!();
+    let mut ack = vec!();
+    remote.recv_simple(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut ack),
+        |header, data| received.push((header.prn, data.to_vec()))).unwrap();
+    assert_eq!(received, vec!((prn, vec!(1, 2, 3))));
+
+    local.recv_simple(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&ack), &mut vec!()), |_,_| {}).unwrap();
+    assert_eq!(local.pending_packets(), 0);
+}
+
+#[test]
+fn test_length_prefix_framing() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = builder(local_addr)
+        .framing(framing::LengthPrefixFraming)
+        .build();
+    let mut remote = new(remote_addr);
+    remote.set_framing(framing::LengthPrefixFraming);
+
+    //FENDs don't need escaping so the frame is just prefixed with its length
+    let data = [kiss::FEND, kiss::FESC, 1, 2];
+    let mut tx = vec!();
+    let prn = local.send_slice(&data, [remote_addr].iter().cloned(), &mut tx).unwrap();
+    assert_eq!(((tx[0] as usize) << 8) | tx[1] as usize, tx.len() - framing::LENGTH_PREFIX_SIZE);
+
+    let mut to_slice = [0; frame::MAX_PACKET_SIZE];
+    let (_, written) = local.send_to_slice(&data, [remote_addr].iter().cloned(), &mut to_slice).unwrap();
+    tx.extend_from_slice(&to_slice[..written]);
+
+    let mut received = vec!();
+    let mut ack = vec!();
+    remote.recv_simple(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut ack),
+        |header, payload| received.push((header.prn, payload.to_vec()))).unwrap();
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0], (prn, data.to_vec()));
+    assert_eq!(received[1].1, data.to_vec());
+
+    local.recv_simple(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&ack), &mut vec!()), |_,_| {}).unwrap();
+    assert_eq!(local.pending_packets(), 0);
+
+    //A KISS node can't make sense of any of it
+    let mut kiss_node = new(remote_addr);
+    kiss_node.recv_simple(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut vec!()),
+        |_,_| assert!(false)).unwrap();
+}
+
+#[test]
+fn test_try_send() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let data = [0; frame::MTU];
+
+    //Fill the queue right up to the block size
+    let mut tx = vec!();
+    let mut sent = 0;
+    while local.buffered_bytes() + data.len() <= tx_queue::BLOCK_SIZE {
+        local.try_send(&data, [remote_addr].iter().cloned(), &mut tx).unwrap();
+        sent += 1;
+    }
+    assert_eq!(local.pending_packets(), sent);
+
+    //Nothing is queued or written when it won't fit
+    let written = tx.len();
+    match local.try_send(&data, [remote_addr].iter().cloned(), &mut tx) {
+        Err(TrySendError::WouldCongest) => (),
+        _ => assert!(false)
+    }
+    assert_eq!(tx.len(), written);
+    assert_eq!(local.pending_packets(), sent);
+
+    //Smaller frames that still fit go out
+    let room = tx_queue::BLOCK_SIZE - local.buffered_bytes();
+    assert!(room > 0);
+    local.try_send(&data[..room], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    assert_eq!(local.pending_packets(), sent + 1);
+    assert_eq!(local.buffered_bytes(), tx_queue::BLOCK_SIZE);
+
+    //A full queue still takes a duplicate since it merges into the frame already in flight
+    let written = tx.len();
+    match local.try_send(&data, [remote_addr].iter().cloned(), &mut tx) {
+        Err(TrySendError::WouldCongest) => (),
+        _ => assert!(false)
+    }
+    local.set_merge_duplicates(true);
+    local.try_send(&data, [remote_addr].iter().cloned(), &mut tx).unwrap();
+    assert_eq!(local.pending_packets(), sent + 1);
+    assert_eq!(tx.len(), written);
+
+    //Other errors are passed through
+    match local.try_send(&[], [remote_addr; routing::MAX_LENGTH].iter().cloned(), &mut tx) {
+        Err(TrySendError::Send(SendError::Frame(_))) => (),
+        _ => assert!(false)
+    }
+}
+
+#[cfg(test)]
+struct FakeClock(Arc<::std::sync::atomic::AtomicUsize>);
+
+#[cfg(test)]
+impl clock::Clock for FakeClock {
+    fn now_ms(&self) -> u64 {
+        self.0.load(Ordering::SeqCst) as u64
+    }
+}
+
+#[test]
+fn test_tick_now() {
+    use std::sync::atomic::AtomicUsize;
+
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let now = Arc::new(AtomicUsize::new(10000));
+    let mut local = new(local_addr);
+    local.set_clock(FakeClock(now.clone()));
+
+    let mut tx = vec!();
+    local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+
+    //First tick only starts the clock
+    local.tick_now(&mut tx, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+    assert_eq!(local.clock_ms(), 0);
+
+    now.fetch_add(tx_queue::RETRY_DELAY_MS - 1, Ordering::SeqCst);
+    local.tick_now(&mut tx, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+    assert_eq!(local.clock_ms(), tx_queue::RETRY_DELAY_MS as u64 - 1);
+
+    //Only the time since the last tick is passed along
+    let mut retries = 0;
+    now.fetch_add(1, Ordering::SeqCst);
+    local.tick_now(&mut tx, |_,_,_| retries += 1, |_,_| assert!(false), |_| {}).unwrap();
+    assert_eq!(retries, 1);
+    assert_eq!(local.clock_ms(), tx_queue::RETRY_DELAY_MS as u64);
+
+    //Nothing passes between back to back ticks
+    local.tick_now(&mut tx, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+    assert_eq!(local.clock_ms(), tx_queue::RETRY_DELAY_MS as u64);
+
+    //Swapping clocks starts over
+    local.set_clock(FakeClock(Arc::new(AtomicUsize::new(5))));
+    local.tick_now(&mut tx, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+    assert_eq!(local.clock_ms(), tx_queue::RETRY_DELAY_MS as u64);
+}
+
+#[test]
+fn test_retry_over_lossy_link() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let (local_io, mut remote_io) = util::duplex();
+    let mut local_io = util::new_lossy(local_io, 1.0);
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    //First transmit never makes it
+    let prn = local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut local_io).unwrap();
+    assert_eq!(local_io.dropped(), 1);
+
+    let mut received = vec!();
+    remote.recv(&mut remote_io, |header,_| received.push(header.prn), |_| {}, |_| assert!(false), |_,_,_| assert!(false)).unwrap();
+    assert_eq!(received.len(), 0);
+
+    //Retry goes out once the link clears up and the ack finds its way back
+    local_io.set_loss(0.0);
+    local.tick(&mut local_io, tx_queue::RETRY_DELAY_MS, |_,_,_| {}, |_,_| assert!(false), |_| {}).unwrap();
+
+    remote.recv(&mut remote_io, |header,_| received.push(header.prn), |_| {}, |_| assert!(false), |_,_,_| assert!(false)).unwrap();
+    assert_eq!(received, vec!(prn));
+
+    local.recv(&mut local_io, |_,_| {}, |_| {}, |_| assert!(false), |_,_,_| assert!(false)).unwrap();
+    assert_eq!(local.pending_packets(), 0);
+}
+
+#[test]
+fn test_dedup() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    let mut tx = vec!();
+    local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    let frame = tx.clone();
+    tx.extend_from_slice(&frame);
+
+    //Second copy is a duplicate by default
+    let mut recv_count = 0;
+    let mut dup_count = 0;
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut vec!()),
+        |_,_| recv_count += 1,
+        |_| {},
+        |_| dup_count += 1,
+        |_,_,_| assert!(false)).unwrap();
+
+    assert_eq!(recv_count, 1);
+    assert_eq!(dup_count, 1);
+
+    //Without dedup both copies come through
+    let mut remote = new(remote_addr);
+    remote.set_dedup(false);
+
+    let mut recv_count = 0;
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut vec!()),
+        |_,data| {
+            recv_count += 1;
+            assert_eq!(data, &[1, 2, 3]);
+        },
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+
+    assert_eq!(recv_count, 2);
+}
+
+#[test]
+fn test_bytes_transmitted() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+    assert_eq!(local.bytes_transmitted(), 0);
+
+    //Escaped bytes count as what actually went out
+    let mut tx = vec!();
+    local.send_slice(&[kiss::FEND, kiss::FESC, 1], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    assert_eq!(local.bytes_transmitted(), tx.len() as u64);
+
+    let mut ack = vec!();
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut ack),
+        |_,_| {},
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(remote.bytes_transmitted(), ack.len() as u64);
+
+    //Retries are counted too
+    let mut retry = vec!();
+    local.tick(&mut retry, tx_queue::RETRY_DELAY_MS, |_,_,_| {}, |_,_| assert!(false), |_| {}).unwrap();
+    assert_eq!(retry.len(), tx.len());
+    assert_eq!(local.bytes_transmitted(), (tx.len() + retry.len()) as u64);
+
+    local.reset_bytes_transmitted();
+    assert_eq!(local.bytes_transmitted(), 0);
+}
+
+#[cfg(test)]
+struct TestChannel {
+    clear: ::std::sync::Arc<::std::sync::atomic::AtomicBool>
+}
+
+#[cfg(test)]
+impl channel::ChannelState for TestChannel {
+    fn is_clear(&self) -> bool {
+        self.clear.load(::std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+#[test]
+fn test_channel_busy() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let clear = Arc::new(AtomicBool::new(false));
+    let mut local = new(local_addr);
+    local.set_channel_state(TestChannel { clear: clear.clone() });
+
+    //Busy channel queues the frame without writing it
+    let mut tx = vec!();
+    let prn = local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    assert_eq!(tx.len(), 0);
+    assert_eq!(local.pending_packets(), 1);
+
+    //Stays held for as long as the channel is busy
+    for _ in 0..4 {
+        local.tick(&mut tx, tx_queue::RETRY_DELAY_MS, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+        assert_eq!(tx.len(), 0);
+    }
+
+    let deadline = local.next_tick_deadline().unwrap();
+    assert!(deadline >= tx_queue::SLOT_TIME_MS && deadline < tx_queue::SLOT_TIME_MS * 2);
+
+    //Once clear it goes out as a first transmit rather than a retry
+    clear.store(true, Ordering::SeqCst);
+    local.tick(&mut tx, deadline - 1, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+    assert_eq!(tx.len(), 0);
+    local.tick(&mut tx, 1, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+    assert!(tx.len() > 0);
+    assert_eq!(local.bytes_transmitted(), tx.len() as u64);
+
+    let mut remote = new(remote_addr);
+    let mut recv_prn = None;
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut vec!()),
+        |header,_| recv_prn = Some(header.prn),
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(recv_prn, Some(prn));
+
+    //After that it's retried as usual
+    let mut retry_count = 0;
+    local.tick(&mut vec!(), tx_queue::RETRY_DELAY_MS, |_,_,_| retry_count += 1, |_,_| assert!(false), |_| {}).unwrap();
+    assert_eq!(retry_count, 1);
+}
+
+#[test]
+fn test_coalesce_acks() {
+    const FRAME_COUNT: usize = 5;
+
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+    remote.set_coalesce_acks(true);
+
+    let mut tx = vec!();
+    let prns = (0..FRAME_COUNT)
+        .map(|i| local.send_slice(&[i as u8], [remote_addr].iter().cloned(), &mut tx).unwrap())
+        .collect::<Vec<_>>();
+
+    let mut recv_count = 0;
+    let mut ack = vec!();
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut ack),
+        |_,_| recv_count += 1,
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(recv_count, FRAME_COUNT);
+
+    //Every PRN goes back in a single frame
+    let mut decoded = vec!();
+    let kiss_frame = kiss::decode(ack.iter().cloned(), &mut decoded).unwrap();
+    assert_eq!(kiss_frame.bytes_read, ack.len());
+
+    let mut payload = [0; frame::MTU];
+    let (header, payload_size) = frame::from_bytes(&mut io::Cursor::new(&decoded), &mut payload, kiss_frame.payload_size).unwrap();
+    assert!(frame::is_multi_ack(&header));
+    assert_eq!(frame::multi_ack_prns(&header, &payload[..payload_size]), prns);
+
+    let mut acked = vec!();
+    local.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&ack), &mut vec!()),
+        |header,data| {
+            assert_eq!(data.len(), 0);
+            acked.push(header.prn);
+        },
+        |observed| {
+            match *observed {
+                ObservedFrame::Ack { src, .. } => assert_eq!(src, remote_addr),
+                _ => assert!(false)
+            }
+        },
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+
+    assert_eq!(acked, prns);
+    assert_eq!(local.pending_packets(), 0);
+}
+
+#[test]
+fn test_keepalive() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+    local.set_keepalive(Some(1000));
+
+    let mut tx = vec!();
+    local.tick(&mut tx, 999, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+    assert_eq!(tx.len(), 0);
+    local.tick(&mut tx, 1, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+    assert!(tx.len() > 0);
+
+    //Not acked or surfaced, only recorded
+    remote.tick(&mut vec!(), 250, |_,_,_| {}, |_,_| {}, |_| {}).unwrap();
+    assert_eq!(remote.last_heard(local_addr), None);
+
+    let mut ack = vec!();
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut ack),
+        |_,_| assert!(false),
+        |_| assert!(false),
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(ack.len(), 0);
+    assert_eq!(remote.last_heard(local_addr), Some(250));
+    assert_eq!(local.pending_packets(), 0);
+}
+
+#[test]
+fn test_ping() {
+    use std::sync::{Arc, Mutex};
+
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    let pongs = Arc::new(Mutex::new(vec!()));
+    let handler_pongs = pongs.clone();
+    local.set_pong_handler(move |prn, rtt| handler_pongs.lock().unwrap().push((prn, rtt)));
+
+    let recv = |node: &mut Node, rx: &[u8], tx: &mut Vec<u8>| {
+        node.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(rx), tx),
+            |_,_| assert!(false),
+            |_| assert!(false),
+            |_| assert!(false),
+            |_,_,_| assert!(false)).unwrap();
+    };
+
+    //Replies are off by default
+    let mut ping = vec!();
+    local.ping(remote_addr, &mut ping).unwrap();
+    let mut pong = vec!();
+    recv(&mut remote, &ping, &mut pong);
+    assert_eq!(pong.len(), 0);
+
+    remote.set_ping_reply(true);
+
+    let mut ping = vec!();
+    let prn = local.ping(remote_addr, &mut ping).unwrap();
+    assert_eq!(local.pending_packets(), 0);
+
+    local.tick(&mut vec!(), 120, |_,_,_| {}, |_,_| {}, |_| {}).unwrap();
+
+    let mut pong = vec!();
+    recv(&mut remote, &ping, &mut pong);
+    assert!(pong.len() > 0);
+
+    recv(&mut local, &pong, &mut vec!());
+    assert_eq!(*pongs.lock().unwrap(), vec!((prn, 120)));
+
+    //Only answered once
+    recv(&mut local, &pong, &mut vec!());
+    assert_eq!(pongs.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn test_ack_rtt() {
+    use std::sync::{Arc, Mutex};
+
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    let acks = Arc::new(Mutex::new(vec!()));
+    let handler_acks = acks.clone();
+    local.set_ack_handler(move |prn, rtt| handler_acks.lock().unwrap().push((prn, rtt)));
+
+    let mut tx = vec!();
+    let prn = local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+
+    //Ack takes a retry to get through
+    local.tick(&mut vec!(), 200, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+    local.tick(&mut vec!(), tx_queue::RETRY_DELAY_MS, |_,_,_| {}, |_,_| assert!(false), |_| {}).unwrap();
+
+    let mut ack = vec!();
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut ack),
+        |_,_| {},
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+
+    local.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&ack), &mut vec!()),
+        |_,_| {},
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(*acks.lock().unwrap(), vec!((prn, 200 + tx_queue::RETRY_DELAY_MS as u64)));
+
+    //Repeated acks don't report again
+    local.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&ack), &mut vec!()),
+        |_,_| {},
+        |_| {},
+        |_| {},
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(acks.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn test_beacon() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+    local.enable_beacon(1000, b"KI7EST".to_vec());
+
+    let mut tx = vec!();
+    local.tick(&mut tx, 999, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+    assert_eq!(tx.len(), 0);
+    local.tick(&mut tx, 1, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+    assert!(tx.len() > 0);
+    assert_eq!(local.pending_packets(), 0);
+
+    //Everyone hears it as a broadcast from us and nobody acks it
+    let mut received = vec!();
+    let mut ack = vec!();
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut ack),
+        |header,data| {
+            assert!(frame::is_keepalive(header));
+            assert_eq!(routing::get_source(&header.address_route), local_addr);
+            received.push(data.to_vec());
+        },
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(received, vec!(b"KI7EST".to_vec()));
+    assert_eq!(ack.len(), 0);
+
+    //Real traffic restarts the interval
+    let mut tx = vec!();
+    local.tick(&mut tx, 500, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+    local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+
+    let mut ack = vec!();
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut ack),
+        |_,_| {},
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    local.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&ack), &mut vec!()),
+        |_,_| {},
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(local.pending_packets(), 0);
+
+    //Resetting the byte count has nothing to do with when we last identified
+    local.reset_bytes_transmitted();
+
+    let sent = tx.len();
+    local.tick(&mut tx, 999, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+    assert_eq!(tx.len(), sent);
+    local.tick(&mut tx, 1, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+    assert!(tx.len() > sent);
+
+    local.disable_beacon();
+    let sent = tx.len();
+    local.tick(&mut tx, 5000, |_,_,_| assert!(false), |_,_| assert!(false), |_| {}).unwrap();
+    assert_eq!(tx.len(), sent);
+}
+
+#[test]
+fn test_heard_stations() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+    let other_addr = address::encode(['K', 'I', '7', 'A', 'B', 'C', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+    assert_eq!(remote.heard_stations(), vec!());
+
+    let mut hear = |remote: &mut Node, src: u32, elapsed_ms: usize| {
+        let mut tx = vec!();
+        local.send_as(src, &[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+        remote.tick(&mut vec!(), elapsed_ms, |_,_,_| {}, |_,_| {}, |_| {}).unwrap();
+        remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut vec!()),
+            |_,_| {},
+            |_| {},
+            |_| assert!(false),
+            |_,_,_| assert!(false)).unwrap();
+    };
+
+    hear(&mut remote, local_addr, 10);
+    hear(&mut remote, other_addr, 10);
+    hear(&mut remote, local_addr, 10);
+    hear(&mut remote, 1, 10);
+    hear(&mut remote, local_addr, 10);
+
+    let heard = remote.heard_stations();
+    assert_eq!(heard.iter().map(|station| (station.addr, station.last_heard, station.count)).collect::<Vec<_>>(),
+        vec!((local_addr, 50, 3), (1, 40, 1), (other_addr, 20, 1)));
+
+    //Oldest are forgotten once we've heard too many
+    for addr in 2..MAX_HEARD_STATIONS as u32 + 1 {
+        hear(&mut remote, addr, 1);
+    }
+
+    let heard = remote.heard_stations();
+    assert_eq!(heard.len(), MAX_HEARD_STATIONS);
+    assert!(heard.iter().all(|station| station.addr != other_addr));
+    assert_eq!(remote.last_heard(other_addr), None);
+    assert_eq!(remote.last_heard(local_addr), Some(50));
+
+    //A coalesced ack for several frames is still only one frame heard
+    remote.set_coalesce_acks(true);
+    let mut tx = vec!();
+    local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    local.send_slice(&[4, 5, 6], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    let pending = local.pending_packets();
+
+    let mut ack = vec!();
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut ack),
+        |_,_| {},
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+
+    let mut decoded = vec!();
+    let kiss_frame = kiss::decode(ack.iter().cloned(), &mut decoded).unwrap();
+    let (header, _, _) = frame::parse(&decoded[..kiss_frame.payload_size]).unwrap();
+    assert!(frame::is_multi_ack(&header));
+
+    local.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&ack), &mut vec!()),
+        |_,_| {},
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+
+    assert_eq!(local.pending_packets(), pending - 2);
+    assert_eq!(local.heard_stations().iter().map(|station| (station.addr, station.count)).collect::<Vec<_>>(),
+        vec!((remote_addr, 1)));
+}
+
+#[test]
+fn test_link_timeout() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+    local.set_keepalive(Some(100));
+
+    let mut tx = vec!();
+    local.tick(&mut tx, 100, |_,_,_| {}, |_,_| {}, |_| {}).unwrap();
+
+    let hear = |remote: &mut Node, tx: &[u8]| {
+        remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(tx), &mut vec!()),
+            |_,_| {},
+            |_| {},
+            |_| {},
+            |_,_,_| assert!(false)).unwrap();
+    };
+    hear(&mut remote, &tx);
+
+    let mut timeouts = vec!();
+    remote.tick(&mut vec!(), 1000, |_,_,_| {}, |_,_| {}, |_| {}).unwrap();
+    remote.check_links(1000, |addr| timeouts.push(addr));
+    assert_eq!(timeouts, vec!());
+
+    remote.tick(&mut vec!(), 1, |_,_,_| {}, |_,_| {}, |_| {}).unwrap();
+    remote.check_links(1000, |addr| timeouts.push(addr));
+    assert_eq!(timeouts, vec!(local_addr));
+
+    //Only reported once
+    remote.tick(&mut vec!(), 1000, |_,_,_| {}, |_,_| {}, |_| {}).unwrap();
+    remote.check_links(1000, |addr| timeouts.push(addr));
+    assert_eq!(timeouts.len(), 1);
+
+    //Until we hear it again
+    hear(&mut remote, &tx);
+    assert_eq!(remote.last_heard(local_addr), Some(2001));
+    remote.tick(&mut vec!(), 1001, |_,_,_| {}, |_,_| {}, |_| {}).unwrap();
+    remote.check_links(1000, |addr| timeouts.push(addr));
+    assert_eq!(timeouts, vec!(local_addr, local_addr));
+}
+
+#[test]
+fn test_duplex_pair() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    let (mut local_io, mut remote_io) = util::duplex();
+
+    local.send((0..5).map(|x| x as u8), [remote_addr].iter().cloned(), &mut local_io).unwrap();
+
+    let mut recv_count = 0;
+    let mut ack_count = 0;
+
+    for _ in 0..4 {
+        remote.recv(&mut remote_io,
+            |_,data| {
+                recv_count += 1;
+                assert!((0..5).eq(data.iter().cloned()));
+            },
+            |_| {},
+            |_| assert!(false),
+            |_,_,_| assert!(false)).unwrap();
+
+        local.recv(&mut local_io,
+            |_,data| {
+                assert_eq!(data.len(), 0);
+                ack_count += 1;
+            },
+            |_| {},
+            |_| assert!(false),
+            |_,_,_| assert!(false)).unwrap();
+
+        local.tick(&mut local_io, 0, |_,_,_| assert!(false), |_,_| assert!(false), |_| assert!(false)).unwrap();
+    }
+
+    assert_eq!(recv_count, 1);
+    assert_eq!(ack_count, 1);
+    assert_eq!(local.tx_queue.pending_packets(), 0);
+}
+
+#[cfg(test)]
+fn gen_callsign(idx: usize) -> [char; 7] {
+    ['T', 'E', 'S', 'T', address::symbol_to_character((idx / 10) as u8), address::symbol_to_character((idx % 10) as u8), '0']
+}
+
+#[test]
+fn test_route() {
+    const CALL_COUNT: usize = 16;
+
+    let route = (0..CALL_COUNT-1)
+        .map(|i| gen_callsign(i))
+        .map(|cs| address::encode(cs).unwrap())
+        .collect::<Vec<_>>();
+
+    let local = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+
+    use std::iter;
+    let mut nodes = iter::once(local).chain(route.iter().cloned())
+        .map(|addr| new(addr))
+        .collect::<Vec<_>>();
+
+    let mut tx_frame = vec!();
+    let mut rx_frame;
+
+    let mut obs = [0; CALL_COUNT];
+    let mut recv = [0; CALL_COUNT];
+
+    //Send initial packet
+    let data = (0..128).map(|x| x as u8);
+    nodes[0].send(data, route.iter().cloned(), &mut tx_frame).unwrap();
+
+    rx_frame = tx_frame.clone();
+    tx_frame.drain(..);
+
+    for _ in 0..CALL_COUNT {
+        for (i,node) in nodes.iter_mut().enumerate() {
+            node.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&rx_frame), &mut tx_frame),
+                |_,data| {
+                    recv[i] += 1;
+                    assert!((0..128).eq(data.iter().cloned()));
+                },
+                |observed| {
+                    let data = observed.payload();
+                    if data.len() > 0 {
+                        obs[i] += 1;
+                        assert!((0..128).eq(data.iter().cloned()));
+                    }
+                },
+                |_| {},
+                |_,_,_| assert!(false)).unwrap();
+        }
+
+        //Swap TX and RX
+        rx_frame = tx_frame.clone();
+        tx_frame.drain(..);
+    }
+
+    for i in 1..CALL_COUNT {
+        assert_eq!(obs[i], 15);
+        assert_eq!(nodes[i].tx_queue.pending_packets(), 0);
+        assert_eq!(nodes[i].recv_buffer.len(), 0);
+    }
+
+    for i in 1..CALL_COUNT-1 {
+        assert_eq!(recv[i], 0);
+    }
+
+    assert_eq!(recv[CALL_COUNT-1], 1);
+}
+
+#[test]
+fn test_broadcast_route() {
+    const CALL_COUNT: usize = 16;
+
+    let route = (0..CALL_COUNT-1)
+        .map(|i| (i, gen_callsign(i)))
+        .map(|(i, cs)| {
+            if i == 1 {
+                routing::BROADCAST_ADDRESS
+            } else {
+                address::encode(cs).unwrap()
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let node_addr = (0..CALL_COUNT-1)
+        .map(|i| gen_callsign(i))
+        .map(|cs| address::encode(cs).unwrap());
+
+    let local = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+
+    use std::iter;
+    let mut nodes = iter::once(local).chain(node_addr)
+        .map(|addr| new(addr))
+        .collect::<Vec<_>>();
+
+    let mut tx_frame = vec!();
+    let mut rx_frame;
+
+    let mut obs = [0; CALL_COUNT];
+    let mut recv = [0; CALL_COUNT];
+
+    //Send initial packet
+    let data = (0..128).map(|x| x as u8);
+    nodes[0].send(data, route.iter().cloned(), &mut tx_frame).unwrap();
+
+    rx_frame = tx_frame.clone();
+    tx_frame.drain(..);
+
+    for _ in 0..CALL_COUNT {
+        for (i,node) in nodes.iter_mut().enumerate() {
+            node.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&rx_frame), &mut tx_frame),
+                |_,data| {
+                    if data.len() > 0 {
+                        recv[i] += 1;
+                        assert!((0..128).eq(data.iter().cloned()));
+                    }
+                },
+                |observed| {
+                    let data = observed.payload();
+                    if data.len() > 0 {
+                        obs[i] += 1;
+                        assert!((0..128).eq(data.iter().cloned()));
+                    }
+                },
+                |_| {},
+                |_,_,_| assert!(false)).unwrap();
+        }
+
+        //Swap TX and RX
+        rx_frame = tx_frame.clone();
+        tx_frame.drain(..);
+    }
+
+    for i in 1..CALL_COUNT {
+        assert_eq!(obs[i], 210);
+        assert_eq!(nodes[i].tx_queue.pending_packets(), 0);
+        assert_eq!(nodes[i].recv_buffer.len(), 0);
+    }
+
+    for i in 1..CALL_COUNT-1 {
+        assert_eq!(recv[i], 0);
+    }
+
+    assert_eq!(recv[CALL_COUNT-1], 1);
+}
+
+#[test]
+fn test_split_path() {
+    fn get_split(prn: &mut prn_id::PRN) -> Vec<u8> {
+        let mut packet = vec!();
+        let callsign = prn.current;
+        let header = frame::new_header(prn, [callsign, routing::ADDRESS_SEPARATOR, callsign, callsign].into_iter().cloned()).unwrap();
+        let data = (0..32).map(|x| x as u8).collect::<Vec<_>>();
+        frame::to_bytes(&mut packet, &header, Some(&data)).unwrap();
+        let mut kiss = vec!();
+        kiss::encode(&mut io::Cursor::new(packet), &mut kiss, 0).unwrap();
+
+        kiss
+    }
+
+    let mut prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
+
+    let mut left_packet = get_split(&mut prn);
+    let right_packet = left_packet.clone();
+
+    left_packet.extend_from_slice(&right_packet);
+
+    let mut node = new(prn.callsign);
+
+    let mut rx_count = 0;
+    let mut obs_count = 0;
+    let mut dup_count = 0;
+
+    let mut tx = vec!();
+    node.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&left_packet), &mut tx),
+        |_, payload| {
+            if payload.len() > 0 {
+                rx_count += 1;
+            }
+        },
+        |observed| {
+            if observed.payload().len() > 0 {
+                obs_count += 1;
+            }
+        },
+        |_| {
+            dup_count += 1;
+        },
+        |_,_,_| assert!(false)).unwrap();
+    
+    assert_eq!(rx_count, 1);
+    assert_eq!(obs_count, 2);
+    assert_eq!(dup_count, 1);
+}
+
+#[test]
+fn test_recv_bad_data() {
+    let prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
+
+    for i in 0..frame::MTU+2 {
+        let mut node = new(prn.callsign);
+
+        let bad_data = (0..i).map(|x| x as u8).collect::<Vec<_>>();
+        let mut bad_kiss = vec!();
+        kiss::encode(&mut io::Cursor::new(bad_data), &mut bad_kiss, 0).unwrap();
+
+        let mut error_count = 0;
+        node.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(bad_kiss), &mut vec!()),
+            |_,_| assert!(false),
+            |_| assert!(false),
+            |_| assert!(false),
+            |_,_,_| error_count += 1).unwrap();
+
+        assert_eq!(error_count, 1);
+
+        let mut packet = vec!();
+        use std::iter;
+        node.send((0..5).map(|x| x as u8), iter::once(prn.callsign), &mut util::new_read_write_dispatch(&mut io::Cursor::new(vec!()), &mut packet)).unwrap();
+
+        node.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(packet), &mut vec!()),
+            |_,data| {
+                for i in 0..5 {
+                    assert_eq!(data[i], i as u8);
+                }
+            },
+            |_| {},
+            |_| {},
+            |_,_,_| assert!(false)).unwrap();
+    }
+}
+
+#[test]
+fn test_rx_ports() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+    remote.set_rx_ports(&[1]);
+
+    //Send the same payload on port 0 and port 1
+    let mut tx = vec!();
+    local.send((0..5).map(|x| x as u8), [remote_addr].iter().cloned(), &mut tx).unwrap();
+    local.set_tx_port(1);
+    local.send((0..5).map(|x| x as u8), [remote_addr].iter().cloned(), &mut tx).unwrap();
+
+    let mut recv_count = 0;
+    let mut ack = vec!();
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut ack),
+        |_,_| {
+            recv_count += 1;
+        },
+        |_| {},
+        |_| {},
+        |_,_,_| assert!(false)).unwrap();
+
+    assert_eq!(recv_count, 1);
+    assert_eq!(remote.recv_buffer.len(), 0);
+    assert!(remote.accepts_port(1));
+    assert!(!remote.accepts_port(0));
+    assert!(!remote.accepts_port(17));
+
+    //Ack should go back out on the port we heard the frame
+    let mut decoded = vec!();
+    match kiss::decode(ack.iter().cloned(), &mut decoded) {
+        Some(result) => {
+            assert_eq!(result.port, 1);
+            assert_eq!(result.bytes_read, ack.len());
+        },
+        None => assert!(false)
+    }
+
+    //Ports KISS can't carry are ignored rather than aliased onto a low port
+    remote.set_rx_ports(&[16, 2]);
+    assert!(!remote.accepts_port(0));
+    assert!(remote.accepts_port(2));
+    assert!(!remote.accepts_port(16));
+
+    remote.set_rx_ports(&[]);
+    assert!(remote.accepts_port(0));
+    assert!(remote.accepts_port(200));
+}
+
+#[test]
+fn test_send_to_path() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let mut local = new(local_addr);
+    let mut tx = vec!();
+
+    match local.send_to_path(&[1, 2, 3], &[], &mut tx) {
+        Err(SendError::Frame(frame::EncodeError::EmptyRoute)) => (),
+        _ => assert!(false)
+    }
+
+    let hops = (0..routing::MAX_HOPS+1).map(|i| address::encode(gen_callsign(i)).unwrap()).collect::<Vec<_>>();
+    match local.send_to_path(&[1, 2, 3], &hops, &mut tx) {
+        Err(SendError::Frame(frame::EncodeError::AddressTooLong)) => (),
+        _ => assert!(false)
+    }
+
+    match local.send_to_path(&[1, 2, 3], &[hops[0], routing::ADDRESS_SEPARATOR, hops[1]], &mut tx) {
+        Err(SendError::Frame(frame::EncodeError::UnexpectedSeparator)) => (),
+        _ => assert!(false)
+    }
+    assert_eq!(tx.len(), 0);
+    assert_eq!(local.pending_packets(), 0);
+
+    //Longest path lands on the last hop with us as the return address
+    local.send_to_path(&[1, 2, 3], &hops[..routing::MAX_HOPS], &mut tx).unwrap();
+
+    let mut decoded = vec!();
+    let kiss_frame = kiss::decode(tx.iter().cloned(), &mut decoded).unwrap();
+    let mut payload = [0; frame::MTU];
+    let (header, _) = frame::from_bytes(&mut io::Cursor::new(&decoded), &mut payload, kiss_frame.payload_size).unwrap();
+
+    assert_eq!(&header.address_route[..routing::MAX_HOPS], &hops[..routing::MAX_HOPS]);
+    assert_eq!(header.address_route[routing::MAX_HOPS], routing::ADDRESS_SEPARATOR);
+    assert_eq!(routing::get_source(&header.address_route), local_addr);
+}
+
+#[cfg(test)]
+struct FailWriter;
+
+#[cfg(test)]
+impl io::Write for FailWriter {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "Write failed"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_send_write_but_queued() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+
+    let prn = match local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut FailWriter) {
+        Err(SendError::WriteButQueued(prn, e)) => {
+            assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+            prn
+        },
+        _ => {
+            assert!(false);
+            return
+        }
+    };
+
+    assert_eq!(local.bytes_transmitted(), 0);
+    assert_eq!(local.pending().len(), 1);
+    assert_eq!(local.pending()[0].prn, prn);
+
+    //Failures that happen before queuing come back as is since nothing will retry them
+    local.set_max_in_flight(1);
+    match local.send_slice(&[4, 5, 6], [remote_addr].iter().cloned(), &mut FailWriter) {
+        Err(SendError::Enqueue(tx_queue::QueueError::TooManyInFlight)) => (),
+        _ => assert!(false)
+    }
+    assert_eq!(local.pending().len(), 1);
+
+    //Once the writer recovers the retry goes out
+    let mut tx = vec!();
+    let mut retries = 0;
+    local.tick(&mut tx, tx_queue::RETRY_DELAY_MS,
+        |header,_,_| {
+            assert_eq!(header.prn, prn);
+            retries += 1;
+        },
+        |_,_| assert!(false),
+        |_| {}).unwrap();
+
+    assert_eq!(retries, 1);
+    assert!(tx.len() > 0);
+}
+
+#[test]
+fn test_tick_clock_on_error() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut vec!()).unwrap();
+
+    //Retry fails to write but the clock still moves
+    assert!(local.tick(&mut FailWriter, tx_queue::RETRY_DELAY_MS, |_,_,_| {}, |_,_| {}, |_| {}).is_err());
+    assert_eq!(local.clock_ms(), tx_queue::RETRY_DELAY_MS as u64);
+}
+
+#[cfg(test)]
+struct FlushCounter {
+    data: Vec<u8>,
+    flushed: usize,
+    flush_count: usize
+}
+
+#[cfg(test)]
+impl io::Write for FlushCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flushed = self.data.len();
+        self.flush_count += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_flush() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    let mut tx = FlushCounter { data: vec!(), flushed: 0, flush_count: 0 };
+    local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    assert_eq!(tx.flush_count, 1);
+    assert_eq!(tx.flushed, tx.data.len());
+
+    //Retries get flushed too
+    local.tick(&mut tx, tx_queue::RETRY_DELAY_MS, |_,_,_| {}, |_,_| {}, |_| {}).unwrap();
+    assert_eq!(tx.flush_count, 2);
+    assert_eq!(tx.flushed, tx.data.len());
+
+    //As do acks
+    let mut ack = FlushCounter { data: vec!(), flushed: 0, flush_count: 0 };
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx.data), &mut ack),
+        |_,_| {},
+        |_| {},
+        |_| {},
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(ack.flush_count, 2);
+    assert_eq!(ack.flushed, ack.data.len());
+
+    //Explicit flushes always reach the writer, even with nothing new written
+    local.flush(&mut tx).unwrap();
+    assert_eq!(tx.flush_count, 3);
+}
+
+#[test]
+fn test_pump() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let (mut local_io, mut remote_io) = util::duplex();
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    let prn = local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut local_io).unwrap();
+
+    let mut events = vec!();
+    remote.pump(&mut remote_io, 0, &mut events).unwrap();
+    assert_eq!(events.len(), 2);
+    match events[0] {
+        Event::Recv(ref header, ref data) => {
+            assert_eq!(header.prn, prn);
+            assert_eq!(data.as_slice(), &[1, 2, 3]);
+        },
+        _ => assert!(false)
+    }
+    match events[1] {
+        Event::Observe(ref header, _) => assert_eq!(header.prn, prn),
+        _ => assert!(false)
+    }
+
+    events.clear();
+    local.pump(&mut local_io, 0, &mut events).unwrap();
+    assert_eq!(events.len(), 2);
+    match events[0] {
+        Event::Ack(ref header) => assert_eq!(header.prn, prn),
+        _ => assert!(false)
+    }
+    assert_eq!(local.pending_packets(), 0);
+
+    //Unacked packet shows up as retries and finally expires
+    let prn = local.send_slice(&[4, 5, 6], [remote_addr].iter().cloned(), &mut local_io).unwrap();
+    events.clear();
+    for _ in 0..tx_queue::RETRY_COUNT+1 {
+        local.pump(&mut local_io, tx_queue::RETRY_DELAY_MS << tx_queue::RETRY_COUNT, &mut events).unwrap();
+    }
+
+    let retries = events.iter().filter(|event| match **event {
+        Event::Retry(ref header, _) => header.prn == prn,
+        _ => false
+    }).count();
+    assert_eq!(retries, tx_queue::RETRY_COUNT);
+    match events.last() {
+        Some(&Event::Expire(ref header, ref data)) => {
+            assert_eq!(header.prn, prn);
+            assert_eq!(data.as_slice(), &[4, 5, 6]);
+        },
+        _ => assert!(false)
+    }
+}
+
+#[test]
+fn test_recv_overflow() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    //100KB without a single FEND
+    let babble = vec!(0x55; 100 * 1024);
+    let mut overflow_count = 0;
+
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&babble), &mut vec!()),
+        |_,_| assert!(false),
+        |_| assert!(false),
+        |_| assert!(false),
+        |prn,e,_| {
+            assert!(prn.is_none());
+            match *e {
+                RecvError::Overflow => overflow_count += 1,
+                _ => assert!(false)
+            }
+        }).unwrap();
+
+    assert!(overflow_count > 0);
+    assert!(remote.recv_buffer.len() <= DEFAULT_MAX_RECV_BUFFER);
+    assert!(remote.recv_buffer.capacity() <= 2 * DEFAULT_MAX_RECV_BUFFER);
+
+    //We should still pick up frames once the peer starts making sense
+    let mut tx = vec!();
+    local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+
+    let mut recv_count = 0;
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut vec!()),
+        |_,data| {
+            recv_count += 1;
+            assert_eq!(data, &[1, 2, 3]);
+        },
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(recv_count, 1);
+}
+
+#[test]
+fn test_send_raw() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+    let origin_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '1']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    //Frame from another station that we're replaying
+    let mut origin_prn = prn_id::new(origin_addr);
+    let header = frame::new_header(&mut origin_prn, [remote_addr, routing::ADDRESS_SEPARATOR, origin_addr].iter().cloned()).unwrap();
+
+    let mut tx = vec!();
+    local.send_raw(header, &[1, 2, 3], &mut tx).unwrap();
+    assert_eq!(local.pending_packets(), 1);
+
+    let mut recv_count = 0;
+    let mut ack = vec!();
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut ack),
+        |recv_header,data| {
+            recv_count += 1;
+            assert_eq!(*recv_header, header);
+            assert_eq!(data, &[1, 2, 3]);
+        },
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(recv_count, 1);
+
+    //Ack goes back to the original source rather than us
+    let mut decoded = vec!();
+    let kiss_frame = kiss::decode(ack.iter().cloned(), &mut decoded).unwrap();
+    let mut payload = [0; frame::MTU];
+    let (ack_header, _) = frame::from_bytes(&mut io::Cursor::new(&decoded), &mut payload, kiss_frame.payload_size).unwrap();
+    assert_eq!(ack_header.prn, header.prn);
+    assert_eq!(ack_header.address_route, routing::gen_route(&[origin_addr, routing::ADDRESS_SEPARATOR, remote_addr]));
+}
+
+#[test]
+fn test_send_as() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+    let client_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '1']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    let mut tx = vec!();
+    let prn = local.send_as(client_addr, &[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+
+    //Ack goes to the client, so it's not ours to retry
+    assert_eq!(local.pending_packets(), 0);
+
+    let mut ack = vec!();
+    let mut recv_count = 0;
+    remote.recv_with_info(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut ack),
+        |header,data,info| {
+            recv_count += 1;
+            assert_eq!(header.prn, prn);
+            assert_eq!(header.address_route, routing::gen_route(&[remote_addr, routing::ADDRESS_SEPARATOR, client_addr]));
+            assert_eq!(info.src, client_addr);
+            assert_eq!(data, &[1, 2, 3]);
+        },
+        |_,_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(recv_count, 1);
+
+    //Which we only overhear
+    local.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&ack), &mut vec!()),
+        |_,_| assert!(false),
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+
+    let mut retried = false;
+    local.tick(&mut vec!(), tx_queue::RETRY_DELAY_MS << tx_queue::RETRY_COUNT, |_,_,_| retried = true, |_,_| assert!(false), |_| {}).unwrap();
+    assert!(!retried);
+}
+
+#[test]
+fn test_error_display() {
+    use std::error::Error;
+
+    let io_err = || io::Error::new(io::ErrorKind::Other, "oops");
+
+    assert_eq!(format!("{}", NodeError::BadCallsign), "Callsign is not valid");
+
+    assert_eq!(format!("{}", SendError::Frame(frame::EncodeError::EmptyRoute)), "Unable to build frame: Route has no hops");
+    assert_eq!(format!("{}", SendError::Enqueue(tx_queue::QueueError::Discarded)), "Unable to queue frame: Frame was discarded due to congestion control");
+    assert_eq!(format!("{}", SendError::Write(frame::WriteError::IO(io_err()))), "Unable to write frame: IO error writing frame: oops");
+    assert_eq!(format!("{}", SendError::Io(io_err())), "IO error sending frame: oops");
+    assert_eq!(format!("{}", SendError::Truncated), "Packet is larger than the max payload");
+    assert_eq!(format!("{}", SendError::Size(util::SizeError { required: 10 })), "Unable to encode frame: Output buffer too small, 10 bytes required");
+    assert_eq!(format!("{}", SendError::WriteButQueued(5, io_err())), "Frame 5 was queued but failed to write: oops");
+
+    assert_eq!(format!("{}", RecvError::Frame(frame::ReadError::Truncated)), "Unable to decode frame: Frame was truncated");
+    assert_eq!(format!("{}", RecvError::Ack(frame::WriteError::IO(io_err()))), "Unable to write ack: IO error writing frame: oops");
+    assert_eq!(format!("{}", RecvError::Io(io_err())), "IO error receiving: oops");
+    assert_eq!(format!("{}", RecvError::Routing(routing::ParseError::BadFormat)), "Unable to route frame: Route has a bad format");
+    assert_eq!(format!("{}", RecvError::Send(SendError::Truncated)), "Unable to send while receiving: Packet is larger than the max payload");
+    assert_eq!(format!("{}", RecvError::Overflow), "Receive buffer overflowed without a complete frame");
+
+    //Wrapped errors chain through source
+    let err = RecvError::Send(SendError::Io(io_err()));
+    let send_err = err.source().unwrap();
+    assert_eq!(format!("{}", send_err.source().unwrap()), "oops");
+    assert!(RecvError::Overflow.source().is_none());
+
+    let boxed: Box<Error> = Box::new(SendError::Truncated);
+    assert_eq!(boxed.to_string(), "Packet is larger than the max payload");
+}
+
+#[test]
+fn test_transport_error() {
+    let io_err = || io::Error::new(io::ErrorKind::BrokenPipe, "oops");
+
+    assert!(is_transport_error(&RecvError::Io(io_err())));
+    assert!(is_transport_error(&RecvError::Ack(frame::WriteError::IO(io_err()))));
+    assert!(is_transport_error(&RecvError::Send(SendError::Io(io_err()))));
+    assert!(is_transport_error(&RecvError::Send(SendError::Write(frame::WriteError::IO(io_err())))));
+    assert!(is_transport_error(&RecvError::Send(SendError::WriteButQueued(5, io_err()))));
+
+    //Failing to build or queue a reply only drops that frame
+    assert!(!is_transport_error(&RecvError::Ack(frame::WriteError::Size(util::SizeError { required: 10 }))));
+    assert!(!is_transport_error(&RecvError::Send(SendError::Frame(frame::EncodeError::AddressTooLong))));
+    assert!(!is_transport_error(&RecvError::Send(SendError::Enqueue(tx_queue::QueueError::Discarded))));
+    assert!(!is_transport_error(&RecvError::Send(SendError::Truncated)));
+    assert!(!is_transport_error(&RecvError::Send(SendError::Size(util::SizeError { required: 10 }))));
+    assert!(!is_transport_error(&RecvError::Frame(frame::ReadError::CRCFailure)));
+    assert!(!is_transport_error(&RecvError::Routing(routing::ParseError::BadFormat)));
+}
+
+#[test]
+fn test_send_multicast() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+    let other_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '1']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+    let mut other = new(other_addr);
+
+    let mut tx = vec!();
+    let mut result = local.send_multicast(&[1, 2, 3], &[remote_addr, other_addr, remote_addr], &mut tx).unwrap();
+    assert_eq!(result.destinations.len(), 2);
+    assert_eq!(local.pending_packets(), 2);
+    assert_eq!(result.state(remote_addr), Some(DeliveryState::Pending));
+    assert!(!result.is_done());
+
+    //Each destination only receives its own copy
+    let mut remote_ack = vec!();
+    let mut recv_count = 0;
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut remote_ack),
+        |header,data| {
+            recv_count += 1;
+            assert_eq!(header.prn, result.destinations[&remote_addr].0);
+            assert_eq!(data, &[1, 2, 3]);
+        },
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(recv_count, 1);
+
+    local.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&remote_ack), &mut vec!()),
+        |header,_| assert!(result.ack(header.prn)),
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(result.state(remote_addr), Some(DeliveryState::Acked));
+    assert_eq!(result.state(other_addr), Some(DeliveryState::Pending));
+    assert_eq!(local.pending_packets(), 1);
+    assert!(!result.is_done());
+
+    //Other never hears it so that copy expires independently
+    let other_prn = result.destinations[&other_addr].0;
+    for _ in 0..tx_queue::RETRY_COUNT+1 {
+        local.tick(&mut vec!(), tx_queue::RETRY_DELAY_MS << tx_queue::RETRY_COUNT,
+            |header,_,_| assert_eq!(header.prn, other_prn),
+            |header,_| assert!(result.expire(header.prn)),
+            |_| {}).unwrap();
+    }
+
+    assert_eq!(result.state(remote_addr), Some(DeliveryState::Acked));
+    assert_eq!(result.state(other_addr), Some(DeliveryState::Expired));
+    assert!(result.is_done());
+
+    //Had it been listening it would have received just its own copy
+    let mut other_count = 0;
+    other.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut vec!()),
+        |header,_| {
+            other_count += 1;
+            assert_eq!(header.prn, other_prn);
+        },
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(other_count, 1);
+
+    match local.send_multicast(&[1, 2, 3], &[], &mut tx) {
+        Err((ref partial, SendError::Frame(frame::EncodeError::EmptyRoute))) => assert!(partial.destinations.is_empty()),
+        _ => assert!(false)
+    }
+
+    //Copies sent before a failure are handed back so they can still be tracked
+    local.set_max_in_flight(1);
+    match local.send_multicast(&[1, 2, 3], &[remote_addr, other_addr], &mut tx) {
+        Err((partial, SendError::Enqueue(tx_queue::QueueError::TooManyInFlight))) => {
+            assert_eq!(partial.destinations.len(), 1);
+            assert_eq!(partial.state(remote_addr), Some(DeliveryState::Pending));
+            assert_eq!(local.pending_packets(), 1);
+        },
+        _ => assert!(false)
+    }
+}
+
+#[test]
+fn test_feed() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    let mut tx = vec!();
+    let prn = local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+
+    //First half is buffered until the rest shows up
+    let mut ack = vec!();
+    let mut received = vec!();
+    let split = tx.len() / 2;
+
+    remote.feed(&tx[..split], &mut ack,
+        |_,_| assert!(false),
+        |_| assert!(false),
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(ack.len(), 0);
+
+    remote.feed(&tx[split..], &mut ack,
+        |header,data| received.push((header.prn, data.to_vec())),
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(received, vec!((prn, vec!(1, 2, 3))));
+
+    //Ack went out on the drain we handed it
+    local.feed(&ack, &mut vec!(),
+        |_,_| {},
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(local.pending_packets(), 0);
+}
+
+#[test]
+fn test_recv_split_escape() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+
+    //Run of escapes around the end of the first 256 byte read
+    let mut payload = vec!(0x55; 300);
+    for idx in 200..260 {
+        payload[idx] = if idx % 2 == 0 { kiss::FEND } else { kiss::FESC };
+    }
+
+    let mut tx = vec!();
+    local.send_slice(&payload, [remote_addr].iter().cloned(), &mut tx).unwrap();
+
+    //Line noise ahead of the frame is dropped, use it to line a FESC up as the last byte of the read
+    if tx[255] != kiss::FESC {
+        tx.insert(0, 0x00);
+    }
+    assert_eq!(tx[255], kiss::FESC);
+
+    let mut remote = new(remote_addr);
+    let mut received = vec!();
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut vec!()),
+        |_,data| received.push(data.to_vec()),
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(received, vec!(payload.clone()));
+
+    //Split at every point, including right after each FESC, across separate recv calls
+    for split in 1..tx.len() {
+        let mut remote = new(remote_addr);
+        let mut received = vec!();
+
+        for chunk in [&tx[..split], &tx[split..]].iter() {
+            remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(chunk), &mut vec!()),
+                |_,data| received.push(data.to_vec()),
+                |_| {},
+                |_| assert!(false),
+                |_,_,_| assert!(false)).unwrap();
+        }
+
+        assert_eq!(received, vec!(payload.clone()));
+        assert_eq!(remote.recv_buffer.len(), 0);
+    }
+}
+
+#[test]
+fn test_stop_flag() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    remote.set_stop_flag(stop.clone());
+
+    let mut tx = vec!();
+    let first = local.send_slice(&[1], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    let second = local.send_slice(&[2], [remote_addr].iter().cloned(), &mut tx).unwrap();
+
+    //Stop as soon as the first frame arrives
+    let mut received = vec!();
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut vec!()),
+        |header,_| {
+            received.push(header.prn);
+            stop.store(true, Ordering::SeqCst);
+        },
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+
+    assert_eq!(received, vec!(first));
+    assert!(remote.recv_buffer.len() > 0);
+
+    //Nothing happens while it's set
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&[]), &mut vec!()),
+        |_,_| assert!(false),
+        |_| assert!(false),
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+
+    //The rest is picked up once it's cleared, even without new bytes
+    stop.store(false, Ordering::SeqCst);
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&[]), &mut vec!()),
+        |header,_| received.push(header.prn),
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+
+    assert_eq!(received, vec!(first, second));
+    assert_eq!(remote.recv_buffer.len(), 0);
+}
+
+#[test]
+fn test_max_hops() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let route = (0..5).map(|i| address::encode(gen_callsign(i)).unwrap()).collect::<Vec<_>>();
+
+    let mut local = new(local_addr);
+    let mut relay = new(route[0]);
+    assert_eq!(relay.max_hops(), routing::MAX_LENGTH);
+    relay.set_max_hops(3);
+
+    let mut tx = vec!();
+    let prn = local.send_slice(&[1, 2, 3], route.iter().cloned(), &mut tx).unwrap();
+
+    let mut forwarded = vec!();
+    let mut errors = 0;
+    relay.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut forwarded),
+        |_,_| assert!(false),
+        |_| assert!(false),
+        |_| assert!(false),
+        |err_prn,e,_| {
+            errors += 1;
+            assert_eq!(err_prn, Some(prn));
+            match *e {
+                RecvError::Routing(routing::ParseError::TooLong) => (),
+                _ => assert!(false)
+            }
+        }).unwrap();
+
+    assert_eq!(errors, 1);
+    assert_eq!(forwarded.len(), 0);
+
+    //Short enough routes still get relayed
+    let mut tx = vec!();
+    local.send_slice(&[1, 2, 3], route[..3].iter().cloned(), &mut tx).unwrap();
+    relay.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut forwarded),
+        |_,_| assert!(false),
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert!(forwarded.len() > 0);
+
+    //Sends over the limit are refused before anything is written
+    let mut tx = vec!();
+    match relay.send_slice(&[1, 2, 3], route[1..5].iter().cloned(), &mut tx) {
+        Err(SendError::Frame(frame::EncodeError::AddressTooLong)) => (),
+        _ => assert!(false)
+    }
+    assert_eq!(tx.len(), 0);
+    assert_eq!(relay.pending_packets(), 0);
+
+    //Without using up a PRN
+    let prn = relay.send_slice(&[1, 2, 3], route[1..4].iter().cloned(), &mut tx).unwrap();
+    assert_eq!(prn, prn_id::new(route[0]).next());
+}
+
+#[test]
+fn test_send_slice_frame() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+
+    let mut tx = vec!();
+    let header = local.send_slice_frame(&[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    assert_eq!(header.address_route, routing::gen_route(&[remote_addr, routing::ADDRESS_SEPARATOR, local_addr]));
+
+    //Matches what retries report
+    let mut retries = 0;
+    local.tick(&mut tx, tx_queue::RETRY_DELAY_MS,
+        |retry,_,_| {
+            retries += 1;
+            assert_eq!(*retry, header);
+        },
+        |_,_| assert!(false),
+        |_| {}).unwrap();
+    assert_eq!(retries, 1);
+
+    //PRN only form is still around
+    let prn = local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    assert!(prn != header.prn);
+}
+
+#[test]
+fn test_send_merge_duplicates() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    local.set_merge_duplicates(true);
+
+    let mut tx = vec!();
+    let prn = local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    let sent = tx.len();
+
+    //Second copy collapses into the first without going out on the wire
+    assert_eq!(local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut tx).unwrap(), prn);
+    assert_eq!(tx.len(), sent);
+    assert_eq!(local.pending_packets(), 1);
+
+    //Same for the other send paths
+    let mut out = [0; frame::MAX_PACKET_SIZE * 2];
+    assert_eq!(local.send_to_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut out).unwrap(), (prn, 0));
+
+    let raw = frame::new_header(&mut prn_id::new(remote_addr), [remote_addr, routing::ADDRESS_SEPARATOR, local_addr].iter().cloned()).unwrap();
+    assert_eq!(local.send_raw(raw, &[1, 2, 3], &mut tx).unwrap(), prn);
+    assert_eq!(tx.len(), sent);
+    assert_eq!(local.pending_packets(), 1);
+
+    //Merged sends don't use up a PRN, anything else is still sent
+    let mut expected = prn_id::new(local_addr);
+    expected.next();
+    assert_eq!(local.send_slice(&[1, 2, 4], [remote_addr].iter().cloned(), &mut tx).unwrap(), expected.next());
+    assert_eq!(local.pending_packets(), 2);
+}
+
+#[test]
+fn test_max_payload() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    assert_eq!(local.max_payload(), frame::MTU);
+
+    local.set_max_payload(frame::MTU * 2);
+    assert_eq!(local.max_payload(), frame::MTU);
+
+    local.set_max_payload(64);
+
+    let mut tx = vec!();
+    match local.send((0..100).map(|x| x as u8), [remote_addr].iter().cloned(), &mut tx) {
+        Err(SendError::Truncated) => (),
+        _ => assert!(false)
+    }
+    match local.send_slice(&[0; 100], [remote_addr].iter().cloned(), &mut tx) {
+        Err(SendError::Truncated) => (),
+        _ => assert!(false)
+    }
+    assert_eq!(tx.len(), 0);
+
+    local.send_slice(&[0; 64], [remote_addr].iter().cloned(), &mut tx).unwrap();
+
+    //Receiving isn't limited by the max payload
+    let mut remote = new(remote_addr);
+    remote.set_max_payload(64);
+    tx.drain(..);
+    local.set_max_payload(frame::MTU);
+    local.send_slice(&[0; 100], [remote_addr].iter().cloned(), &mut tx).unwrap();
+
+    let mut recv_size = 0;
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut vec!()),
+        |_,data| recv_size = data.len(),
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(recv_size, 100);
+}
+
+#[test]
+fn test_send_oversized_iter() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+
+    //Only reads one byte past what fits before rejecting
+    let mut consumed = 0;
+    let mut tx = vec!();
+    match local.send((0..1_000_000).map(|x| { consumed += 1; x as u8 }), [remote_addr].iter().cloned(), &mut tx) {
+        Err(SendError::Truncated) => (),
+        _ => assert!(false)
+    }
+
+    assert_eq!(consumed, frame::MTU + 1);
+    assert_eq!(tx.len(), 0);
+
+    //Never ends, so this only returns if we stop reading
+    let endless = ::std::iter::repeat(0).enumerate().map(|(idx, byte)| {
+        assert!(idx <= frame::MTU, "Read past MTU + 1");
+        byte
+    });
+    match local.send(endless, [remote_addr].iter().cloned(), &mut tx) {
+        Err(SendError::Truncated) => (),
+        _ => assert!(false)
+    }
+
+    //Exactly max payload still fits
+    local.send((0..frame::MTU).map(|x| x as u8), [remote_addr].iter().cloned(), &mut tx).unwrap();
+}
+
+#[test]
+fn test_observe_forwarded() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let relay_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '1']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut relay = new(relay_addr);
+    let mut remote = new(remote_addr);
+
+    let mut tx = vec!();
+    let prn = local.send_to_path(&[1, 2, 3], &[relay_addr, remote_addr], &mut tx).unwrap();
+
+    let mut relayed = vec!();
+    let mut forwarded = 0;
+    relay.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut relayed),
+        |_,_| assert!(false),
+        |observed| {
+            match *observed {
+                ObservedFrame::Forwarded { header, payload } => {
+                    forwarded += 1;
+                    assert_eq!(header.prn, prn);
+                    assert_eq!(payload, &[1, 2, 3]);
+                },
+                _ => assert!(false)
+            }
+        },
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(forwarded, 1);
+
+    let mut ack = vec!();
+    let mut data = 0;
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&relayed), &mut ack),
+        |_,_| {},
+        |observed| {
+            match *observed {
+                ObservedFrame::Data { header, payload } => {
+                    data += 1;
+                    assert_eq!(header.prn, prn);
+                    assert_eq!(payload, &[1, 2, 3]);
+                },
+                _ => assert!(false)
+            }
+        },
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(data, 1);
+
+    //Relayed ack is reported as forwarded, not as an ack we heard
+    let mut forwarded_ack = 0;
+    relay.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&ack), &mut vec!()),
+        |_,_| assert!(false),
+        |observed| {
+            match *observed {
+                ObservedFrame::Forwarded { header, payload } => {
+                    forwarded_ack += 1;
+                    assert_eq!(header.prn, prn);
+                    assert_eq!(payload.len(), 0);
+                },
+                _ => assert!(false)
+            }
+        },
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(forwarded_ack, 1);
+}
+
+#[test]
+fn test_recv_addressing() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let relay_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '1']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+    let other_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '1']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut relay = new(relay_addr);
+    let mut remote = new(remote_addr);
+    let mut other = new(other_addr);
+
+    let mut tx = vec!();
+    local.send_to_path(&[1, 2, 3], &[relay_addr, remote_addr], &mut tx).unwrap();
+
+    //Relay passes it along
+    let mut relayed = vec!();
+    let mut relay_info = vec!();
+    relay.recv_with_info(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut relayed),
+        |_,_,_| assert!(false),
+        |_,info| relay_info.push((info.addressed_to_us, info.is_relay)),
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(relay_info, vec!((false, true)));
+
+    //Destination gets it for itself
+    let mut remote_info = vec!();
+    remote.recv_with_info(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&relayed), &mut vec!()),
+        |_,_,info| assert!(info.addressed_to_us),
+        |_,info| remote_info.push((info.addressed_to_us, info.is_relay)),
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(remote_info, vec!((true, false)));
+
+    //Anyone else only overhears it
+    let mut other_info = vec!();
+    other.recv_with_info(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut vec!()),
+        |_,_,_| assert!(false),
+        |_,info| other_info.push((info.addressed_to_us, info.is_relay)),
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(other_info, vec!((false, false)));
+}
+
+#[test]
+fn test_recv_raw() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
 
-    tx_local.drain(..);
+    //Escaped bytes should come through unescaped
+    let payload = [kiss::FEND, kiss::FESC, 1, 2];
+    let header = frame::new_header(&mut local.prn, [remote_addr, routing::ADDRESS_SEPARATOR, local_addr].iter().cloned()).unwrap();
+    let mut wire = vec!();
+    frame::to_bytes(&mut wire, &header, Some(&payload)).unwrap();
 
-    let mut match_ack = false;
-    local.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx_remote), &mut tx_local),
-        |_,_| {},
-        |header,payload| {
-            if payload.len() == 0 {
-                match_ack = true;
-                assert_eq!(prn, header.prn);
-                assert_eq!(header.address_route, routing::gen_route(&[local_addr, routing::ADDRESS_SEPARATOR, remote_addr]));
-            } else {
-                assert!(false);
-            }
-        }).unwrap();
+    let mut rx = vec!();
+    kiss::encode(&mut io::Cursor::new(&wire), &mut rx, 0).unwrap();
+    rx.extend_from_slice(&rx.clone());
 
-    assert!(match_ack);
-    assert_eq!(local.tx_queue.pending_packets(), 0);
+    let mut raw = vec!();
+    remote.recv_with_info(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&rx), &mut vec!()),
+        |_,_,info| assert_eq!(info.raw, &wire[..]),
+        |_,info| raw.push(info.raw.to_vec()),
+        |_| {},
+        |_,_,_| assert!(false)).unwrap();
+
+    assert_eq!(raw, vec!(wire.clone(), wire.clone()));
+
+    //Frames that fail to parse hand their bytes to error_drain instead
+    let mut corrupt = wire.clone();
+    let last = corrupt.len() - 1;
+    corrupt[last] ^= 0xFF;
+
+    let mut rx = vec!();
+    kiss::encode(&mut io::Cursor::new(&corrupt), &mut rx, 0).unwrap();
+
+    let mut errors = vec!();
+    remote.recv_with_info(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&rx), &mut vec!()),
+        |_,_,_| assert!(false),
+        |_,_| assert!(false),
+        |_| {},
+        |_,_,raw| errors.push(raw.to_vec())).unwrap();
+
+    assert_eq!(errors, vec!(corrupt));
 }
 
-#[cfg(test)]
-fn gen_callsign(idx: usize) -> [char; 7] {
-    ['T', 'E', 'S', 'T', address::symbol_to_character((idx / 10) as u8), address::symbol_to_character((idx % 10) as u8), '0']
+#[test]
+fn test_recv_with_info() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    let mut tx = vec!();
+    local.send_slice(&[1], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    local.set_tx_port(2);
+    local.send_slice(&[2], [remote_addr].iter().cloned(), &mut tx).unwrap();
+
+    let mut recv_ports = vec!();
+    let mut observe_ports = vec!();
+    let mut ack_tx = vec!();
+    remote.recv_with_info(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&tx), &mut ack_tx),
+        |_,data,info| {
+            recv_ports.push((data[0], info.port));
+            assert_eq!(info.src, local_addr);
+        },
+        |_,info| {
+            observe_ports.push(info.port);
+        },
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+
+    assert_eq!(recv_ports, vec!((1, 0), (2, 2)));
+    assert_eq!(observe_ports, vec!(0, 2));
+
+    //Acks are from the station that acked
+    let mut ack_src = vec!();
+    local.recv_with_info(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&ack_tx), &mut vec!()),
+        |_,_,info| ack_src.push(info.src),
+        |_,_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+
+    assert_eq!(ack_src, vec!(remote_addr, remote_addr));
 }
 
 #[test]
-fn test_route() {
-    const CALL_COUNT: usize = 16;
+fn test_recv_kiss_command() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
 
-    let route = (0..CALL_COUNT-1)
-        .map(|i| gen_callsign(i))
-        .map(|cs| address::encode(cs).unwrap())
-        .collect::<Vec<_>>();
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
 
-    let local = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    //TNC echoing a command back shouldn't be parsed as a frame
+    let mut rx = vec!();
+    kiss::encode_cmd(&mut rx, kiss::CMD_TX_DELAY, 4, 0);
+    kiss::encode_cmd(&mut rx, kiss::CMD_RETURN, 0, 0);
+    local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut rx).unwrap();
 
-    use std::iter;
-    let mut nodes = iter::once(local).chain(route.iter().cloned())
-        .map(|addr| new(addr))
-        .collect::<Vec<_>>();
+    let mut recv_count = 0;
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&rx), &mut vec!()),
+        |_,data| {
+            recv_count += 1;
+            assert_eq!(data, &[1, 2, 3]);
+        },
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
 
-    let mut tx_frame = vec!();
-    let mut rx_frame;
+    assert_eq!(recv_count, 1);
+    assert_eq!(remote.recv_buffer.len(), 0);
+}
 
-    let mut obs = [0; CALL_COUNT];
-    let mut recv = [0; CALL_COUNT];
+#[test]
+fn test_tx_port_retry() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
 
-    //Send initial packet
-    let data = (0..128).map(|x| x as u8);
-    nodes[0].send(data, route.iter().cloned(), &mut tx_frame).unwrap();
+    let mut node = new(local_addr);
+    node.set_tx_port(2);
 
-    rx_frame = tx_frame.clone();
-    tx_frame.drain(..);
+    let mut tx = vec!();
+    node.send((0..5).map(|x| x as u8), [remote_addr].iter().cloned(), &mut tx).unwrap();
 
-    for _ in 0..CALL_COUNT {
-        for (i,node) in nodes.iter_mut().enumerate() {
-            node.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&rx_frame), &mut tx_frame),
-                |_,data| {
-                    recv[i] += 1;
-                    assert!((0..128).eq(data.iter().cloned()));
-                },
-                |_,data| {
-                    if data.len() > 0 {
-                        obs[i] += 1;
-                        assert!((0..128).eq(data.iter().cloned()));
-                    }
-                }).unwrap();
-        }
+    //Changing the port shouldn't move a frame that's already queued
+    node.set_tx_port(0);
 
-        //Swap TX and RX
-        rx_frame = tx_frame.clone();
-        tx_frame.drain(..);
-    }
+    let mut retry = vec!();
+    let mut retry_count = 0;
+    node.tick(&mut retry, tx_queue::RETRY_DELAY_MS, |_,_,_| retry_count += 1, |_,_| {}, |_| {}).unwrap();
 
-    for i in 1..CALL_COUNT {
-        assert_eq!(obs[i], 15);
-        assert_eq!(nodes[i].tx_queue.pending_packets(), 0);
-        assert_eq!(nodes[i].recv_buffer.len(), 0);
-    }
+    assert_eq!(retry_count, 1);
+    assert_eq!(tx, retry);
 
-    for i in 1..CALL_COUNT-1 {
-        assert_eq!(recv[i], 0);
+    let mut decoded = vec!();
+    match kiss::decode(retry.iter().cloned(), &mut decoded) {
+        Some(result) => assert_eq!(result.port, 2),
+        None => assert!(false)
     }
-
-    assert_eq!(recv[CALL_COUNT-1], 1);
 }
 
 #[test]
-fn test_broadcast_route() {
-    const CALL_COUNT: usize = 16;
+fn test_recv_corrupt_frame() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
 
-    let route = (0..CALL_COUNT-1)
-        .map(|i| (i, gen_callsign(i)))
-        .map(|(i, cs)| {
-            if i == 1 {
-                routing::BROADCAST_ADDRESS
-            } else {
-                address::encode(cs).unwrap()
-            }
-        })
-        .collect::<Vec<_>>();
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
 
-    let node_addr = (0..CALL_COUNT-1)
-        .map(|i| gen_callsign(i))
-        .map(|cs| address::encode(cs).unwrap());
+    let mut first = vec!();
+    let mut corrupt = vec!();
+    let mut last = vec!();
+    local.send((0..5).map(|x| x as u8), [remote_addr].iter().cloned(), &mut first).unwrap();
+    local.send((5..10).map(|x| x as u8), [remote_addr].iter().cloned(), &mut corrupt).unwrap();
+    local.send((10..15).map(|x| x as u8), [remote_addr].iter().cloned(), &mut last).unwrap();
 
-    let local = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    //Flip a bit in the payload of the middle frame, just ahead of the CRC and trailing FEND
+    let corrupt_idx = corrupt.len() - 4;
+    corrupt[corrupt_idx] ^= 0x01;
 
-    use std::iter;
-    let mut nodes = iter::once(local).chain(node_addr)
-        .map(|addr| new(addr))
-        .collect::<Vec<_>>();
+    let mut rx = first.clone();
+    rx.extend_from_slice(&corrupt);
+    rx.extend_from_slice(&last);
 
-    let mut tx_frame = vec!();
-    let mut rx_frame;
+    let mut received = vec!();
+    let mut error_count = 0;
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&rx), &mut vec!()),
+        |_,data| received.push(data.to_vec()),
+        |_| {},
+        |_| assert!(false),
+        |prn,e,_| {
+            error_count += 1;
+            assert!(prn.is_none());
 
-    let mut obs = [0; CALL_COUNT];
-    let mut recv = [0; CALL_COUNT];
+            match *e {
+                RecvError::Frame(frame::ReadError::CRCFailure) => (),
+                _ => assert!(false)
+            }
+        }).unwrap();
 
-    //Send initial packet
-    let data = (0..128).map(|x| x as u8);
-    nodes[0].send(data, route.iter().cloned(), &mut tx_frame).unwrap();
+    assert_eq!(error_count, 1);
+    assert_eq!(received, vec!((0..5).collect::<Vec<u8>>(), (10..15).collect::<Vec<u8>>()));
+    assert_eq!(remote.recv_buffer.len(), 0);
+}
 
-    rx_frame = tx_frame.clone();
-    tx_frame.drain(..);
+#[test]
+fn test_recv_truncated_crc() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
 
-    for _ in 0..CALL_COUNT {
-        for (i,node) in nodes.iter_mut().enumerate() {
-            node.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&rx_frame), &mut tx_frame),
-                |_,data| {
-                    if data.len() > 0 {
-                        recv[i] += 1;
-                        assert!((0..128).eq(data.iter().cloned()));
-                    }
-                },
-                |_,data| {
-                    if data.len() > 0 {
-                        obs[i] += 1;
-                        assert!((0..128).eq(data.iter().cloned()));
-                    }
-                }).unwrap();
-        }
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
 
-        //Swap TX and RX
-        rx_frame = tx_frame.clone();
-        tx_frame.drain(..);
-    }
+    //Data frame and ack that each lost their last CRC byte before the FEND
+    let data = frame::new_header(&mut local.prn, [remote_addr, routing::ADDRESS_SEPARATOR, local_addr].iter().cloned()).unwrap();
+    let ack = frame::new_ack(local.prn.next(), routing::gen_route(&[remote_addr, routing::ADDRESS_SEPARATOR, local_addr]));
 
-    for i in 1..CALL_COUNT {
-        assert_eq!(obs[i], 210);
-        assert_eq!(nodes[i].tx_queue.pending_packets(), 0);
-        assert_eq!(nodes[i].recv_buffer.len(), 0);
+    let mut rx = vec!();
+    for &(ref header, payload) in [(data, &[1, 2, 3][..]), (ack, &[][..])].iter() {
+        let mut frame_data = vec!();
+        frame::to_bytes(&mut frame_data, header, Some(payload)).unwrap();
+        frame_data.pop();
+
+        kiss::encode(&mut io::Cursor::new(&frame_data), &mut rx, 0).unwrap();
     }
 
-    for i in 1..CALL_COUNT-1 {
-        assert_eq!(recv[i], 0);
+    //Along with something far too big to be a frame
+    kiss::encode(&mut io::Cursor::new(&[0; frame::MAX_PACKET_SIZE + 1][..]), &mut rx, 0).unwrap();
+
+    local.send_slice(&[4, 5, 6], [remote_addr].iter().cloned(), &mut rx).unwrap();
+
+    //Split across reads right where the CRC of the first frame was cut
+    let split = rx.iter().position(|byte| *byte == kiss::FEND).unwrap() + 1;
+    let split = split + rx[split..].iter().position(|byte| *byte == kiss::FEND).unwrap() - 1;
+
+    let mut received = vec!();
+    let mut error_count = 0;
+    for chunk in [&rx[..split], &rx[split..]].iter() {
+        remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(chunk), &mut vec!()),
+            |_,data| received.push(data.to_vec()),
+            |_| {},
+            |_| assert!(false),
+            |_,e,_| {
+                error_count += 1;
+
+                match *e {
+                    RecvError::Frame(_) => (),
+                    _ => assert!(false)
+                }
+            }).unwrap();
     }
 
-    assert_eq!(recv[CALL_COUNT-1], 1);
+    assert_eq!(error_count, 3);
+    assert_eq!(received, vec!(vec!(4, 5, 6)));
+    assert_eq!(remote.recv_buffer.len(), 0);
 }
 
 #[test]
-fn test_split_path() {
-    fn get_split(prn: &mut prn_id::PRN) -> Vec<u8> {
-        let mut packet = vec!();
-        let callsign = prn.current;
-        let header = frame::new_header(prn, [callsign, routing::ADDRESS_SEPARATOR, callsign, callsign].into_iter().cloned()).unwrap();
-        let data = (0..32).map(|x| x as u8).collect::<Vec<_>>();
-        frame::to_bytes(&mut packet, &header, Some(&data)).unwrap();
-        let mut kiss = vec!();
-        kiss::encode(&mut io::Cursor::new(packet), &mut kiss, 0).unwrap();
+fn test_recv_leading_garbage() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
 
-        kiss
-    }
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
 
-    let mut prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
+    //Noise ahead of a partial frame is dropped without waiting for the frame to complete
+    let mut rx = (0..50).collect::<Vec<u8>>();
+    local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut rx).unwrap();
+    let partial = rx.len() - 3;
 
-    let mut left_packet = get_split(&mut prn);
-    let right_packet = left_packet.clone();
+    let mut received = vec!();
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&rx[..partial]), &mut vec!()),
+        |_,data| received.push(data.to_vec()),
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(received.len(), 0);
+    assert_eq!(remote.recv_buffer.len(), partial - 50);
 
-    left_packet.extend_from_slice(&right_packet);
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&rx[partial..]), &mut vec!()),
+        |_,data| received.push(data.to_vec()),
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+    assert_eq!(received, vec!(vec!(1, 2, 3)));
+    assert_eq!(remote.recv_buffer.len(), 0);
 
-    let mut node = new(prn.callsign);
+    //Junk straight ahead of a complete frame
+    received.clear();
+    let mut rx = (0..50).collect::<Vec<u8>>();
+    local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut rx).unwrap();
 
-    let mut rx_count = 0;
-    let mut obs_count = 0;
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&rx), &mut vec!()),
+        |_,data| received.push(data.to_vec()),
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+
+    assert_eq!(received, vec!(vec!(1, 2, 3)));
+    assert_eq!(remote.recv_buffer.len(), 0);
+}
+
+#[test]
+fn test_recv_malformed_route() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut remote = new(remote_addr);
+
+    //Separator in the last slot leaves nowhere to record our hop, advance rejects it so the route always gets shorter
+    let mut route = [local_addr; routing::MAX_LENGTH];
+    route[0] = remote_addr;
+    route[routing::MAX_LENGTH-1] = routing::ADDRESS_SEPARATOR;
+
+    let header = frame::new_ack(1234, route);
+    let mut packet = vec!();
+    frame::to_bytes(&mut packet, &header, Some(&[1, 2, 3])).unwrap();
+    let mut rx = vec!();
+    kiss::encode(&mut io::Cursor::new(&packet), &mut rx, 0).unwrap();
 
     let mut tx = vec!();
-    node.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&left_packet), &mut tx),
-        |_, payload| {
-            if payload.len() > 0 {
-                rx_count += 1;
-            }
-        },
-        |_, payload| {
-            if payload.len() > 0 {
-                obs_count += 1;
+    let mut error_count = 0;
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&rx), &mut tx),
+        |_,_| assert!(false),
+        |_| assert!(false),
+        |_| assert!(false),
+        |prn,e,_| {
+            error_count += 1;
+            assert_eq!(prn, Some(1234));
+
+            match *e {
+                RecvError::Routing(routing::ParseError::BadFormat) => (),
+                _ => assert!(false)
             }
         }).unwrap();
-    
-    assert_eq!(rx_count, 1);
-    assert_eq!(obs_count, 2);
+
+    assert_eq!(error_count, 1);
+    assert_eq!(tx.len(), 0);
 }
 
 #[test]
-fn test_recv_bad_data() {
-    let prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
+fn test_tick_congestion() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
 
-    for i in 0..frame::MTU+2 {
-        let mut node = new(prn.callsign);
+    let mut node = new(local_addr);
+    let mut tx = vec!();
 
-        let bad_data = (0..i).map(|x| x as u8).collect::<Vec<_>>();
-        let mut bad_kiss = vec!();
-        kiss::encode(&mut io::Cursor::new(bad_data), &mut bad_kiss, 0).unwrap();
+    //Nobody acks so enough full packets will put us over the congestion limit
+    for _ in 0..(tx_queue::CONGEST_CONTROL / frame::MTU + 1) {
+        node.send_slice(&[0; frame::MTU], [remote_addr].iter().cloned(), &mut tx).unwrap();
+    }
 
-        let result = node.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(bad_kiss), &mut vec!()),
-            |_,_| {},
-            |_,_| {});
+    let mut states = vec!();
+    node.tick(&mut tx, 0, |_,_,_| {}, |_,_| {}, |congested| states.push(congested)).unwrap();
+    assert_eq!(states, vec!(true));
 
-        match result {
-            Ok(()) => assert!(false),
-            _ => ()
-        }
+    //No change shouldn't notify again
+    node.tick(&mut tx, 0, |_,_,_| {}, |_,_| {}, |congested| states.push(congested)).unwrap();
+    assert_eq!(states, vec!(true));
 
-        let mut packet = vec!();
-        use std::iter;
-        node.send((0..5).map(|x| x as u8), iter::once(prn.callsign), &mut util::new_read_write_dispatch(&mut io::Cursor::new(vec!()), &mut packet)).unwrap();
+    //Packets get discarded after their retry while congested, which brings us back out
+    node.tick(&mut tx, tx_queue::RETRY_DELAY_MS, |_,_,_| {}, |_,_| {}, |congested| states.push(congested)).unwrap();
+    assert_eq!(states, vec!(true, false));
+}
 
-        node.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(packet), &mut vec!()),
-            |_,data| {
-                for i in 0..5 {
-                    assert_eq!(data[i], i as u8);
-                }
-            },
-            |_,_| {
+#[test]
+fn test_next_tick_deadline() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
 
-            }).unwrap();
-    }
-}
\ No newline at end of file
+    let mut node = new(local_addr);
+    assert_eq!(node.next_tick_deadline(), None);
+
+    let mut tx = vec!();
+    node.send((0..5).map(|x| x as u8), [remote_addr].iter().cloned(), &mut tx).unwrap();
+    assert_eq!(node.next_tick_deadline(), Some(tx_queue::RETRY_DELAY_MS));
+
+    //Ticking exactly to the deadline triggers the retry
+    let mut retry_count = 0;
+    let deadline = node.next_tick_deadline().unwrap();
+    node.tick(&mut tx, deadline - 1, |_,_,_| retry_count += 1, |_,_| {}, |_| {}).unwrap();
+    assert_eq!(retry_count, 0);
+    node.tick(&mut tx, 1, |_,_,_| retry_count += 1, |_,_| {}, |_| {}).unwrap();
+    assert_eq!(retry_count, 1);
+}
+
+#[test]
+fn test_send_to_slice() {
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let mut local = new(local_addr);
+    let mut remote = new(remote_addr);
+
+    //Too small a buffer shouldn't leave anything queued or use up a PRN
+    let mut small = [0; 8];
+    let required = match local.send_to_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut small) {
+        Err(SendError::Size(e)) => e.required,
+        _ => { assert!(false); 0 }
+    };
+    assert_eq!(local.tx_queue.pending_packets(), 0);
+
+    let mut out = [0; frame::MAX_PACKET_SIZE * 2];
+    let (prn, written) = local.send_to_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut out).unwrap();
+    assert_eq!(local.tx_queue.pending_packets(), 1);
+    assert_eq!(prn, prn_id::new(local_addr).next());
+    assert_eq!(written, required);
+
+    let mut recv_prn = None;
+    remote.recv(&mut util::new_read_write_dispatch(&mut io::Cursor::new(&out[..written]), &mut vec!()),
+        |header,data| {
+            recv_prn = Some(header.prn);
+            assert_eq!(data, &[1, 2, 3]);
+        },
+        |_| {},
+        |_| assert!(false),
+        |_,_,_| assert!(false)).unwrap();
+
+    assert_eq!(recv_prn, Some(prn));
+}
@@ -1,17 +1,23 @@
 ///! Table for tracking recieved PRNs
 use spec::prn_id;
 
-const TABLE_SIZE: usize = 1000;
+/// Number of PRNs a table from `new()` remembers
+pub const TABLE_SIZE: usize = 1000;
 
-///Table of last 1000 recieved PRNs
+///Table of the last recieved PRNs, 1000 unless constructed with `with_capacity`
 pub struct Table {
-    prns: [prn_id::PrnValue; TABLE_SIZE],
+    prns: Vec<prn_id::PrnValue>,
     last_idx: usize
 }
 
 pub fn new() -> Table {
+    with_capacity(TABLE_SIZE)
+}
+
+/// Constructs a table that remembers the last `capacity` PRNs, at least one is always kept
+pub fn with_capacity(capacity: usize) -> Table {
     Table {
-        prns: [0; TABLE_SIZE],
+        prns: vec!(0; ::std::cmp::max(capacity, 1)),
         last_idx: 0
     }
 }
@@ -25,11 +31,16 @@ impl Table {
 
         self.last_idx += 1;
 
-        if self.last_idx >= 1000 {
+        if self.last_idx >= self.prns.len() {
             self.last_idx = 0;
         }
     }
 
+    /// Number of PRNs this table remembers
+    pub fn capacity(&self) -> usize {
+        self.prns.len()
+    }
+
     /// Checks if a prn is contained within the table
     pub fn contains(&self, prn: prn_id::PrnValue) -> bool {
         self.prns.iter().any(|search| *search == prn)
@@ -65,4 +76,43 @@ fn test_last_1000() {
     }
 
     assert!(!table.contains(first_prn));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_with_capacity() {
+    let mut prn = prn_id::new(address::encode(['K', 'I' ,'7', 'E', 'S', 'T', '0']).unwrap());
+    let mut table = with_capacity(4);
+    assert_eq!(table.capacity(), 4);
+
+    let prns = (0..5).map(|_| prn.next()).collect::<Vec<_>>();
+    for prn_value in &prns[..4] {
+        table.add(*prn_value);
+    }
+    assert!(prns[..4].iter().all(|prn_value| table.contains(*prn_value)));
+
+    //Fifth insert evicts the oldest
+    table.add(prns[4]);
+    assert!(!table.contains(prns[0]));
+    assert!(prns[1..].iter().all(|prn_value| table.contains(*prn_value)));
+
+    assert_eq!(with_capacity(0).capacity(), 1);
+}
+
+#[test]
+fn test_wraparound() {
+    let mut prn = prn_id::new(address::encode(['K', 'I' ,'7', 'E', 'S', 'T', '0']).unwrap());
+
+    //Eviction follows the table's size whatever it is, across several wraps
+    for &capacity in [1, 2, 3, 7, 64, TABLE_SIZE].iter() {
+        let mut table = with_capacity(capacity);
+        let prns = (0..capacity*3+1).map(|_| prn.next()).collect::<Vec<_>>();
+
+        for prn_value in &prns {
+            table.add(*prn_value);
+        }
+
+        let (evicted, kept) = prns.split_at(prns.len() - capacity);
+        assert!(kept.iter().all(|prn_value| table.contains(*prn_value)));
+        assert!(evicted.iter().all(|prn_value| !table.contains(*prn_value)));
+    }
+}
@@ -1,31 +1,134 @@
 ///! Transmitting queue for outgoing frames
+use std::cmp;
 use std::fmt;
+use std::io;
+use std::error;
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
 use rand;
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
 use spec::frame;
+use spec::routing;
 
-/// Maximum number of packets in flight
+/// Default maximum number of packets in flight
 pub const MAX_PACKET: usize = 256;
 /// Data buffer size
 pub const BLOCK_SIZE: usize = 50 * 1024;
 /// Data buffer size in flight before congestion control takes effect
 pub const CONGEST_CONTROL: usize = 35 * 1024;
-/// Number of times a packet will attempt to retry
+/// Number of times a packet will attempt to retry by default
 pub const RETRY_COUNT: usize = 4;
-/// Number of milliseconds until we will resend an un-ack'd packet. Grows proportional to the number of retries.
+/// Most retries `set_retry_count` allows, keeps the backoff delay in range and fits the export format
+pub const MAX_RETRY_COUNT: usize = 16;
+/// Number of milliseconds until we will resend an un-ack'd packet. Doubles with each retry.
 pub const RETRY_DELAY_MS: usize = 500;
+/// Version of the format written by `Queue::export`
+pub const EXPORT_VERSION: u32 = 1;
+/// Shortest wait in ms before trying a transmit that was held off by a busy channel
+pub const SLOT_TIME_MS: usize = 100;
 
 /// Queue of packets waiting to be recieved
 pub struct Queue {
     /// Packets waiting to go our on the wire
     pending: Vec<PendingPacket>,
     /// Payloads for pending packets
-    data: Vec<u8>
+    data: Vec<u8>,
+    /// Maximum number of packets waiting for an ack
+    max_packets: usize,
+    /// Whether we were under congestion control the last time it was checked
+    congested: bool,
+    /// How we shed load while under congestion control
+    policy: CongestionPolicy,
+    /// Source of retry jitter, falls back to the thread RNG if not provided
+    rng: Option<Box<rand::Rng + Send>>,
+    /// Whether an identical payload on the same route collapses into the packet already in flight
+    merge_duplicates: bool,
+    /// Number of times a packet is retried before it's discarded
+    retry_count: usize,
+    /// Total ms ticked, packets are stamped with it when queued to measure round trip times
+    elapsed_ms: usize
+}
+
+/// What the queue gives up on while under congestion control
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CongestionPolicy {
+    /// Packets that come due while congested get one last retry and are then discarded
+    DropDueRetries,
+    /// The oldest packets are discarded on the next tick regardless of when they're due
+    DropOldest,
+    /// New packets are rejected, everything already queued retries as usual
+    RejectNew
 }
 
 #[derive(Debug)]
 pub enum QueueError {
     /// Congestion control is underway and this frame was immediately discarded
-    Discarded
+    Discarded,
+    /// Too many packets are waiting for an ack and this frame was immediately discarded
+    TooManyInFlight
+}
+
+/// Error cases for restoring an exported queue
+#[derive(Debug)]
+pub enum ImportError {
+    /// Export was written by a version we don't know how to read
+    UnsupportedVersion(u32),
+    /// Export ended early
+    Truncated,
+    /// A stored frame couldn't be parsed
+    Frame(frame::ReadError),
+    /// The queue couldn't hold every stored frame
+    Queue(QueueError)
+}
+
+impl From<frame::ReadError> for ImportError {
+    fn from(err: frame::ReadError) -> ImportError {
+        ImportError::Frame(err)
+    }
+}
+
+impl From<QueueError> for ImportError {
+    fn from(err: QueueError) -> ImportError {
+        ImportError::Queue(err)
+    }
+}
+
+impl From<io::Error> for ImportError {
+    fn from(_: io::Error) -> ImportError {
+        ImportError::Truncated
+    }
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QueueError::Discarded => write!(f, "Frame was discarded due to congestion control"),
+            QueueError::TooManyInFlight => write!(f, "Too many frames are waiting for an ack")
+        }
+    }
+}
+
+impl error::Error for QueueError {}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImportError::UnsupportedVersion(version) => write!(f, "Unsupported queue export version {}", version),
+            ImportError::Truncated => write!(f, "Queue export was truncated"),
+            ImportError::Frame(ref e) => write!(f, "Stored frame couldn't be parsed: {}", e),
+            ImportError::Queue(ref e) => write!(f, "Stored frame couldn't be queued: {}", e)
+        }
+    }
+}
+
+impl error::Error for ImportError {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            ImportError::Frame(ref e) => Some(e),
+            ImportError::Queue(ref e) => Some(e),
+            _ => None
+        }
+    }
 }
 
 /// Pending packet to be recieved
@@ -37,28 +140,113 @@ pub struct PendingPacket {
     next_send: usize,
     /// Number of retry attempts
     retry_count: usize,
+    /// KISS port this packet is transmitted on
+    port: u8,
+    /// False until the first transmit goes out, set when the channel was busy at send time
+    sent: bool,
     /// Byte offset for our payload packet
     data_offset: usize,
     /// Size of our data packet
-    data_size : usize
+    data_size : usize,
+    /// Hash of the route and payload, used to spot duplicate sends
+    hash: u64,
+    /// Queue's `elapsed_ms` when this packet was queued
+    queued_at: usize
+}
+
+/// Snapshot of a packet waiting for an ack, for display
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PendingInfo {
+    /// PRN of the packet
+    pub prn: u32,
+    /// Final destination of the packet
+    pub dest: u32,
+    /// Number of times it's been retried so far
+    pub retry_count: usize,
+    /// Size of the payload in bytes
+    pub size: usize,
+    /// Milliseconds until it's retried or discarded
+    pub next_send: usize
+}
+
+/// Delay in ms before the next retry of a packet. Backs off exponentially with the retry count and
+/// uses `rnd`([0, 1)) to pick a point in the upper half of that interval so the delay never collapses to zero.
+fn retry_delay(retry_count: usize, rnd: f32) -> usize {
+    let interval = RETRY_DELAY_MS << retry_count;
+
+    ((0.5 + rnd * 0.5) * interval as f32) as usize
+}
+
+/// Delay in ms before trying a transmit again after finding the channel busy, between one and two slot times
+/// so stations waiting on the same channel don't all key up together.
+fn slot_delay(rnd: f32) -> usize {
+    ((1.0 + rnd) * SLOT_TIME_MS as f32) as usize
+}
+
+/// Hashes a route and payload for duplicate detection
+fn payload_hash(route: &routing::Route, payload: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    route.hash(&mut hasher);
+    payload.hash(&mut hasher);
+
+    hasher.finish()
 }
 
 /// Constructs a new queue
 pub fn new() -> Queue {
     Queue {
         pending: vec!(),
-        data: vec!()
+        data: vec!(),
+        max_packets: MAX_PACKET,
+        congested: false,
+        policy: CongestionPolicy::DropDueRetries,
+        rng: None,
+        merge_duplicates: false,
+        retry_count: RETRY_COUNT,
+        elapsed_ms: 0
+    }
+}
+
+/// Constructs a new queue that draws retry jitter from `rng`, useful for reproducible retry timing
+pub fn new_with_rng<R>(rng: R) -> Queue where R: rand::Rng + Send + 'static {
+    Queue {
+        pending: vec!(),
+        data: vec!(),
+        max_packets: MAX_PACKET,
+        congested: false,
+        policy: CongestionPolicy::DropDueRetries,
+        rng: Some(Box::new(rng)),
+        merge_duplicates: false,
+        retry_count: RETRY_COUNT,
+        elapsed_ms: 0
     }
 }
 
 impl Queue {
-    /// Enqueue a new frame, called just after we send out a frame over the wire
-    pub fn enqueue(&mut self, header: frame::Frame, payload: &[u8]) -> Result<(),QueueError> {
-        trace!("Enqueuing frame {} with {} bytes, waiting for ACK", header.prn, payload.len());
+    /// Enqueue a new frame, called just after we send out a frame over the wire. Retries go out on the same KISS port.
+    /// Returns the frame carrying the payload, which is the one already in flight if it was merged(see `set_merge_duplicates`).
+    pub fn enqueue(&mut self, header: frame::Frame, payload: &[u8], port: u8) -> Result<frame::Frame,QueueError> {
+        self.push(header, payload, port, RETRY_DELAY_MS, true)
+    }
+
+    /// Enqueue a new frame that hasn't been sent yet because the channel was busy. It's handed to `send_deferred`
+    /// after a random slot delay and waits for an ack like any other frame from then on.
+    pub fn enqueue_deferred(&mut self, header: frame::Frame, payload: &[u8], port: u8) -> Result<frame::Frame,QueueError> {
+        let delay = slot_delay(self.random());
+        self.push(header, payload, port, delay, false)
+    }
+
+    fn push(&mut self, header: frame::Frame, payload: &[u8], port: u8, next_send: usize, sent: bool) -> Result<frame::Frame,QueueError> {
+        trace!("Enqueuing frame {} with {} bytes on port {}, waiting for ACK", header.prn, payload.len(), port);
+
+        if let Some(existing) = self.find_duplicate(&header.address_route, payload) {
+            debug!("Frame {} duplicates frame {} already in flight, merging", header.prn, existing.prn);
+            return Ok(existing)
+        }
 
-        if self.data.len() + payload.len() > BLOCK_SIZE {
-            error!("Tried to queue packet but congestion control is under way and was discarded");
-            return Err(QueueError::Discarded);
+        if let Err(e) = self.can_enqueue(payload.len()) {
+            warn!("Tried to queue packet {} with {} bytes in flight but it was discarded: {}", header.prn, self.data.len(), e);
+            return Err(e)
         }
 
         //Store where we started reading data so we can move our copy back if it fails
@@ -68,66 +256,129 @@ impl Queue {
 
         self.pending.push(PendingPacket {
             packet: header,
-            next_send: RETRY_DELAY_MS,
+            next_send: next_send,
             retry_count: 0,
+            port: port,
+            sent: sent,
             data_offset: data_start,
-            data_size: payload.len()
+            data_size: payload.len(),
+            hash: payload_hash(&header.address_route, payload),
+            queued_at: self.elapsed_ms
         });
 
         trace!("Queued packet, buffer at {} of {} bytes", self.data.len(), BLOCK_SIZE);
 
+        Ok(header)
+    }
+
+    /// Checks whether a payload of `size` bytes would be accepted right now without queuing anything
+    pub fn can_enqueue(&self, size: usize) -> Result<(), QueueError> {
+        if self.data.len() + size > BLOCK_SIZE {
+            return Err(QueueError::Discarded)
+        }
+
+        if self.policy == CongestionPolicy::RejectNew && self.is_congested() {
+            return Err(QueueError::Discarded)
+        }
+
+        if self.pending.len() >= self.max_packets {
+            return Err(QueueError::TooManyInFlight)
+        }
+
         Ok(())
     }
 
+    /// Finds the packet already in flight with the same route and payload, only matches when merging is enabled
+    pub fn find_duplicate(&self, route: &routing::Route, payload: &[u8]) -> Option<frame::Frame> {
+        if !self.merge_duplicates {
+            return None
+        }
+
+        let hash = payload_hash(route, payload);
+
+        self.pending.iter()
+            .find(|pending| {
+                pending.hash == hash
+                    && pending.packet.address_route == *route
+                    && &self.data[pending.data_offset..pending.data_offset+pending.data_size] == payload
+            })
+            .map(|pending| pending.packet)
+    }
+
     // Called when we recieve an ack packet
     pub fn ack_recv(&mut self, prn: u32) -> bool {
+        self.ack_recv_rtt(prn).is_some()
+    }
+
+    /// Same as `ack_recv` but returns the ms ticked between queuing the packet and its ack, `None` if it wasn't found
+    pub fn ack_recv_rtt(&mut self, prn: u32) -> Option<usize> {
         match self.pending.iter().position(|pending| pending.packet.prn == prn) {
             Some(idx) => {
+                let rtt = self.elapsed_ms.wrapping_sub(self.pending[idx].queued_at);
                 self.discard(idx);
-                trace!("ACK for {}, buffer at {} bytes", prn, self.data.len());
+                info!("ACK for {} after {}ms, buffer at {} bytes", prn, rtt, self.data.len());
 
-                true
+                Some(rtt)
             },
             None => {
                 trace!("Tried to ack packet {} but it wasn't found in our table", prn);
-                false
+                None
             }
         } 
     }
 
-    // Check any packets that have expired, resend is called on packets we want to retry, discard on packets that have exceeded the retry count
+    // Check any packets that have expired, resend is called on packets we want to retry, discard on packets that have exceeded the retry count.
+    // A failed resend doesn't use up a retry and is tried again next tick, the first error is returned once every packet has been ticked.
     pub fn tick<R,D,E>(&mut self, elapsed_ms: usize, mut retry: R, mut discard: D) -> Result<(),E>
         where
-            R: FnMut(&frame::Frame, &[u8], usize) -> Result<(),E>,
+            R: FnMut(&frame::Frame, &[u8], u8, usize) -> Result<(),E>,
             D: FnMut(&frame::Frame, &[u8]),
             E: fmt::Debug
     {
         //trace!("Ticking send queue for {}ms", elapsed_ms);
+        self.elapsed_ms = self.elapsed_ms.wrapping_add(elapsed_ms);
+
+        if self.policy == CongestionPolicy::DropOldest {
+            while self.is_congested() && self.pending.len() > 0 {
+                warn!("Congestion control underway, discarding oldest packet {}", self.pending[0].packet.prn);
+                discard(&self.pending[0].packet, self.get_packet_data(&self.pending[0]));
+                self.discard(0);
+            }
+        }
+
+        let drop_due = self.policy == CongestionPolicy::DropDueRetries;
+        let mut result = Ok(());
         let mut idx = 0;
         while idx < self.pending.len() {
-            if self.pending[idx].next_send <= elapsed_ms {
-                let will_discard = self.pending[idx].retry_count >= RETRY_COUNT || self.data.len() > CONGEST_CONTROL;
-                let will_retry = self.pending[idx].retry_count < RETRY_COUNT;
+            if !self.pending[idx].sent {
+                //Hasn't gone out yet so there's nothing to retry, send_deferred picks it up once it's due
+                self.pending[idx].next_send = self.pending[idx].next_send.saturating_sub(elapsed_ms);
+                idx += 1;
+            } else if self.pending[idx].next_send <= elapsed_ms {
+                let will_discard = self.pending[idx].retry_count >= self.retry_count || (drop_due && self.data.len() > CONGEST_CONTROL);
+                let will_retry = self.pending[idx].retry_count < self.retry_count;
 
                 //If we're going to retry do it first in case we're in a congestion scenario
                 if will_retry {
                     trace!("Retrying {} packet with retry count {}", self.pending[idx].packet.prn, self.pending[idx].retry_count);
 
-                    //Note that we increment our retry count here in case something about this packet prevents it
-                    //from being sent so we won't hang the whole link
-                    self.pending[idx].retry_count += 1;
-
                     //Determine when we want to retry again. Note that we randomize so two transmitters won't collide
-                    use rand::distributions::IndependentSample;
-                    let rnd = rand::distributions::Range::new(0.0, 1.0).ind_sample(&mut rand::thread_rng());
-                    let next_send = ((1.0 + self.pending[idx].retry_count as f32 * rnd) * RETRY_DELAY_MS as f32) as usize;
-                    self.pending[idx].next_send = next_send;
-
-                    match retry(&self.pending[idx].packet, self.get_packet_data(&self.pending[idx]), next_send) {
-                        Ok(()) => (),
+                    let rnd = self.random();
+                    let next_send = retry_delay(self.pending[idx].retry_count + 1, rnd);
+
+                    match retry(&self.pending[idx].packet, self.get_packet_data(&self.pending[idx]), self.pending[idx].port, next_send) {
+                        Ok(()) => {
+                            self.pending[idx].retry_count += 1;
+                            self.pending[idx].next_send = next_send;
+                        },
                         Err(e) => {
-                            trace!("Error retrying packet {:?}, incrementing retry counter and aborting", &e);
-                            return Err(e)
+                            //Nothing went out so this attempt doesn't count, leave it due so the next tick tries it again.
+                            //Keep going so everything else sees the elapsed time, the first error is returned at the end.
+                            trace!("Error retrying packet {} {:?}, not using up a retry", self.pending[idx].packet.prn, &e);
+                            self.pending[idx].next_send = 0;
+                            if result.is_ok() {
+                                result = Err(e);
+                            }
                         }
                     }
                 }
@@ -135,9 +386,9 @@ impl Queue {
                 //Discard our packet if we've flagged it for discarding
                 if will_discard {
                     if self.data.len() > CONGEST_CONTROL {
-                        trace!("Congestion control underway, discarding packet after last retry");
+                        warn!("Congestion control underway, discarding packet {} after last retry", self.pending[idx].packet.prn);
                     } else {
-                        trace!("Packet {} has exceeded retry count, discarding", self.pending[idx].packet.prn);
+                        warn!("Packet {} has exceeded retry count, discarding", self.pending[idx].packet.prn);
                     }
 
                     discard(&self.pending[idx].packet, self.get_packet_data(&self.pending[idx]));
@@ -158,23 +409,156 @@ impl Queue {
             }
         }
 
+        result
+    }
+
+    /// Sends any frames that were deferred by a busy channel and are now due. Once sent they wait
+    /// for an ack and are retried by `tick`.
+    pub fn send_deferred<S,E>(&mut self, mut send: S) -> Result<(),E>
+        where
+            S: FnMut(&frame::Frame, &[u8], u8) -> Result<(),E>,
+            E: fmt::Debug
+    {
+        for idx in 0..self.pending.len() {
+            if self.pending[idx].sent || self.pending[idx].next_send > 0 {
+                continue
+            }
+
+            trace!("Sending deferred packet {}", self.pending[idx].packet.prn);
+            try!(send(&self.pending[idx].packet, self.get_packet_data(&self.pending[idx]), self.pending[idx].port));
+
+            self.pending[idx].sent = true;
+            self.pending[idx].next_send = RETRY_DELAY_MS;
+        }
+
+        Ok(())
+    }
+
+    /// Called in place of `tick` while the channel is busy. Nothing is sent or discarded, anything that
+    /// comes due is pushed back by a random slot delay.
+    pub fn hold(&mut self, elapsed_ms: usize) {
+        for idx in 0..self.pending.len() {
+            if self.pending[idx].next_send <= elapsed_ms {
+                let delay = slot_delay(self.random());
+                trace!("Channel busy, holding {} for {}ms", self.pending[idx].packet.prn, delay);
+                self.pending[idx].next_send = delay;
+            } else {
+                self.pending[idx].next_send -= elapsed_ms;
+            }
+        }
+    }
+
+    /// Serializes every pending packet along with its retry state so it can be restored with `import`
+    pub fn export(&self) -> Vec<u8> {
+        let mut exported = vec!();
+
+        //Writing to a Vec can't fail
+        exported.write_u32::<BigEndian>(EXPORT_VERSION).unwrap();
+        exported.write_u32::<BigEndian>(self.pending.len() as u32).unwrap();
+
+        for pending in self.pending.iter() {
+            let mut frame_data = vec!();
+            frame::to_bytes(&mut frame_data, &pending.packet, Some(self.get_packet_data(pending))).unwrap();
+
+            exported.write_u16::<BigEndian>(frame_data.len() as u16).unwrap();
+            exported.extend_from_slice(&frame_data);
+            exported.write_u32::<BigEndian>(pending.next_send as u32).unwrap();
+            exported.write_u8(pending.retry_count as u8).unwrap();
+            exported.write_u8(pending.port).unwrap();
+            exported.write_u8(pending.sent as u8).unwrap();
+        }
+
+        trace!("Exported {} pending packets in {} bytes", self.pending.len(), exported.len());
+
+        exported
+    }
+
+    /// Restores packets written by `export`, they're added after anything already pending and retry from where they left off.
+    /// The whole export is parsed and queued or nothing is, the queue is left as it was on any error.
+    pub fn import(&mut self, exported: &[u8]) -> Result<(), ImportError> {
+        let mut reader = io::Cursor::new(exported);
+
+        let version = try!(reader.read_u32::<BigEndian>());
+        if version != EXPORT_VERSION {
+            error!("Unable to import queue with version {}", version);
+            return Err(ImportError::UnsupportedVersion(version))
+        }
+
+        let count = try!(reader.read_u32::<BigEndian>());
+        let mut packets = vec!();
+
+        for _ in 0..count {
+            let frame_len = try!(reader.read_u16::<BigEndian>()) as usize;
+
+            let start = reader.position() as usize;
+            if start + frame_len > exported.len() {
+                return Err(ImportError::Truncated)
+            }
+
+            let mut payload = [0; frame::MTU];
+            let (header, payload_size) = try!(frame::from_bytes(&mut io::Cursor::new(&exported[start..start+frame_len]), &mut payload, frame_len));
+            reader.set_position((start + frame_len) as u64);
+
+            let next_send = try!(reader.read_u32::<BigEndian>()) as usize;
+            let retry_count = try!(reader.read_u8()) as usize;
+            let port = try!(reader.read_u8());
+            let sent = try!(reader.read_u8()) != 0;
+
+            packets.push((header, payload[..payload_size].to_vec(), next_send, retry_count, port, sent));
+        }
+
+        //Back out anything we queued if the rest doesn't fit
+        let (pending_len, data_len) = (self.pending.len(), self.data.len());
+
+        for (header, payload, next_send, retry_count, port, sent) in packets {
+            if let Err(e) = self.push(header, &payload, port, next_send, sent) {
+                self.pending.truncate(pending_len);
+                self.data.truncate(data_len);
+                return Err(ImportError::Queue(e))
+            }
+
+            let idx = self.pending.len() - 1;
+            self.pending[idx].retry_count = retry_count;
+        }
+
+        trace!("Imported {} pending packets", count);
+
         Ok(())
     }
 
+    /// Random value in [0, 1) used for jitter
+    fn random(&mut self) -> f32 {
+        use rand::distributions::IndependentSample;
+        let range = rand::distributions::Range::new(0.0, 1.0);
+
+        match self.rng {
+            Some(ref mut rng) => range.ind_sample(rng),
+            None => range.ind_sample(&mut rand::thread_rng())
+        }
+    }
+
+    /// Gives up on every pending packet at once, discard is called for each one in the order they were queued
+    pub fn expire_all<D>(&mut self, mut discard: D) where D: FnMut(&frame::Frame, &[u8]) {
+        for pending in &self.pending {
+            info!("Expiring packet {}", pending.packet.prn);
+            discard(&pending.packet, self.get_packet_data(pending));
+        }
+
+        self.pending.clear();
+        self.data.clear();
+    }
+
     fn discard(&mut self, idx: usize) {
-        //Erase the data associated
-        let data_start = self.pending[idx].data_offset;
-        let data_end = data_start + self.pending[idx].data_size;
-        self.data.drain(data_start..data_end);
-        
         //Remove packet
-        self.pending.remove(idx);
+        let removed = self.pending.remove(idx);
 
-        //Update offsets
-        for packet in &mut self.pending {
-            if packet.data_offset >= data_end {
-                packet.data_offset -= data_end - data_start;
-            }
+        //Erase the data associated
+        self.data.drain(removed.data_offset..removed.data_offset+removed.data_size);
+
+        //Payloads are stored in the same order as pending packets so everything after the removed
+        //packet shifts down by its size
+        for packet in &mut self.pending[idx..] {
+            packet.data_offset -= removed.data_size;
         }
     }
 
@@ -185,15 +569,79 @@ impl Queue {
     pub fn pending_packets(&self) -> usize {
         self.pending.len()
     }
+
+    /// Details of every packet waiting for an ack, in the order they were sent
+    pub fn pending_info(&self) -> Vec<PendingInfo> {
+        self.pending.iter()
+            .map(|pending| PendingInfo {
+                prn: pending.packet.prn,
+                dest: routing::get_dest(&pending.packet.address_route),
+                retry_count: pending.retry_count,
+                size: pending.data_size,
+                next_send: pending.next_send
+            })
+            .collect()
+    }
+
+    /// Milliseconds until the next pending packet is due for a retry or discard, None if nothing is pending.
+    /// Packets held for a busy channel count too since tick sends them once they're due. See `Node::next_tick_deadline`.
+    pub fn next_deadline(&self) -> Option<usize> {
+        self.pending.iter().map(|pending| pending.next_send).min()
+    }
+
+    /// Bytes of payload waiting for an ack
+    pub fn buffered_bytes(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Checks if enough data is in flight that congestion control is underway
+    pub fn is_congested(&self) -> bool {
+        self.data.len() > CONGEST_CONTROL
+    }
+
+    /// Returns the new congestion state if it changed since the last time this was called
+    pub fn update_congestion(&mut self) -> Option<bool> {
+        let congested = self.is_congested();
+
+        if congested != self.congested {
+            if congested {
+                warn!("Congestion control started, {} bytes in flight", self.data.len());
+            } else {
+                info!("Congestion control stopped, {} bytes in flight", self.data.len());
+            }
+
+            self.congested = congested;
+            Some(congested)
+        } else {
+            None
+        }
+    }
+
+    /// Sets how load is shed under congestion control, defaults to `CongestionPolicy::DropDueRetries`
+    pub fn set_congestion_policy(&mut self, policy: CongestionPolicy) {
+        self.policy = policy;
+    }
+
+    /// Sets the maximum number of packets that can wait for an ack before enqueue starts rejecting them
+    pub fn set_max_packets(&mut self, max_packets: usize) {
+        self.max_packets = max_packets;
+    }
+
+    /// Sets how many times a packet is retried before it's discarded, defaults to `RETRY_COUNT` and capped at `MAX_RETRY_COUNT`
+    pub fn set_retry_count(&mut self, retry_count: usize) {
+        self.retry_count = cmp::min(retry_count, MAX_RETRY_COUNT);
+    }
+
+    /// Sets whether enqueuing a payload identical to one in flight on the same route is merged into the
+    /// existing packet instead of queuing a second copy, defaults to off
+    pub fn set_merge_duplicates(&mut self, merge: bool) {
+        self.merge_duplicates = merge;
+    }
 }
 
-#[cfg(test)]
-use std::io;
 #[cfg(test)]
 use spec::prn_id;
 #[cfg(test)]
-use spec::routing;
-#[cfg(test)]
 use spec::address;
 
 #[cfg(test)]
@@ -216,14 +664,30 @@ fn create_packet_with<T>(prn: &mut prn_id::PRN, data: T) -> (frame::Frame, Vec<u
     (header, data)
 }
 
+#[test]
+fn test_error_display() {
+    use std::error::Error;
+
+    assert_eq!(format!("{}", QueueError::Discarded), "Frame was discarded due to congestion control");
+    assert_eq!(format!("{}", QueueError::TooManyInFlight), "Too many frames are waiting for an ack");
+
+    assert_eq!(format!("{}", ImportError::UnsupportedVersion(2)), "Unsupported queue export version 2");
+    assert_eq!(format!("{}", ImportError::Truncated), "Queue export was truncated");
+    assert_eq!(format!("{}", ImportError::Frame(frame::ReadError::CRCFailure)), "Stored frame couldn't be parsed: Frame failed CRC check");
+
+    let err = ImportError::Queue(QueueError::TooManyInFlight);
+    assert_eq!(format!("{}", err), "Stored frame couldn't be queued: Too many frames are waiting for an ack");
+    assert_eq!(format!("{}", err.source().unwrap()), "Too many frames are waiting for an ack");
+}
+
 #[test]
 fn test_enqueue() {
     let mut prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
     let (header, data) = create_sample_packet(&mut prn, 256);
 
     let mut queue = new();
-    match queue.enqueue(header, &data) {
-        Ok(()) => (),
+    match queue.enqueue(header, &data, 0) {
+        Ok(_) => (),
         Err(_) => assert!(false)
     };
 
@@ -248,19 +712,20 @@ fn test_discard() {
         let iter = (0..1024).map(|_| i as u8);
         let (header, data) = create_packet_with(&mut prn, iter);
 
-        match queue.enqueue(header, &data) {
+        match queue.enqueue(header, &data, 0) {
             Err(_) => assert!(false),
-            Ok(()) => ()
+            Ok(_) => ()
         }
     }
 
     {
         let (header, data) = create_sample_packet(&mut prn, 1);
-        match queue.enqueue(header, &data) {
-            Ok(()) => assert!(false),
+        match queue.enqueue(header, &data, 0) {
+            Ok(_) => assert!(false),
             Err(e) => {
                 match e {
-                    QueueError::Discarded => ()
+                    QueueError::Discarded => (),
+                    _ => assert!(false)
                 }
             }
         }
@@ -272,8 +737,8 @@ fn test_discard() {
     {
         for _ in 0..4 {
             let (header, data) = create_sample_packet(&mut prn, 256);
-            match queue.enqueue(header, &data) {
-                Ok(()) => (),
+            match queue.enqueue(header, &data, 0) {
+                Ok(_) => (),
                 Err(_) => assert!(false)
             }
         }
@@ -281,8 +746,8 @@ fn test_discard() {
 
     {
         let (header, data) = create_sample_packet(&mut prn, 1);
-        match queue.enqueue(header, &data) {
-            Ok(()) => assert!(false),
+        match queue.enqueue(header, &data, 0) {
+            Ok(_) => assert!(false),
             Err(_) => ()
         }
     }
@@ -296,7 +761,7 @@ fn test_empty_tick() {
     let mut discard_count = 0;
 
     let result = queue.tick::<_,_,io::ErrorKind>(0, 
-        |_, _| {
+        |_, _, _, _| {
             retry_count += 1;
             Ok(())
         },
@@ -311,8 +776,13 @@ fn test_empty_tick() {
 
 #[test]
 fn test_tick_lifetime() {
+    use rand::{SeedableRng, XorShiftRng};
+    use rand::distributions::IndependentSample;
+
+    const SEED: [u32; 4] = [0x193a6754, 0xa8a7d469, 0x97830e05, 0x113ba7bb];
+
     let mut prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
-    let mut queue = new();
+    let mut queue = new_with_rng(XorShiftRng::from_seed(SEED));
     let (header, data) = create_sample_packet(&mut prn, 1);
 
     let header_prn = header.prn;
@@ -320,31 +790,37 @@ fn test_tick_lifetime() {
     let mut retry_count = 0;
     let mut discard_count = 0;
 
-    assert!(queue.enqueue(header, &data).is_ok());
+    assert!(queue.enqueue(header, &data, 0).is_ok());
 
-    //Calculate the maximum retry ms we need for a single packet to discard
-    fn calc_retry(count: usize) -> usize {
-        if count == 0 {
-            return RETRY_DELAY_MS
-        } else {
-            return (1+count) * RETRY_DELAY_MS + calc_retry(count-1)
-        }
-    }
+    //Mirror the queue's RNG so we know exactly when each retry is due
+    let mut expected_rng = XorShiftRng::from_seed(SEED);
+    let range = rand::distributions::Range::new(0.0, 1.0);
+    let mut next_send = RETRY_DELAY_MS;
 
-    //Force a retry and discard
-    for _ in 0..(calc_retry(RETRY_COUNT) / 50) + 1 {
-        let result = queue.tick::<_,_,io::ErrorKind>(50,
-            |header,_| {
+    for attempt in 0..RETRY_COUNT+1 {
+        //Nothing should happen right up until the deadline
+        queue.tick::<_,_,io::ErrorKind>(next_send - 1,
+            |_,_,_,_| {
+                assert!(false);
+                Ok(())
+            },
+            |_,_| assert!(false)).unwrap();
+
+        let expected_next = retry_delay(attempt + 1, range.ind_sample(&mut expected_rng));
+
+        queue.tick::<_,_,io::ErrorKind>(1,
+            |header,_,_,next_retry| {
                 assert_eq!(header.prn, header_prn);
+                assert_eq!(next_retry, expected_next);
                 retry_count += 1;
                 Ok(())
             },
             |header,_| {
                 assert_eq!(header.prn, header_prn);
                 discard_count += 1;
-            });
+            }).unwrap();
 
-        assert!(result.is_ok());
+        next_send = expected_next;
     }
 
     assert_eq!(retry_count, RETRY_COUNT);
@@ -356,32 +832,51 @@ fn test_tick_bad_io() {
     let mut prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
     let mut queue = new();
     let (header, data) = create_sample_packet(&mut prn, 1);
+    let (later_header, later_data) = create_sample_packet(&mut prn, 1);
+
+    const FAILED_WRITES: usize = 3;
 
+    let mut attempt_count = 0;
     let mut retry_count = 0;
     let mut discard_count = 0;
-
-    assert!(queue.enqueue(header, &data).is_ok());
-
-    //Force all packets to try and eventually discard
-    for _ in 0..RETRY_COUNT+1 {
-        let is_discard = retry_count == RETRY_COUNT;
-
-        let result = queue.tick(RETRY_DELAY_MS * (1 + RETRY_COUNT),
-            |_,_| {
-                retry_count += 1;
-                Err(io::ErrorKind::NotConnected)
+    let mut error_count = 0;
+
+    assert!(queue.enqueue(header, &data, 0).is_ok());
+    queue.tick::<_,_,io::ErrorKind>(RETRY_DELAY_MS / 2, |_,_,_,_| Ok(()), |_,_| {}).unwrap();
+    assert!(queue.enqueue(later_header, &later_data, 0).is_ok());
+
+    //The failed packet stays due without using up a retry, packets after it still have the elapsed time taken off
+    let result = queue.tick(RETRY_DELAY_MS / 2, |_,_,_,_| Err(io::ErrorKind::NotConnected), |_,_| {});
+    assert_eq!(result, Err(io::ErrorKind::NotConnected));
+    assert_eq!(queue.pending_info()[0].retry_count, 0);
+    assert_eq!(queue.next_deadline(), Some(0));
+    assert!(queue.ack_recv(header.prn));
+    assert_eq!(queue.next_deadline(), Some(RETRY_DELAY_MS / 2));
+
+    //First few writes fail, those shouldn't count against the packet's retries
+    while queue.pending_packets() > 0 {
+        let result = queue.tick(RETRY_DELAY_MS << RETRY_COUNT,
+            |_,_,_,_| {
+                attempt_count += 1;
+
+                if attempt_count <= FAILED_WRITES {
+                    Err(io::ErrorKind::NotConnected)
+                } else {
+                    retry_count += 1;
+                    Ok(())
+                }
             },
             |_,_| {
                 discard_count += 1;
             });
 
-        if !is_discard {
-            assert!(result.is_err());
-        } else {
-            assert!(result.is_ok());
+        if result.is_err() {
+            error_count += 1;
         }
     }
 
+    assert_eq!(error_count, FAILED_WRITES);
+    assert_eq!(attempt_count, FAILED_WRITES + RETRY_COUNT);
     assert_eq!(retry_count, RETRY_COUNT);
     assert_eq!(discard_count, 1);
 }
@@ -394,7 +889,7 @@ fn test_discard_mixed() {
     let mut queue = new();
 
     for &(ref header, ref data) in &packets {
-        queue.enqueue(*header, data).unwrap();
+        queue.enqueue(*header, data, 0).unwrap();
     }
 
     assert_eq!(queue.data.len(), queue.pending.len() * 8);
@@ -413,6 +908,42 @@ fn test_discard_mixed() {
     }
 }
 
+#[test]
+fn test_ack_out_of_order() {
+    let mut prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
+
+    //Vary size and content so a stale offset can't line up by accident
+    let packets = (0..5)
+        .map(|i| create_packet_with(&mut prn, (0..(i+1)*7).map(|x| (x + i * 50) as u8)))
+        .collect::<Vec<_>>();
+
+    let mut queue = new();
+
+    for &(ref header, ref data) in &packets {
+        queue.enqueue(*header, data, 0).unwrap();
+    }
+
+    assert!(queue.ack_recv(packets[1].0.prn));
+    assert!(queue.ack_recv(packets[3].0.prn));
+
+    assert_eq!(queue.pending_packets(), 3);
+    assert_eq!(queue.data.len(), packets[0].1.len() + packets[2].1.len() + packets[4].1.len());
+
+    let mut retried = vec!();
+    queue.tick::<_,_,io::ErrorKind>(RETRY_DELAY_MS,
+        |header, data, _, _| {
+            retried.push((header.prn, data.to_vec()));
+            Ok(())
+        },
+        |_,_| assert!(false)).unwrap();
+
+    let expected = [&packets[0], &packets[2], &packets[4]].iter()
+        .map(|&&(ref header, ref data)| (header.prn, data.clone()))
+        .collect::<Vec<_>>();
+
+    assert_eq!(retried, expected);
+}
+
 #[test]
 fn test_multi_ack() {
     let mut prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
@@ -423,11 +954,11 @@ fn test_multi_ack() {
 
     //Add all the ack and discard packets
     for &(ref header, ref data) in &discard {
-        queue.enqueue(*header, data).unwrap();
+        queue.enqueue(*header, data, 0).unwrap();
     }
 
     for &(ref header, ref data) in &ack {
-        queue.enqueue(*header, data).unwrap();
+        queue.enqueue(*header, data, 0).unwrap();
     }
 
     let mut discard_count = 0;
@@ -437,7 +968,7 @@ fn test_multi_ack() {
         queue.ack_recv(header.prn);
 
         let result = queue.tick::<_,_,io::ErrorKind>(1,
-            |_,_| {
+            |_,_,_,_| {
                 Ok(())
             },
             |_,_| {
@@ -449,8 +980,8 @@ fn test_multi_ack() {
 
     //Time out the discard packets
     for _ in 0..RETRY_COUNT+1 {
-        queue.tick::<_,_,io::ErrorKind>(RETRY_DELAY_MS * (1 + RETRY_COUNT),
-            |_,_| {
+        queue.tick::<_,_,io::ErrorKind>(RETRY_DELAY_MS << RETRY_COUNT,
+            |_,_,_,_| {
                 Ok(())
             },
             |header, data| {
@@ -472,14 +1003,18 @@ fn test_congestion() {
     let packets = (0..40).map(|i| create_packet_with(&mut prn, (0..1024).map(|_| i as u8))).collect::<Vec<_>>();
 
     for (header, data) in packets {
-        queue.enqueue(header, &data).unwrap();
+        queue.enqueue(header, &data, 0).unwrap();
     }
 
+    assert!(queue.is_congested());
+    assert_eq!(queue.update_congestion(), Some(true));
+    assert_eq!(queue.update_congestion(), None);
+
     let mut retry_count = 0;
     let mut discard_count = 0;
 
     queue.tick::<_,_,io::ErrorKind>(RETRY_DELAY_MS,
-        |_,_| {
+        |_,_,_,_| {
             retry_count += 1;
             Ok(())
         },
@@ -491,4 +1026,429 @@ fn test_congestion() {
     
     //Only 5 should discard before we drop out of congestion control
     assert_eq!(discard_count, 5);
-}
\ No newline at end of file
+
+    assert!(!queue.is_congested());
+    assert_eq!(queue.update_congestion(), Some(false));
+    assert_eq!(queue.update_congestion(), None);
+}
+
+#[test]
+fn test_congestion_drop_oldest() {
+    let mut prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
+    let mut queue = new();
+    queue.set_congestion_policy(CongestionPolicy::DropOldest);
+
+    let packets = (0..40).map(|i| create_packet_with(&mut prn, (0..1024).map(|_| i as u8))).collect::<Vec<_>>();
+
+    for &(header, ref data) in packets.iter() {
+        queue.enqueue(header, data, 0).unwrap();
+    }
+
+    assert!(queue.is_congested());
+
+    //Nothing is due yet but the oldest go anyway
+    let mut discarded = vec!();
+    queue.tick::<_,_,io::ErrorKind>(0,
+        |_,_,_,_| {
+            assert!(false);
+            Ok(())
+        },
+        |header,_| discarded.push(header.prn)).unwrap();
+
+    assert_eq!(discarded, packets[..5].iter().map(|&(header, _)| header.prn).collect::<Vec<_>>());
+    assert!(!queue.is_congested());
+
+    //Everything left retries without being discarded
+    let mut retry_count = 0;
+    queue.tick::<_,_,io::ErrorKind>(RETRY_DELAY_MS,
+        |_,_,_,_| {
+            retry_count += 1;
+            Ok(())
+        },
+        |_,_| assert!(false)).unwrap();
+
+    assert_eq!(retry_count, 35);
+}
+
+#[test]
+fn test_congestion_reject_new() {
+    let mut prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
+    let mut queue = new();
+    queue.set_congestion_policy(CongestionPolicy::RejectNew);
+
+    let packets = (0..40).map(|i| create_packet_with(&mut prn, (0..1024).map(|_| i as u8))).collect::<Vec<_>>();
+
+    //Accepted until we cross the congestion threshold
+    let accepted = packets.iter()
+        .filter(|&&(header, ref data)| queue.enqueue(header, data, 0).is_ok())
+        .count();
+
+    assert_eq!(accepted, CONGEST_CONTROL / 1024 + 1);
+    assert!(queue.is_congested());
+
+    //Nothing queued is discarded for congestion
+    let mut retry_count = 0;
+    queue.tick::<_,_,io::ErrorKind>(RETRY_DELAY_MS,
+        |_,_,_,_| {
+            retry_count += 1;
+            Ok(())
+        },
+        |_,_| assert!(false)).unwrap();
+
+    assert_eq!(retry_count, accepted);
+    assert_eq!(queue.pending_packets(), accepted);
+}
+
+#[test]
+fn test_retry_delay() {
+    use rand::distributions::IndependentSample;
+
+    let range = rand::distributions::Range::new(0.0, 1.0);
+    let mut rng = rand::thread_rng();
+
+    for retry_count in 1..RETRY_COUNT+1 {
+        let interval = RETRY_DELAY_MS << retry_count;
+
+        assert_eq!(retry_delay(retry_count, 0.0), interval / 2);
+        assert!(retry_delay(retry_count, 0.999) <= interval);
+
+        let (min, max) = (0..10000)
+            .map(|_| retry_delay(retry_count, range.ind_sample(&mut rng)))
+            .fold((usize::max_value(), 0), |(min, max), delay| {
+                assert!(delay >= interval / 2);
+                assert!(delay <= interval);
+
+                (::std::cmp::min(min, delay), ::std::cmp::max(max, delay))
+            });
+
+        //Samples should cover the whole window, not cluster at the floor
+        assert!(min < interval / 2 + interval / 20);
+        assert!(max > interval - interval / 20);
+    }
+}
+
+#[test]
+fn test_max_in_flight() {
+    let mut prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
+    let mut queue = new();
+
+    for _ in 0..MAX_PACKET {
+        let (header, data) = create_sample_packet(&mut prn, 1);
+        queue.enqueue(header, &data, 0).unwrap();
+    }
+
+    let (header, data) = create_sample_packet(&mut prn, 1);
+    match queue.enqueue(header, &data, 0) {
+        Err(QueueError::TooManyInFlight) => (),
+        _ => assert!(false)
+    }
+
+    assert_eq!(queue.pending_packets(), MAX_PACKET);
+
+    //Acking one frees a slot
+    let first_prn = queue.pending[0].packet.prn;
+    queue.ack_recv(first_prn);
+    queue.enqueue(header, &data, 0).unwrap();
+
+    //Lowering the limit applies to the next enqueue
+    queue.set_max_packets(4);
+    let (header, data) = create_sample_packet(&mut prn, 1);
+    match queue.enqueue(header, &data, 0) {
+        Err(QueueError::TooManyInFlight) => (),
+        _ => assert!(false)
+    }
+}
+
+#[test]
+fn test_next_deadline() {
+    let mut prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
+    let mut queue = new();
+
+    assert_eq!(queue.next_deadline(), None);
+
+    let (header, data) = create_sample_packet(&mut prn, 8);
+    queue.enqueue(header, &data, 0).unwrap();
+    assert_eq!(queue.next_deadline(), Some(RETRY_DELAY_MS));
+
+    queue.tick::<_,_,io::ErrorKind>(100, |_,_,_,_| Ok(()), |_,_| {}).unwrap();
+    assert_eq!(queue.next_deadline(), Some(RETRY_DELAY_MS - 100));
+
+    //A newer packet isn't due as soon
+    let (second, data) = create_sample_packet(&mut prn, 8);
+    queue.enqueue(second, &data, 0).unwrap();
+    assert_eq!(queue.next_deadline(), Some(RETRY_DELAY_MS - 100));
+
+    //Once the first retries the second one is next
+    let mut next_retry = 0;
+    queue.tick::<_,_,io::ErrorKind>(RETRY_DELAY_MS - 100, |_,_,_,next| {
+        next_retry = next;
+        Ok(())
+    }, |_,_| {}).unwrap();
+    assert_eq!(queue.next_deadline(), Some(::std::cmp::min(next_retry, 100)));
+
+    queue.ack_recv(header.prn);
+    queue.ack_recv(second.prn);
+    assert_eq!(queue.next_deadline(), None);
+}
+
+#[test]
+fn test_deferred() {
+    let mut prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
+    let mut queue = new();
+
+    let (header, data) = create_sample_packet(&mut prn, 8);
+    queue.enqueue_deferred(header, &data, 2).unwrap();
+
+    let delay = queue.next_deadline().unwrap();
+    assert!(delay >= SLOT_TIME_MS && delay < SLOT_TIME_MS * 2);
+
+    //Holding pushes it back another slot once it comes due
+    queue.hold(delay);
+    let delay = queue.next_deadline().unwrap();
+    assert!(delay >= SLOT_TIME_MS && delay < SLOT_TIME_MS * 2);
+
+    //Tick never retries or discards something that hasn't gone out
+    queue.tick::<_,_,io::ErrorKind>(RETRY_DELAY_MS << RETRY_COUNT, |_,_,_,_| { assert!(false); Ok(()) }, |_,_| assert!(false)).unwrap();
+    assert_eq!(queue.pending_packets(), 1);
+
+    let mut sent = 0;
+    queue.send_deferred::<_,io::ErrorKind>(|sent_header, sent_data, port| {
+        sent += 1;
+        assert_eq!(sent_header.prn, header.prn);
+        assert_eq!(sent_data, &data[..]);
+        assert_eq!(port, 2);
+        Ok(())
+    }).unwrap();
+    assert_eq!(sent, 1);
+    assert_eq!(queue.next_deadline(), Some(RETRY_DELAY_MS));
+
+    //Only sent once
+    queue.send_deferred::<_,io::ErrorKind>(|_,_,_| { assert!(false); Ok(()) }).unwrap();
+}
+
+#[test]
+fn test_export_import() {
+    let mut prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
+    let mut queue = new();
+
+    let packets = (0..4).map(|i| create_sample_packet(&mut prn, 16 * (i + 1))).collect::<Vec<_>>();
+    for (i, &(header, ref data)) in packets.iter().enumerate() {
+        queue.enqueue(header, data, i as u8).unwrap();
+    }
+
+    //Get some retry state in there
+    queue.tick::<_,_,io::ErrorKind>(RETRY_DELAY_MS, |_,_,_,_| Ok(()), |_,_| {}).unwrap();
+    let (header, data) = create_sample_packet(&mut prn, 8);
+    queue.enqueue(header, &data, 0).unwrap();
+
+    let exported = queue.export();
+
+    let mut imported = new();
+    imported.import(&exported).unwrap();
+    assert_eq!(imported.pending_packets(), queue.pending_packets());
+    assert_eq!(imported.data, queue.data);
+
+    for (original, restored) in queue.pending.iter().zip(imported.pending.iter()) {
+        assert_eq!(original.packet, restored.packet);
+        assert_eq!(original.next_send, restored.next_send);
+        assert_eq!(original.retry_count, restored.retry_count);
+        assert_eq!(original.port, restored.port);
+        assert_eq!(original.data_size, restored.data_size);
+    }
+
+    //Both retry the same packets
+    let mut retried = vec!();
+    queue.tick::<_,_,io::ErrorKind>(RETRY_DELAY_MS << 1, |header, data, port, _| {
+        retried.push((header.prn, data.to_vec(), port));
+        Ok(())
+    }, |_,_| {}).unwrap();
+
+    let mut restored_retried = vec!();
+    imported.tick::<_,_,io::ErrorKind>(RETRY_DELAY_MS << 1, |header, data, port, _| {
+        restored_retried.push((header.prn, data.to_vec(), port));
+        Ok(())
+    }, |_,_| {}).unwrap();
+
+    assert_eq!(retried.len(), packets.len() + 1);
+    assert_eq!(retried, restored_retried);
+
+    match new().import(&exported[..exported.len() - 1]) {
+        Err(ImportError::Truncated) => (),
+        _ => assert!(false)
+    }
+
+    let mut bad_version = exported.clone();
+    bad_version[3] = 0xFF;
+    match new().import(&bad_version) {
+        Err(ImportError::UnsupportedVersion(0xFF)) => (),
+        _ => assert!(false)
+    }
+
+    //A bad tail doesn't leave the packets before it queued
+    let mut partial = new();
+    let (header, data) = create_sample_packet(&mut prn, 8);
+    partial.enqueue(header, &data, 0).unwrap();
+    let before = partial.data.clone();
+
+    match partial.import(&exported[..exported.len() - 1]) {
+        Err(ImportError::Truncated) => (),
+        _ => assert!(false)
+    }
+    assert_eq!(partial.pending_packets(), 1);
+    assert_eq!(partial.data, before);
+
+    //Neither does running out of room part way through
+    partial.set_max_packets(3);
+    match partial.import(&exported) {
+        Err(ImportError::Queue(QueueError::TooManyInFlight)) => (),
+        _ => assert!(false)
+    }
+    assert_eq!(partial.pending_packets(), 1);
+    assert_eq!(partial.data, before);
+}
+
+#[test]
+fn test_pending_info() {
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let dest = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+    let mut queue = new();
+
+    assert_eq!(queue.pending_info(), vec!());
+
+    let first = frame::new_header(&mut prn, [callsign, dest, routing::ADDRESS_SEPARATOR, callsign].iter().cloned()).unwrap();
+    queue.enqueue(first, &[1, 2, 3], 0).unwrap();
+    queue.tick::<_,_,io::ErrorKind>(RETRY_DELAY_MS, |_,_,_,_| Ok(()), |_,_| {}).unwrap();
+
+    let (second, data) = create_sample_packet(&mut prn, 32);
+    queue.enqueue(second, &data, 0).unwrap();
+
+    let info = queue.pending_info();
+    assert_eq!(info.len(), 2);
+
+    assert_eq!(info[0].prn, first.prn);
+    assert_eq!(info[0].dest, dest);
+    assert_eq!(info[0].retry_count, 1);
+    assert_eq!(info[0].size, 3);
+    assert_eq!(info[0].next_send, queue.pending[0].next_send);
+
+    assert_eq!(info[1], PendingInfo {
+        prn: second.prn,
+        dest: callsign,
+        retry_count: 0,
+        size: 32,
+        next_send: RETRY_DELAY_MS
+    });
+}
+
+#[test]
+fn test_merge_duplicates() {
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let dest = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+    let route = [dest, routing::ADDRESS_SEPARATOR, callsign];
+
+    //Off by default, both copies are queued
+    let mut queue = new();
+    let first = frame::new_header(&mut prn, route.iter().cloned()).unwrap();
+    let second = frame::new_header(&mut prn, route.iter().cloned()).unwrap();
+    queue.enqueue(first, &[1, 2, 3], 0).unwrap();
+    queue.enqueue(second, &[1, 2, 3], 0).unwrap();
+    assert_eq!(queue.pending_info().len(), 2);
+    assert!(queue.find_duplicate(&first.address_route, &[1, 2, 3]).is_none());
+
+    let mut queue = new();
+    queue.set_merge_duplicates(true);
+    assert_eq!(queue.enqueue(first, &[1, 2, 3], 0).unwrap().prn, first.prn);
+    assert_eq!(queue.find_duplicate(&second.address_route, &[1, 2, 3]).map(|f| f.prn), Some(first.prn));
+
+    //Second copy merges into the first rather than queuing
+    assert_eq!(queue.enqueue(second, &[1, 2, 3], 0).unwrap().prn, first.prn);
+    assert_eq!(queue.enqueue_deferred(second, &[1, 2, 3], 0).unwrap().prn, first.prn);
+
+    let info = queue.pending_info();
+    assert_eq!(info.len(), 1);
+    assert_eq!(info[0].prn, first.prn);
+    assert!(!queue.ack_recv(second.prn));
+
+    //Different payload or route is a new packet
+    assert!(queue.find_duplicate(&first.address_route, &[1, 2, 4]).is_none());
+    let other = frame::new_header(&mut prn, [callsign, routing::ADDRESS_SEPARATOR, dest].iter().cloned()).unwrap();
+    queue.enqueue(other, &[1, 2, 3], 0).unwrap();
+    queue.enqueue(second, &[3, 2, 1], 0).unwrap();
+    assert_eq!(queue.pending_info().len(), 3);
+
+    //Once acked the payload can be sent again
+    assert!(queue.ack_recv(first.prn));
+    assert!(queue.find_duplicate(&first.address_route, &[1, 2, 3]).is_none());
+}
+
+#[test]
+fn test_ack_rtt() {
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+    let mut queue = new();
+
+    queue.tick::<_,_,io::ErrorKind>(1000, |_,_,_,_| Ok(()), |_,_| {}).unwrap();
+
+    let (first, data) = create_sample_packet(&mut prn, 16);
+    queue.enqueue(first, &data, 0).unwrap();
+    queue.tick::<_,_,io::ErrorKind>(120, |_,_,_,_| Ok(()), |_,_| {}).unwrap();
+
+    let (second, data) = create_sample_packet(&mut prn, 16);
+    queue.enqueue(second, &data, 0).unwrap();
+    queue.tick::<_,_,io::ErrorKind>(30, |_,_,_,_| Ok(()), |_,_| {}).unwrap();
+
+    //Only time since each packet was queued counts
+    assert_eq!(queue.ack_recv_rtt(second.prn), Some(30));
+    assert_eq!(queue.ack_recv_rtt(first.prn), Some(150));
+    assert_eq!(queue.ack_recv_rtt(first.prn), None);
+}
+
+#[test]
+fn test_expire_all() {
+    let callsign = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let mut prn = prn_id::new(callsign);
+    let mut queue = new();
+
+    let packets = (0..3).map(|i| create_sample_packet(&mut prn, 8 + i)).collect::<Vec<_>>();
+    for &(ref header, ref data) in &packets {
+        queue.enqueue(*header, data, 0).unwrap();
+    }
+
+    let mut discarded = vec!();
+    queue.expire_all(|header, data| discarded.push((*header, data.to_vec())));
+    assert_eq!(discarded, packets);
+    assert_eq!(queue.pending_packets(), 0);
+    assert_eq!(queue.data.len(), 0);
+
+    //Nothing left to retry or discard
+    queue.tick::<_,_,io::ErrorKind>(RETRY_DELAY_MS << RETRY_COUNT, |_,_,_,_| { assert!(false); Ok(()) }, |_,_| assert!(false)).unwrap();
+}
+
+#[test]
+fn test_max_retry_count() {
+    let mut prn = prn_id::new(address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap());
+    let mut queue = new();
+    queue.set_retry_count(usize::max_value());
+
+    let (header, data) = create_sample_packet(&mut prn, 1);
+    queue.enqueue(header, &data, 0).unwrap();
+
+    //Longest backoff still fits and the packet is discarded after the capped number of retries
+    let mut retry_count = 0;
+    let mut discarded = false;
+    while queue.pending_packets() > 0 {
+        queue.tick::<_,_,io::ErrorKind>(RETRY_DELAY_MS << MAX_RETRY_COUNT,
+            |_,_,_,next_send| {
+                assert!(next_send <= RETRY_DELAY_MS << MAX_RETRY_COUNT);
+                retry_count += 1;
+                Ok(())
+            },
+            |_,_| discarded = true).unwrap();
+    }
+
+    assert_eq!(retry_count, MAX_RETRY_COUNT);
+    assert!(discarded);
+    assert!(MAX_RETRY_COUNT <= u8::max_value() as usize);
+}
@@ -1,32 +1,67 @@
 //! Pseudo-Random number generator for packet identification
+use spec::frame;
 
 /// Pseudo-Random packet id generation
 pub struct PRN {
     /// Current PRN value
     pub current: u32,
     /// Callsign that is used to XOR for a unique sequence
-    pub callsign: u32
+    pub callsign: u32,
+    /// Mask of the register bits that feed back into the LFSR
+    taps: u32
 }
 
 /// Value type for actual prn values
 pub type PrnValue = u32;
 
+/// Tap positions of the NBP 4-tap poly in the form of 1 + x^25 + x^26 + x^30 + x^32
+pub const NBP_TAPS: [u8; 4] = [25, 26, 30, 32];
+
+/// Converts tap positions into the mask of register bits they read
+fn taps_to_mask(taps: &[u8]) -> u32 {
+    taps.iter().fold(0, |mask, tap| mask | (1 << (32 - *tap as u32)))
+}
+
 /// Creates new PRN id from an existing callsign
 pub fn new(callsign: u32) -> PRN {
     PRN {
         current: 0xFFFFFFFF,
-        callsign: callsign
+        callsign: callsign,
+        taps: taps_to_mask(&NBP_TAPS)
     }
 }
 
+/// Creates new PRN id from an existing callsign using an alternate LFSR poly given by its tap positions(1-32).
+/// Returns None if a tap is out of range or x^32 isn't included, since the register wouldn't use all of its bits.
+/// Note that this doesn't check that the poly is primitive, a poly that isn't will repeat before the full period.
+pub fn new_with_poly(callsign: u32, taps: &[u8]) -> Option<PRN> {
+    if taps.iter().any(|tap| *tap == 0 || *tap > 32) || !taps.contains(&32) {
+        warn!("Invalid LFSR taps {:?}", taps);
+        return None
+    }
+
+    Some(PRN {
+        current: 0xFFFFFFFF,
+        callsign: callsign,
+        taps: taps_to_mask(taps)
+    })
+}
+
 impl PRN {
     /// Generates a new packet id value from the previous packet id.
     pub fn next(&mut self) -> PrnValue {
-        //use a 4-tap poly in the form of 1 + x^25 + x^26 + x^30 + x^32
-        let bit = ((self.current >> (32-25)) ^ (self.current >> (32-26)) ^ (self.current >> (32-30)) ^ (self.current >> (32-32))) & 0x1;
+        loop {
+            //XOR of every tapped bit, defaults to the NBP poly 1 + x^25 + x^26 + x^30 + x^32
+            let bit = (self.current & self.taps).count_ones() & 0x1;
+
+            //Shift every bit down, insert newly generated bit at the top
+            self.current = (self.current >> 1) | (bit << 31);
 
-        //Shift every bit down, insert newly generated bit at the top
-        self.current = (self.current >> 1) | (bit << 31);
+            //Skip ids that a versioned frame parser would mistake for a version marker
+            if !frame::is_version_marker(self.current()) {
+                break
+            }
+        }
 
         //Make sure to return a unique id by XORing with callsign
         self.current()
@@ -96,4 +131,50 @@ fn test_seed() {
     assert_eq!(initial, repeat);
     assert!(initial != different);
     assert!(repeat != different);
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_skip_version_marker() {
+    let callsign = address::encode(['K', 'I' ,'7', 'E', 'S', 'T', '0']).unwrap();
+    let marker = (frame::VERSION_MAGIC | (frame::VERSION_CURRENT as u32) << 4) ^ callsign;
+
+    //Seed so the next shift would land on the marker with either top bit
+    for low_bit in 0..2 {
+        let mut prn = new(callsign);
+        prn.seed((marker << 1) | low_bit);
+        assert!(!frame::is_version_marker(prn.next()));
+    }
+}
+
+#[cfg(test)]
+fn steps_to_repeat(prn: &mut PRN, max_steps: usize) -> Option<usize> {
+    let start = prn.current;
+
+    (1..max_steps+1).find(|_| {
+        prn.next();
+        prn.current == start
+    })
+}
+
+#[test]
+fn test_poly() {
+    let callsign = address::encode(['K', 'I' ,'7', 'E', 'S', 'T', '0']).unwrap();
+
+    //Explicit NBP taps should match the default sequence
+    let mut default = new(callsign);
+    let mut explicit = new_with_poly(callsign, &NBP_TAPS).unwrap();
+    for _ in 0..1024 {
+        assert_eq!(default.next(), explicit.next());
+    }
+
+    assert!(new_with_poly(callsign, &[25, 26, 30]).is_none());
+    assert!(new_with_poly(callsign, &[0, 32]).is_none());
+    assert!(new_with_poly(callsign, &[33, 32]).is_none());
+
+    //Full period is 2^32 - 1 which is too long to walk here, make sure we don't cycle early
+    const STEPS: usize = 1 << 20;
+    assert_eq!(steps_to_repeat(&mut new(callsign), STEPS), None);
+
+    //While a poly that isn't primitive is caught by the same check
+    assert!(steps_to_repeat(&mut new_with_poly(callsign, &[16, 32]).unwrap(), STEPS).is_some());
+}
@@ -1,4 +1,8 @@
 ///! Address routing functions
+use std::cmp;
+use std::iter;
+use std::fmt;
+use std::error;
 use spec::address;
 
 ///Separater value to determine where we are in the routing path
@@ -10,13 +14,92 @@ pub const BROADCAST_ADDRESS: u32 = 0xFFFFFFFF;
 /// Maximum amount of addresses in a route
 pub const MAX_LENGTH: usize = 17;
 
+/// Maximum number of hops a sender can list, leaves room for the separator and sender's callsign
+pub const MAX_HOPS: usize = MAX_LENGTH - 2;
+
 ///Route of a packet, allows for 16 callsigns + separator to denote where the packet is in its routing
 pub type Route = [u32; MAX_LENGTH];
 
 #[derive(Debug)]
 pub enum ParseError {
     /// Route has a bad format
-    BadFormat
+    BadFormat,
+    /// Route has more addresses than fit in a packet
+    TooLong,
+    /// Route didn't have exactly one separator
+    SeparatorCount(usize)
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::BadFormat => write!(f, "Route has a bad format"),
+            ParseError::TooLong => write!(f, "Route has more than {} addresses", MAX_LENGTH),
+            ParseError::SeparatorCount(count) => write!(f, "Route has {} separators, expected 1", count)
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// Assembles a route hop by hop, validating it when built
+pub struct RouteBuilder {
+    addrs: Vec<u32>,
+    separators: usize,
+    invalid_hop: bool
+}
+
+/// Constructs an empty route builder
+pub fn builder() -> RouteBuilder {
+    RouteBuilder {
+        addrs: vec!(),
+        separators: 0,
+        invalid_hop: false
+    }
+}
+
+impl RouteBuilder {
+    /// Appends an address to the route, addresses before the separator are the forward path
+    pub fn hop(mut self, addr: u32) -> RouteBuilder {
+        self.invalid_hop = self.invalid_hop || addr == ADDRESS_SEPARATOR;
+        self.addrs.push(addr);
+        self
+    }
+
+    /// Marks the end of the forward path, addresses after this are the return path
+    pub fn separator(mut self) -> RouteBuilder {
+        self.separators += 1;
+        self.addrs.push(ADDRESS_SEPARATOR);
+        self
+    }
+
+    /// Validates the route and pads the remainder with zeros
+    pub fn build(self) -> Result<Route, ParseError> {
+        if self.invalid_hop {
+            return Err(ParseError::BadFormat)
+        }
+
+        if self.separators != 1 {
+            return Err(ParseError::SeparatorCount(self.separators))
+        }
+
+        //Same as a parsed frame, there needs to be somewhere to send it and someone it came from
+        let forward = self.addrs.iter().position(|addr| *addr == ADDRESS_SEPARATOR).unwrap_or(0);
+        if forward == 0 || forward + 1 == self.addrs.len() {
+            return Err(ParseError::BadFormat)
+        }
+
+        if forward > MAX_LENGTH - 1 || self.addrs.len() > MAX_LENGTH {
+            return Err(ParseError::TooLong)
+        }
+
+        let mut route: Route = [0; MAX_LENGTH];
+        for (idx, addr) in self.addrs.into_iter().enumerate() {
+            route[idx] = addr;
+        }
+
+        Ok(route)
+    }
 }
 
 /// Determines if a route has this node as it's current hop
@@ -45,6 +128,49 @@ pub fn get_source(route: &Route) -> u32 {
     return ADDRESS_SEPARATOR
 }
 
+/// Gets the final destination of the forward path
+pub fn get_dest(route: &Route) -> u32 {
+    match forward_len(route) {
+        0 => ADDRESS_SEPARATOR,
+        len => route[len - 1]
+    }
+}
+
+/// Number of hops left in the forward path, including the current one
+pub fn forward_len(route: &Route) -> usize {
+    match route.iter().position(|addr| *addr == ADDRESS_SEPARATOR) {
+        Some(idx) => idx,
+        None => route.len()
+    }
+}
+
+/// Synchronized PRN for a broadcast frame, the original PRN XOR'd with every address in the forward path.
+/// Every station that hears the same frame computes the same value so monitoring tools can match up broadcast
+/// copies. Relays in this implementation still forward with the original PRN since the sender acks against it.
+pub fn broadcast_prn(original_prn: u32, route: &Route) -> u32 {
+    route[..forward_len(route)].iter().fold(original_prn, |prn, addr| prn ^ *addr)
+}
+
+/// Checks if two routes are the same end to end path regardless of how far along each has been relayed.
+///
+/// Compares the unordered endpoints(source and final destination) and the multiset of every address in the
+/// route. Since the endpoints are unordered an ack's route matches the frame it acks. Broadcast hops that a
+/// relay has filled in with its own address won't match the unrelayed route.
+pub fn same_path(a: &Route, b: &Route) -> bool {
+    let endpoints = |route: &Route| {
+        let (src, dest) = (get_source(route), get_dest(route));
+        (cmp::min(src, dest), cmp::max(src, dest))
+    };
+
+    let addrs = |route: &Route| {
+        let mut addrs = route.iter().cloned().filter(|addr| *addr != ADDRESS_SEPARATOR).collect::<Vec<_>>();
+        addrs.sort();
+        addrs
+    };
+
+    endpoints(a) == endpoints(b) && addrs(a) == addrs(b)
+}
+
 /// Advances the route with our address(in case we had a broadcast address)
 pub fn advance(route: &Route, this_addr: u32) -> Result<Route, ParseError> {
     let sep_idx = match route.iter().position(|addr| *addr == ADDRESS_SEPARATOR) {
@@ -94,14 +220,29 @@ pub fn format_route(route: &[u32; 17]) -> String {
         })
 }
 
-/// Takes a route and reverse it
+/// Takes a route and reverse it so it leads back to the source.
+///
+/// The return path is already ordered nearest hop first so it becomes the new forward path, the remaining
+/// forward path is reversed to become the new return path. This keeps a single separator between them so
+/// the reversed route can be advanced hop by hop.
 pub fn reverse(route: &[u32; 17]) -> [u32; 17] {
-    let reversed = route.iter().rev()
-        .skip_while(|addr| **addr == ADDRESS_SEPARATOR);
-
     let mut new_route: [u32; 17] = [0; 17];
 
-    for (idx, addr) in reversed.enumerate() {
+    let sep_idx = match route.iter().position(|addr| *addr == ADDRESS_SEPARATOR) {
+        Some(idx) => idx,
+        None => route.len()
+    };
+
+    let return_path = route[cmp::min(sep_idx+1, route.len())..].iter()
+        .take_while(|addr| **addr != ADDRESS_SEPARATOR);
+
+    let forward_path = route[..sep_idx].iter().rev();
+
+    let reversed = return_path
+        .chain(iter::once(&ADDRESS_SEPARATOR))
+        .chain(forward_path);
+
+    for (idx, addr) in reversed.take(new_route.len()).enumerate() {
         new_route[idx] = *addr;
     }
 
@@ -126,19 +267,169 @@ pub fn gen_route<'a, T>(route: T) -> Route where T: IntoIterator<Item=&'a u32> {
     final_route
 }
 
+#[test]
+fn test_broadcast_prn() {
+    let src = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let dest = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+    let route = gen_route(&[BROADCAST_ADDRESS, dest, ADDRESS_SEPARATOR, src]);
+    let prn = 0x12345678;
+
+    let mut expected = prn;
+    for addr in route.iter().take_while(|addr| **addr != ADDRESS_SEPARATOR) {
+        expected ^= *addr;
+    }
+
+    assert_eq!(broadcast_prn(prn, &route), expected);
+    assert_eq!(broadcast_prn(prn, &route), prn ^ BROADCAST_ADDRESS ^ dest);
+
+    //Return path doesn't change it
+    let other_src = gen_route(&[BROADCAST_ADDRESS, dest, ADDRESS_SEPARATOR, dest]);
+    assert_eq!(broadcast_prn(prn, &route), broadcast_prn(prn, &other_src));
+}
+
+#[test]
+fn test_parse_error_display() {
+    assert_eq!(format!("{}", ParseError::BadFormat), "Route has a bad format");
+    assert_eq!(format!("{}", ParseError::TooLong), "Route has more than 17 addresses");
+    assert_eq!(format!("{}", ParseError::SeparatorCount(2)), "Route has 2 separators, expected 1");
+}
+
+#[test]
+fn test_route_builder() {
+    let route = builder().hop(1).hop(2).separator().hop(3).build().unwrap();
+    assert_eq!(route, gen_route(&[1, 2, 0, 3]));
+
+    //Full forward path with the source in the last slot
+    let full = (1..MAX_HOPS as u32 + 1).fold(builder(), |builder, addr| builder.hop(addr));
+    assert_eq!(forward_len(&full.separator().hop(100).build().unwrap()), MAX_HOPS);
+
+    //Zero would be read as a separator
+    match builder().hop(1).hop(ADDRESS_SEPARATOR).separator().build() {
+        Err(ParseError::BadFormat) => (),
+        _ => assert!(false)
+    }
+
+    //Need somewhere to send it
+    match builder().separator().hop(1).build() {
+        Err(ParseError::BadFormat) => (),
+        _ => assert!(false)
+    }
+
+    //And someone to ack
+    match builder().hop(1).separator().build() {
+        Err(ParseError::BadFormat) => (),
+        _ => assert!(false)
+    }
+}
+
+#[test]
+fn test_route_builder_too_long() {
+    let forward = (1..MAX_LENGTH as u32 + 1).fold(builder(), |builder, addr| builder.hop(addr));
+    match forward.separator().hop(100).build() {
+        Err(ParseError::TooLong) => (),
+        _ => assert!(false)
+    }
+
+    //Forward path fits but the return path pushes us over
+    let forward = (1..MAX_LENGTH as u32 - 1).fold(builder(), |builder, addr| builder.hop(addr));
+    match forward.separator().hop(100).hop(101).build() {
+        Err(ParseError::TooLong) => (),
+        _ => assert!(false)
+    }
+}
+
+#[test]
+fn test_route_builder_separator() {
+    match builder().hop(1).hop(2).build() {
+        Err(ParseError::SeparatorCount(0)) => (),
+        _ => assert!(false)
+    }
+
+    match builder().hop(1).separator().hop(2).separator().hop(3).build() {
+        Err(ParseError::SeparatorCount(2)) => (),
+        _ => assert!(false)
+    }
+}
+
 #[test]
 fn test_reverse() {
     let route = [1, 2, 3, 0, 5, 6, 7, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0];
     let reversed = reverse(&route);
-    let matched = [8, 7, 6, 5, 0, 3, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let matched = [5, 6, 7, 8, 0, 3, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
     
     assert_eq!(reversed, matched);
 }
 
 #[test]
-fn test_routing() {
-    use std::iter;
+fn test_forward_len() {
+    assert_eq!(forward_len(&gen_route(&[1, 2, 3, 0, 4])), 3);
+    assert_eq!(forward_len(&gen_route(&[1, 0, 4])), 1);
+    assert_eq!(forward_len(&[1; MAX_LENGTH]), MAX_LENGTH);
 
+    let advanced = advance(&gen_route(&[1, 2, 3, 0, 4]), 1).unwrap();
+    assert_eq!(forward_len(&advanced), 2);
+}
+
+#[test]
+fn test_same_path() {
+    let src = gen_test_addr(0);
+    let hops = [gen_test_addr(1), gen_test_addr(2)];
+    let dest = gen_test_addr(3);
+
+    let original = gen_route(&[hops[0], hops[1], dest, ADDRESS_SEPARATOR, src]);
+
+    //Every relay stage is the same path, as is the ack back
+    let mut route = original;
+    for hop in hops.iter().cloned() {
+        route = advance(&route, hop).unwrap();
+        assert!(same_path(&original, &route));
+        assert!(same_path(&route, &original));
+    }
+    assert!(same_path(&original, &reverse(&route)));
+
+    //Different hops, endpoints or extra hops aren't
+    assert!(!same_path(&original, &gen_route(&[hops[0], gen_test_addr(4), dest, ADDRESS_SEPARATOR, src])));
+    assert!(!same_path(&original, &gen_route(&[hops[0], hops[1], src, ADDRESS_SEPARATOR, dest, gen_test_addr(4)])));
+    assert!(!same_path(&original, &gen_route(&[hops[0], hops[1], gen_test_addr(4), dest, ADDRESS_SEPARATOR, src])));
+
+    //Same addresses but the endpoints moved
+    assert!(!same_path(&original, &gen_route(&[hops[0], dest, hops[1], ADDRESS_SEPARATOR, src])));
+}
+
+#[test]
+fn test_reverse_multi_hop() {
+    let src = gen_test_addr(0);
+    let hops = [gen_test_addr(1), gen_test_addr(2), gen_test_addr(3)];
+    let dest = gen_test_addr(4);
+
+    //src -> hop1 -> hop2 -> hop3 -> dest
+    let mut route = gen_route(&[hops[0], hops[1], hops[2], dest, ADDRESS_SEPARATOR, src]);
+
+    for hop in hops.iter().cloned() {
+        assert!(is_destination(&route, hop));
+        assert!(!final_addr(&route));
+        route = advance(&route, hop).unwrap();
+    }
+
+    assert!(is_destination(&route, dest));
+    assert!(final_addr(&route));
+
+    //Ack should retrace the hops in reverse and land back at the source
+    let mut ack_route = reverse(&route);
+    assert_eq!(ack_route, gen_route(&[hops[2], hops[1], hops[0], src, ADDRESS_SEPARATOR, dest]));
+
+    for hop in hops.iter().rev().cloned() {
+        assert!(is_destination(&ack_route, hop));
+        assert!(!final_addr(&ack_route));
+        ack_route = advance(&ack_route, hop).unwrap();
+    }
+
+    assert!(is_destination(&ack_route, src));
+    assert!(final_addr(&ack_route));
+}
+
+#[test]
+fn test_routing() {
     let self_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
     let mut route = [0; MAX_LENGTH];
     let route_iter = (0..14).map(|i| gen_test_addr(i))
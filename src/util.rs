@@ -1,14 +1,41 @@
 ///! Utility functions
+#[cfg(feature = "logger")]
 use log;
+#[cfg(feature = "logger")]
 use fern;
+#[cfg(feature = "logger")]
 use time;
 use std::io;
+use std::fs;
+use std::path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::cmp;
+use std::fmt;
+use std::mem;
+use std::error;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use rand;
+use kiss;
 
+/// How records are written to stdout and the log file
+#[cfg(feature = "logger")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// `[ms][date][level] msg` in the log file, bare messages on stdout
+    Text,
+    /// One JSON object per line with `ts`(ms since the epoch), `level` and `msg` fields
+    Json
+}
+
+#[cfg(feature = "logger")]
 pub fn init_log(trace: log::LogLevelFilter) {
-    init_log_callback(trace, true, |_msg: &str, _level: &log::LogLevel, _location: &log::LogLocation| {});
+    init_log_callback(trace, true, LogFormat::Text, |_msg: &str, _level: &log::LogLevel, _location: &log::LogLocation| {});
 }
 
-pub fn init_log_callback<D>(trace: log::LogLevelFilter, log_file: bool, dispatch: D) 
+#[cfg(feature = "logger")]
+pub fn init_log_callback<D>(trace: log::LogLevelFilter, log_file: bool, log_format: LogFormat, dispatch: D) 
         where D: Fn(&str, &log::LogLevel, &log::LogLocation) + Send + Sync + 'static {
     struct Logger {
         log: Box<Fn(&str, &log::LogLevel, &log::LogLocation) + Send + Sync + 'static>
@@ -21,9 +48,16 @@ pub fn init_log_callback<D>(trace: log::LogLevelFilter, log_file: bool, dispatch
         }
     }
 
-    //Print is gated by trace level
+    //Print is gated by trace level, records reach it already formatted when there's a file logger in front of it
+    let print_json = log_format == LogFormat::Json && !log_file;
     let print_logger = fern::DispatchConfig {
-        format: Box::new(|msg, _, _| msg.to_string()),
+        format: Box::new(move |msg: &str, level: &log::LogLevel, _location: &log::LogLocation| {
+            if print_json {
+                json_record(epoch_ms(), level, msg)
+            } else {
+                msg.to_string()
+            }
+        }),
         output: vec![fern::OutputConfig::stdout(), fern::OutputConfig::custom(Box::new(Logger { log: Box::new(dispatch) }))],
         level: trace,
     };
@@ -31,9 +65,12 @@ pub fn init_log_callback<D>(trace: log::LogLevelFilter, log_file: bool, dispatch
     //Always log trace to the file with a bit more info
     let final_logger = if log_file {
         fern::DispatchConfig {
-            format: Box::new(|msg: &str, level: &log::LogLevel, _location: &log::LogLocation| {
-                //Log unique MS time and date
-                format!("[{}][{}][{}] {}", time::precise_time_ns() / 1_000_000, time::now().strftime("%Y-%m-%d][%H:%M:%S").unwrap(), level, msg)
+            format: Box::new(move |msg: &str, level: &log::LogLevel, _location: &log::LogLocation| {
+                match log_format {
+                    //Log unique MS time and date
+                    LogFormat::Text => format!("[{}][{}][{}] {}", time::precise_time_ns() / 1_000_000, time::now().strftime("%Y-%m-%d][%H:%M:%S").unwrap(), level, msg),
+                    LogFormat::Json => json_record(epoch_ms(), level, msg)
+                }
             }),
             output: vec![fern::OutputConfig::file("output.log"), fern::OutputConfig::child(print_logger)],
             level: log::LogLevelFilter::Trace,
@@ -47,11 +84,59 @@ pub fn init_log_callback<D>(trace: log::LogLevelFilter, log_file: bool, dispatch
     }
 }
 
-pub struct WriteDispatch<'a> {
-    pub write: &'a mut io::Write
+/// Wall clock time in ms since the epoch
+#[cfg(feature = "logger")]
+fn epoch_ms() -> u64 {
+    let now = time::get_time();
+    now.sec as u64 * 1000 + now.nsec as u64 / 1_000_000
+}
+
+/// Formats a log record as a single line JSON object
+#[cfg(feature = "logger")]
+fn json_record(ts_ms: u64, level: &log::LogLevel, msg: &str) -> String {
+    format!("{{\"ts\":{},\"level\":\"{}\",\"msg\":\"{}\"}}", ts_ms, level, json_escape(msg))
+}
+
+/// Escapes a string for use inside a JSON string literal
+#[cfg(feature = "logger")]
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c)
+        }
+    }
+
+    escaped
 }
 
-impl<'a> io::Write for WriteDispatch<'a> {
+/// Output buffer was too small to hold the encoded bytes
+#[derive(Debug)]
+pub struct SizeError {
+    /// Number of bytes the output needed to be
+    pub required: usize
+}
+
+impl fmt::Display for SizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Output buffer too small, {} bytes required", self.required)
+    }
+}
+
+impl error::Error for SizeError {}
+
+pub struct WriteDispatch<'a, W: ?Sized + 'a> where W: io::Write {
+    pub write: &'a mut W
+}
+
+impl<'a, W: ?Sized> io::Write for WriteDispatch<'a, W> where W: io::Write {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.write.write(buf)
     }
@@ -61,18 +146,18 @@ impl<'a> io::Write for WriteDispatch<'a> {
     }
 }
 
-pub fn new_write_dispatch<'a>(write: &'a mut io::Write) -> WriteDispatch<'a> {
+pub fn new_write_dispatch<'a, W: ?Sized>(write: &'a mut W) -> WriteDispatch<'a, W> where W: io::Write {
     WriteDispatch {
         write: write
     }
 }
 
-pub struct ReadWriteDispatch<'a> {
-    read: &'a mut io::Read,
-    write: &'a mut io::Write
+pub struct ReadWriteDispatch<'a, R: ?Sized + 'a, W: ?Sized + 'a> where R: io::Read, W: io::Write {
+    read: &'a mut R,
+    write: &'a mut W
 }
 
-impl <'a> io::Write for ReadWriteDispatch<'a> {
+impl <'a, R: ?Sized, W: ?Sized> io::Write for ReadWriteDispatch<'a, R, W> where R: io::Read, W: io::Write {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.write.write(buf)
     }
@@ -82,15 +167,836 @@ impl <'a> io::Write for ReadWriteDispatch<'a> {
     }
 }
 
-impl <'a> io::Read for ReadWriteDispatch<'a> {
+impl <'a, R: ?Sized, W: ?Sized> io::Read for ReadWriteDispatch<'a, R, W> where R: io::Read, W: io::Write {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.read.read(buf)
     }
 }
 
-pub fn new_read_write_dispatch<'a>(read: &'a mut io::Read, write: &'a mut io::Write) -> ReadWriteDispatch<'a> {
+pub fn new_read_write_dispatch<'a, R: ?Sized, W: ?Sized>(read: &'a mut R, write: &'a mut W) -> ReadWriteDispatch<'a, R, W>
+    where R: io::Read, W: io::Write
+{
     ReadWriteDispatch {
         read: read,
         write: write
     }
-}
\ No newline at end of file
+}
+
+/// In-memory transport where every byte written can be read back, for exercising a node without hardware
+pub struct Loopback {
+    data: Vec<u8>,
+    trace: bool
+}
+
+/// Constructs an empty loopback, trace logging of traffic is off by default
+pub fn new_loopback() -> Loopback {
+    Loopback {
+        data: vec!(),
+        trace: false
+    }
+}
+
+impl Loopback {
+    /// Enables trace logging of bytes written and read
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+}
+
+impl io::Write for Loopback {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.trace {
+            trace!("Loopback write {} bytes {:?}", buf.len(), buf);
+        }
+
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Read for Loopback {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = cmp::min(buf.len(), self.data.len());
+        buf[..read].copy_from_slice(&self.data[..read]);
+
+        self.data.drain(..read);
+
+        if self.trace && read > 0 {
+            trace!("Loopback read {} bytes {:?}", read, &buf[..read]);
+        }
+
+        Ok(read)
+    }
+}
+
+/// One end of a linked duplex transport, bytes written here are read from the other end
+pub struct DuplexEnd {
+    rx: Arc<Mutex<Vec<u8>>>,
+    tx: Arc<Mutex<Vec<u8>>>
+}
+
+/// Pair of linked duplex endpoints
+pub type DuplexPair = (DuplexEnd, DuplexEnd);
+
+/// Constructs two linked endpoints, handy for connecting two nodes in tests and examples
+pub fn duplex() -> DuplexPair {
+    let a_to_b = Arc::new(Mutex::new(vec!()));
+    let b_to_a = Arc::new(Mutex::new(vec!()));
+
+    let a = DuplexEnd {
+        rx: b_to_a.clone(),
+        tx: a_to_b.clone()
+    };
+
+    let b = DuplexEnd {
+        rx: a_to_b,
+        tx: b_to_a
+    };
+
+    (a, b)
+}
+
+impl io::Write for DuplexEnd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.tx.lock() {
+            Ok(mut tx) => {
+                tx.extend_from_slice(buf);
+                Ok(buf.len())
+            },
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "Duplex endpoint was poisoned"))
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl io::Read for DuplexEnd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.rx.lock() {
+            Ok(mut rx) => {
+                let read = cmp::min(buf.len(), rx.len());
+                buf[..read].copy_from_slice(&rx[..read]);
+                rx.drain(..read);
+
+                Ok(read)
+            },
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "Duplex endpoint was poisoned"))
+        }
+    }
+}
+
+/// Wraps a transport and records every byte read from and written to it, for capturing a live session
+pub struct Capture<T> where T: io::Read + io::Write {
+    inner: T,
+    read: Vec<u8>,
+    written: Vec<u8>
+}
+
+/// Constructs a capture around `inner`
+pub fn new_capture<T>(inner: T) -> Capture<T> where T: io::Read + io::Write {
+    Capture {
+        inner: inner,
+        read: vec!(),
+        written: vec!()
+    }
+}
+
+impl<T> Capture<T> where T: io::Read + io::Write {
+    /// All bytes read from the transport so far
+    pub fn read_bytes(&self) -> &[u8] {
+        &self.read
+    }
+
+    /// All bytes written to the transport so far
+    pub fn written_bytes(&self) -> &[u8] {
+        &self.written
+    }
+
+    /// Returns the wrapped transport along with the bytes read and written
+    pub fn into_inner(self) -> (T, Vec<u8>, Vec<u8>) {
+        (self.inner, self.read, self.written)
+    }
+}
+
+impl<T> io::Write for Capture<T> where T: io::Read + io::Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = try!(self.inner.write(buf));
+        self.written.extend_from_slice(&buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T> io::Read for Capture<T> where T: io::Read + io::Write {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = try!(self.inner.read(buf));
+        self.read.extend_from_slice(&buf[..read]);
+
+        Ok(read)
+    }
+}
+
+/// Feeds a recorded byte stream back as a transport, anything written is discarded
+pub struct Replay {
+    data: Vec<u8>,
+    pos: usize
+}
+
+impl Replay {
+    /// Constructs a replay of `data`, usually the `read_bytes()` from a `Capture`
+    pub fn from_bytes(data: &[u8]) -> Replay {
+        Replay {
+            data: data.to_vec(),
+            pos: 0
+        }
+    }
+
+    /// Number of bytes that haven't been read yet
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+}
+
+impl io::Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = cmp::min(buf.len(), self.remaining());
+        buf[..read].copy_from_slice(&self.data[self.pos..self.pos+read]);
+        self.pos += read;
+
+        Ok(read)
+    }
+}
+
+impl io::Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Which way the bytes in a `FrameRecord` were going
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// Read from the transport
+    Rx,
+    /// Written to the transport
+    Tx
+}
+
+/// Single read or write logged by a `FrameLogger`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameRecord {
+    /// Ms since the unix epoch when the bytes crossed the transport
+    pub timestamp_ms: u64,
+    pub direction: Direction,
+    pub data: Vec<u8>
+}
+
+/// Size of the timestamp, direction and length that lead each record
+pub const FRAME_RECORD_HEADER_SIZE: usize = 8 + 1 + 4;
+
+/// Wraps a transport and appends every read and write to `log` as a record of big-endian u64 timestamp in ms,
+/// direction(0 = rx, 1 = tx), u32 length and the bytes themselves. Unlike `Capture` nothing is held in memory so
+/// it's suitable for long running captures, read them back with `read_frame_log`. Failing to write the log is
+/// only warned about, it never fails reads or writes on the transport.
+pub struct FrameLogger<T, L> where T: io::Read + io::Write, L: io::Write {
+    inner: T,
+    log: L
+}
+
+/// Constructs a frame logger around `inner` that writes records to `log`
+pub fn new_frame_logger<T, L>(inner: T, log: L) -> FrameLogger<T, L> where T: io::Read + io::Write, L: io::Write {
+    FrameLogger {
+        inner: inner,
+        log: log
+    }
+}
+
+/// Constructs a frame logger around `inner` that appends records to the file at `path`, creating it if needed
+pub fn open_frame_logger<T, P>(inner: T, path: P) -> io::Result<FrameLogger<T, fs::File>> where T: io::Read + io::Write, P: AsRef<path::Path> {
+    let file = try!(fs::OpenOptions::new().create(true).append(true).open(path));
+    Ok(new_frame_logger(inner, file))
+}
+
+impl<T, L> FrameLogger<T, L> where T: io::Read + io::Write, L: io::Write {
+    /// Returns the wrapped transport and log
+    pub fn into_inner(self) -> (T, L) {
+        (self.inner, self.log)
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        if data.len() == 0 {
+            return Ok(())
+        }
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() * 1000 + (elapsed.subsec_nanos() / 1_000_000) as u64)
+            .unwrap_or(0);
+
+        try!(self.log.write_u64::<BigEndian>(timestamp));
+        try!(self.log.write_u8(match direction {
+            Direction::Rx => 0,
+            Direction::Tx => 1
+        }));
+        try!(self.log.write_u32::<BigEndian>(data.len() as u32));
+        self.log.write_all(data)
+    }
+}
+
+impl<T, L> io::Write for FrameLogger<T, L> where T: io::Read + io::Write, L: io::Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = try!(self.inner.write(buf));
+        if let Err(e) = self.record(Direction::Tx, &buf[..written]) {
+            warn!("Unable to log {} bytes written: {}", written, e);
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.inner.flush());
+        if let Err(e) = self.log.flush() {
+            warn!("Unable to flush frame log: {}", e);
+        }
+
+        Ok(())
+    }
+}
+
+impl<T, L> io::Read for FrameLogger<T, L> where T: io::Read + io::Write, L: io::Write {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = try!(self.inner.read(buf));
+        if let Err(e) = self.record(Direction::Rx, &buf[..read]) {
+            warn!("Unable to log {} bytes read: {}", read, e);
+        }
+
+        Ok(read)
+    }
+}
+
+/// Iterates the records written by a `FrameLogger`
+pub struct FrameLogReader<R> where R: io::Read {
+    log: R
+}
+
+/// Constructs a reader over the records in `log`
+pub fn read_frame_log<R>(log: R) -> FrameLogReader<R> where R: io::Read {
+    FrameLogReader {
+        log: log
+    }
+}
+
+impl<R> Iterator for FrameLogReader<R> where R: io::Read {
+    type Item = io::Result<FrameRecord>;
+
+    /// Next record, None at the end of the log. A log cut off partway through a record is an `UnexpectedEof` error.
+    fn next(&mut self) -> Option<io::Result<FrameRecord>> {
+        let mut header = [0; FRAME_RECORD_HEADER_SIZE];
+        let mut read = 0;
+        while read < header.len() {
+            match self.log.read(&mut header[read..]) {
+                Ok(0) if read == 0 => return None,
+                Ok(0) => return Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Frame log ended inside a record"))),
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Some(Err(e))
+            }
+        }
+
+        let direction = match header[8] {
+            0 => Direction::Rx,
+            1 => Direction::Tx,
+            other => return Some(Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown frame log direction {}", other))))
+        };
+
+        //Read through take so a corrupt length can't make us allocate more than the log actually holds
+        let len = BigEndian::read_u32(&header[9..]) as usize;
+        let mut data = vec!();
+        match io::Read::read_to_end(&mut io::Read::take(&mut self.log, len as u64), &mut data) {
+            Ok(read) if read == len => (),
+            Ok(_) => return Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Frame log ended inside a record"))),
+            Err(e) => return Some(Err(e))
+        }
+
+        Some(Ok(FrameRecord {
+            timestamp_ms: BigEndian::read_u64(&header[..8]),
+            direction: direction,
+            data: data
+        }))
+    }
+}
+
+/// Wraps a transport and drops or delays whole KISS frames written to it, for testing retries end to end.
+/// Written bytes are grouped into frames at FEND boundaries, reads pass straight through.
+pub struct LossyTransport<T> where T: io::Read + io::Write {
+    inner: T,
+    /// Fraction of frames dropped, from 0.0 to 1.0
+    loss: f32,
+    /// Ticks a frame waits before it's written to `inner`
+    delay: usize,
+    /// Bytes of a frame that hasn't been closed by a FEND yet
+    partial: Vec<u8>,
+    /// Frames waiting out their delay, with the ticks they have left
+    delayed: VecDeque<(usize, Vec<u8>)>,
+    /// Number of frames dropped so far
+    dropped: usize,
+    /// Source of drop decisions, falls back to the thread RNG if not provided
+    rng: Option<Box<rand::Rng + Send>>
+}
+
+/// Constructs a lossy transport around `inner` that drops `loss` of the frames written to it
+pub fn new_lossy<T>(inner: T, loss: f32) -> LossyTransport<T> where T: io::Read + io::Write {
+    LossyTransport {
+        inner: inner,
+        loss: loss,
+        delay: 0,
+        partial: vec!(),
+        delayed: VecDeque::new(),
+        dropped: 0,
+        rng: None
+    }
+}
+
+/// Constructs a lossy transport that draws drop decisions from `rng`, useful for reproducible tests
+pub fn new_lossy_with_rng<T,R>(inner: T, loss: f32, rng: R) -> LossyTransport<T> where T: io::Read + io::Write, R: rand::Rng + Send + 'static {
+    let mut lossy = new_lossy(inner, loss);
+    lossy.rng = Some(Box::new(rng));
+
+    lossy
+}
+
+impl<T> LossyTransport<T> where T: io::Read + io::Write {
+    /// Sets the fraction of frames dropped, from 0.0 to 1.0
+    pub fn set_loss(&mut self, loss: f32) {
+        self.loss = loss;
+    }
+
+    /// Sets how many calls to `tick` each frame written from now on waits before it's delivered. Frames are always
+    /// delivered in the order they were written, so a frame can wait longer than this behind an earlier one.
+    pub fn set_delay(&mut self, ticks: usize) {
+        self.delay = ticks;
+    }
+
+    /// Number of frames dropped so far
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Advances delayed frames by one tick, writing any that are due to the wrapped transport
+    pub fn tick(&mut self) -> io::Result<()> {
+        for &mut (ref mut ticks, _) in self.delayed.iter_mut() {
+            *ticks = ticks.saturating_sub(1);
+        }
+
+        while self.delayed.front().map(|&(ticks, _)| ticks == 0).unwrap_or(false) {
+            let (_, frame) = self.delayed.pop_front().unwrap();
+            try!(self.inner.write_all(&frame));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the wrapped transport, partial and delayed frames are discarded
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Drops, delays or writes a complete frame
+    fn deliver(&mut self, frame: Vec<u8>) -> io::Result<()> {
+        use rand::distributions::IndependentSample;
+        let range = rand::distributions::Range::new(0.0, 1.0);
+
+        let roll: f32 = match self.rng {
+            Some(ref mut rng) => range.ind_sample(rng),
+            None => range.ind_sample(&mut rand::thread_rng())
+        };
+
+        if roll < self.loss {
+            trace!("Dropping frame of {} bytes", frame.len());
+            self.dropped += 1;
+            Ok(())
+        } else if self.delay > 0 || !self.delayed.is_empty() {
+            //Anything still waiting goes first, frames are delayed but never reordered
+            self.delayed.push_back((self.delay, frame));
+            Ok(())
+        } else {
+            self.inner.write_all(&frame)
+        }
+    }
+}
+
+impl<T> io::Write for LossyTransport<T> where T: io::Read + io::Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf.iter().cloned() {
+            //A FEND after anything other than FENDs closes the frame, otherwise it opens one
+            let closes = byte == kiss::FEND && self.partial.iter().any(|partial| *partial != kiss::FEND);
+            self.partial.push(byte);
+
+            if closes {
+                let frame = mem::replace(&mut self.partial, vec!());
+                try!(self.deliver(frame));
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T> io::Read for LossyTransport<T> where T: io::Read + io::Write {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+/// Formats bytes as a classic hexdump, 16 bytes per line with the offset, hex and printable ASCII.
+///
+/// ```
+/// use simplelink::util;
+///
+/// let dump = util::hexdump(b"Hi!\n");
+/// assert!(dump.starts_with("00000000  48 69 21 0a "));
+/// assert!(dump.ends_with(" |Hi!.|"));
+/// ```
+pub fn hexdump(bytes: &[u8]) -> String {
+    const LINE_SIZE: usize = 16;
+
+    bytes.chunks(LINE_SIZE).enumerate().map(|(line, chunk)| {
+        let mut hex = String::with_capacity(LINE_SIZE * 3 + 1);
+        for idx in 0..LINE_SIZE {
+            //Extra gap between the two halves of the line
+            if idx == LINE_SIZE / 2 {
+                hex.push(' ');
+            }
+
+            match chunk.get(idx) {
+                Some(byte) => hex.push_str(format!("{:02x} ", byte).as_str()),
+                None => hex.push_str("   ")
+            }
+        }
+
+        let ascii = chunk.iter().map(|byte| {
+            match *byte {
+                0x20...0x7E => *byte as char,
+                _ => '.'
+            }
+        }).collect::<String>();
+
+        format!("{:08x}  {}|{}|", line * LINE_SIZE, hex, ascii)
+    }).collect::<Vec<_>>().join("\n")
+}
+
+/// Formats a payload for display, printable ASCII is shown as-is and everything else as `\xNN` escapes.
+pub fn format_payload(payload: &[u8]) -> String {
+    payload.iter().fold(String::with_capacity(payload.len()), |mut formatted, byte| {
+        match *byte {
+            b'\\' => formatted.push_str("\\\\"),
+            0x20...0x7E => formatted.push(*byte as char),
+            _ => formatted.push_str(format!("\\x{:02X}", byte).as_str())
+        }
+
+        formatted
+    })
+}
+
+#[test]
+fn test_loopback() {
+    use std::io::{Read, Write};
+
+    let mut loopback = new_loopback();
+
+    loopback.write_all(&[1, 2, 3, 4, 5]).unwrap();
+
+    let mut buf = [0; 3];
+    assert_eq!(loopback.read(&mut buf).unwrap(), 3);
+    assert_eq!(buf, [1, 2, 3]);
+
+    assert_eq!(loopback.read(&mut buf).unwrap(), 2);
+    assert_eq!(buf[..2], [4, 5]);
+
+    assert_eq!(loopback.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn test_dispatch() {
+    use std::io::{Read, Write};
+
+    //Buffered writer only passes bytes on once flushed
+    let mut buffered = io::BufWriter::new(vec!());
+    {
+        let mut dispatch = new_write_dispatch(&mut buffered);
+        assert_eq!(dispatch.write(&[1, 2, 3]).unwrap(), 3);
+        dispatch.flush().unwrap();
+    }
+    assert_eq!(buffered.get_ref(), &vec!(1, 2, 3));
+
+    let mut input = io::Cursor::new(vec!(4, 5));
+    let mut output = io::BufWriter::new(vec!());
+    {
+        let mut dispatch = new_read_write_dispatch(&mut input, &mut output);
+        let mut buf = [0; 4];
+        assert_eq!(dispatch.read(&mut buf).unwrap(), 2);
+        assert_eq!(buf[..2], [4, 5]);
+
+        dispatch.write_all(&buf[..2]).unwrap();
+        dispatch.flush().unwrap();
+    }
+    assert_eq!(output.get_ref(), &vec!(4, 5));
+
+    //Trait objects still work for callers that only have one
+    let mut out = vec!();
+    {
+        let write: &mut io::Write = &mut out;
+        new_write_dispatch(write).write_all(&[6]).unwrap();
+    }
+    assert_eq!(out, vec!(6));
+}
+
+#[test]
+fn test_duplex() {
+    use std::io::{Read, Write};
+
+    let (mut a, mut b) = duplex();
+    let mut buf = [0; 8];
+
+    a.write_all(&[1, 2, 3]).unwrap();
+    b.write_all(&[4, 5]).unwrap();
+
+    //Writes never loop back to the same end
+    assert_eq!(b.read(&mut buf).unwrap(), 3);
+    assert_eq!(buf[..3], [1, 2, 3]);
+    assert_eq!(b.read(&mut buf).unwrap(), 0);
+
+    assert_eq!(a.read(&mut buf).unwrap(), 2);
+    assert_eq!(buf[..2], [4, 5]);
+    assert_eq!(a.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn test_capture_replay() {
+    use spec::{address, node};
+
+    let local_addr = address::encode(['K', 'I', '7', 'E', 'S', 'T', '0']).unwrap();
+    let remote_addr = address::encode(['K', 'F', '7', 'S', 'J', 'K', '0']).unwrap();
+
+    let (mut local_io, remote_io) = duplex();
+    let mut local = node::new(local_addr);
+    let mut remote = node::new(remote_addr);
+
+    local.send_slice(&[1, 2, 3], [remote_addr].iter().cloned(), &mut local_io).unwrap();
+    local.send_slice(&[4, 5], [remote_addr].iter().cloned(), &mut local_io).unwrap();
+
+    //Capture a live session
+    let mut capture = new_capture(remote_io);
+    let mut live = vec!();
+    remote.recv(&mut capture,
+        |_,data| live.push(data.to_vec()),
+        |_| {},
+        |_| {},
+        |_,_,_| assert!(false)).unwrap();
+
+    assert_eq!(live.len(), 2);
+    assert!(capture.read_bytes().len() > 0);
+    assert!(capture.written_bytes().len() > 0);
+
+    //A fresh node fed the same bytes should receive and emit the same things
+    let mut replay = new_capture(Replay::from_bytes(capture.read_bytes()));
+    let mut replayed = vec!();
+    node::new(remote_addr).recv(&mut replay,
+        |_,data| replayed.push(data.to_vec()),
+        |_| {},
+        |_| {},
+        |_,_,_| assert!(false)).unwrap();
+
+    assert_eq!(replayed, live);
+    assert_eq!(replay.written_bytes(), capture.written_bytes());
+
+    let (replay, read, _) = replay.into_inner();
+    assert_eq!(replay.remaining(), 0);
+    assert_eq!(read.as_slice(), capture.read_bytes());
+}
+
+#[test]
+fn test_hexdump() {
+    let bytes = (0x41..0x41 + 20).chain(vec!(0x00, 0xFF).into_iter()).collect::<Vec<u8>>();
+
+    assert_eq!(hexdump(&bytes),
+        "00000000  41 42 43 44 45 46 47 48  49 4a 4b 4c 4d 4e 4f 50 |ABCDEFGHIJKLMNOP|\n\
+         00000010  51 52 53 54 00 ff                                |QRST..|");
+    assert_eq!(hexdump(&[]), "");
+}
+
+#[test]
+fn test_format_payload() {
+    assert_eq!(format_payload(b"Hello World"), "Hello World");
+    assert_eq!(format_payload(&[b'O', b'K', 0x00, 0xFF, b'\n', b'!']), "OK\\x00\\xFF\\x0A!");
+    assert_eq!(format_payload(b"C:\\"), "C:\\\\");
+    assert_eq!(format_payload(&[]), "");
+}
+
+#[test]
+#[cfg(feature = "logger")]
+fn test_json_record() {
+    assert_eq!(json_record(1234, &log::LogLevel::Warn, "Frame 5 dropped"),
+        r#"{"ts":1234,"level":"WARN","msg":"Frame 5 dropped"}"#);
+
+    //Payload dumps span lines and may hold anything
+    assert_eq!(json_record(0, &log::LogLevel::Trace, "a \"b\"\\\n\tc\u{1}"),
+        r#"{"ts":0,"level":"TRACE","msg":"a \"b\"\\\n\tc\u0001"}"#);
+
+    assert!(epoch_ms() > 0);
+}
+
+#[test]
+fn test_frame_logger() {
+    use std::io::{Read, Write};
+
+    let mut first = vec!();
+    kiss::encode(&mut io::Cursor::new(&[1, 2, 3]), &mut first, 0).unwrap();
+    let mut second = vec!();
+    kiss::encode(&mut io::Cursor::new(&[4, 5]), &mut second, 0).unwrap();
+
+    let mut logger = new_frame_logger(new_loopback(), vec!());
+    logger.write_all(&first).unwrap();
+    logger.write_all(&second).unwrap();
+    logger.flush().unwrap();
+
+    let mut buf = [0; 64];
+    let read_len = logger.read(&mut buf).unwrap();
+    assert_eq!(read_len, first.len() + second.len());
+    let read = buf[..read_len].to_vec();
+
+    let (_, log) = logger.into_inner();
+    let records = read_frame_log(io::Cursor::new(&log)).collect::<io::Result<Vec<_>>>().unwrap();
+    assert_eq!(records.len(), 3);
+    assert_eq!((records[0].direction, &records[0].data), (Direction::Tx, &first));
+    assert_eq!((records[1].direction, &records[1].data), (Direction::Tx, &second));
+    assert_eq!((records[2].direction, &records[2].data), (Direction::Rx, &read));
+    assert!(records.iter().all(|record| record.timestamp_ms > 0));
+
+    //Log cut off partway through a record
+    let mut truncated = read_frame_log(io::Cursor::new(&log[..log.len()-1]));
+    assert!(truncated.next().unwrap().is_ok());
+    assert!(truncated.next().unwrap().is_ok());
+    assert_eq!(truncated.next().unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+    //Files are appended to across loggers
+    let path = ::std::env::temp_dir().join(format!("simplelink_frame_log_{}.bin", ::std::process::id()));
+    let _ = fs::remove_file(&path);
+    for frame in [&first, &second].iter() {
+        let mut logger = open_frame_logger(new_loopback(), &path).unwrap();
+        logger.write_all(frame).unwrap();
+        logger.flush().unwrap();
+    }
+
+    let records = read_frame_log(fs::File::open(&path).unwrap()).collect::<io::Result<Vec<_>>>().unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(records.iter().map(|record| record.data.clone()).collect::<Vec<_>>(), vec!(first.clone(), second));
+
+    //Corrupt length is an error rather than a 4GB allocation
+    let mut corrupt = log[..FRAME_RECORD_HEADER_SIZE].to_vec();
+    BigEndian::write_u32(&mut corrupt[9..], u32::max_value());
+    corrupt.extend_from_slice(&first);
+    let mut reader = read_frame_log(io::Cursor::new(&corrupt));
+    assert_eq!(reader.next().unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+    //A full log doesn't stop traffic
+    let mut full = [0; 4];
+    let mut logger = new_frame_logger(new_loopback(), io::Cursor::new(&mut full[..]));
+    assert_eq!(logger.write(&first).unwrap(), first.len());
+    logger.flush().unwrap();
+    assert_eq!(logger.read(&mut buf).unwrap(), first.len());
+}
+
+#[test]
+fn test_lossy_transport() {
+    use std::io::{Read, Write};
+    use rand::{SeedableRng, XorShiftRng};
+
+    let mut lossy = new_lossy_with_rng(new_loopback(), 0.5, XorShiftRng::from_seed([0x193a6754, 0xa8a7d469, 0x97830e05, 0x113ba7bb]));
+
+    //Frames with escaped FENDs in them, written a few bytes at a time so frames span writes
+    let mut written = vec!();
+    for frame in 0..50u8 {
+        let payload = [frame, kiss::FEND, frame, kiss::FESC];
+        kiss::encode(&mut io::Cursor::new(&payload), &mut written, 0).unwrap();
+    }
+    for chunk in written.chunks(3) {
+        lossy.write_all(chunk).unwrap();
+    }
+
+    assert!(lossy.dropped() > 0 && lossy.dropped() < 50);
+
+    //Whatever made it through is whole frames
+    let mut read = vec!();
+    lossy.read_to_end(&mut read).unwrap();
+
+    let mut received = vec!();
+    let mut decoded = vec!();
+    let mut offset = 0;
+    while let Some(result) = kiss::decode(read[offset..].iter().cloned(), &mut decoded) {
+        assert_eq!(decoded.len(), 4);
+        assert_eq!(&decoded[1..], &[kiss::FEND, decoded[0], kiss::FESC]);
+        received.push(decoded[0]);
+
+        offset += result.bytes_read;
+        decoded.clear();
+    }
+
+    assert_eq!(offset, read.len());
+    assert_eq!(received.len(), 50 - lossy.dropped());
+    assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+
+    //Delayed frames show up once enough ticks pass
+    lossy.set_loss(0.0);
+    lossy.set_delay(2);
+    kiss::encode(&mut io::Cursor::new(&[1, 2, 3]), &mut lossy, 0).unwrap();
+
+    let mut read = vec!();
+    lossy.read_to_end(&mut read).unwrap();
+    assert_eq!(read.len(), 0);
+
+    lossy.tick().unwrap();
+    lossy.read_to_end(&mut read).unwrap();
+    assert_eq!(read.len(), 0);
+
+    lossy.tick().unwrap();
+    lossy.read_to_end(&mut read).unwrap();
+    assert_eq!(read, vec!(kiss::FEND, kiss::CMD_DATA, 1, 2, 3, kiss::FEND));
+
+    //Dropping the delay doesn't let a new frame jump ahead of one that's still waiting
+    kiss::encode(&mut io::Cursor::new(&[4]), &mut lossy, 0).unwrap();
+    lossy.set_delay(0);
+    kiss::encode(&mut io::Cursor::new(&[5]), &mut lossy, 0).unwrap();
+
+    let mut read = vec!();
+    lossy.read_to_end(&mut read).unwrap();
+    assert_eq!(read.len(), 0);
+
+    lossy.tick().unwrap();
+    lossy.tick().unwrap();
+    lossy.read_to_end(&mut read).unwrap();
+    assert_eq!(read, vec!(kiss::FEND, kiss::CMD_DATA, 4, kiss::FEND, kiss::FEND, kiss::CMD_DATA, 5, kiss::FEND));
+
+    //Once nothing is waiting frames go straight through again
+    let mut read = vec!();
+    kiss::encode(&mut io::Cursor::new(&[6]), &mut lossy, 0).unwrap();
+    lossy.read_to_end(&mut read).unwrap();
+    assert_eq!(read, vec!(kiss::FEND, kiss::CMD_DATA, 6, kiss::FEND));
+}